@@ -0,0 +1,19 @@
+use std::time::Instant;
+use web_stack::{run_builtin_example, EXAMPLES};
+
+/// Measure average time to run the example program
+fn bench(name: &str, count: u32) {
+    let start = Instant::now();
+    let mut output = 0;
+    for _ in 0..count {
+        output = run_builtin_example(name).map_or(0, |result| result.output().len());
+    }
+    let elapsed = start.elapsed() / count;
+    println!("{name:>8}: {elapsed:?} ({output} bytes of output)");
+}
+
+fn main() {
+    for (name, _, _) in EXAMPLES {
+        bench(name, 10);
+    }
+}
@@ -0,0 +1,25 @@
+use std::time::Instant;
+use web_stack::run_stack;
+
+/// Measure time to run the program
+fn bench(name: &str, src: &str) {
+    let start = Instant::now();
+    let result = run_stack(src);
+    let elapsed = start.elapsed();
+    println!(
+        "{name:>8}: {elapsed:?} ({} bytes of output)",
+        result.output().len()
+    );
+}
+
+fn main() {
+    let count = 5000;
+    bench(
+        "concat",
+        &format!("() 0 {count} 1 range (i) (i concat) for print"),
+    );
+    bench(
+        "sb-push",
+        &format!("sb-new 0 {count} 1 range (i) (i sb-push) for sb-build print"),
+    );
+}
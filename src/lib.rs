@@ -4,466 +4,4984 @@ use wasm_bindgen::prelude::*;
 pub fn run_stack(src: &str) -> Result {
     let mut executor = Executor::new();
     executor.evaluate_program(src.to_string());
-    Result::new(executor.output, executor.log)
+    executor.result()
 }
 
+/// Run program with options given as JSON. Options that don't exist are warned,
+/// and ones that can't be parsed stop it with the status "error"
 #[wasm_bindgen]
-extern "C" {
-    pub fn prompt(s: &str) -> String;
+pub fn run_stack_opts(src: &str, options_json: &str) -> Result {
+    let config = match serde_json::from_str(options_json) {
+        Ok(config) => config,
+        Err(err) => return invalid_options(err),
+    };
+
+    let mut executor = Executor::new();
+    executor.config = config;
+    executor.evaluate_program(src.to_string());
+    executor.result()
 }
 
+/// Representative programs shipped with the interpreter, by name, difficulty and source
+pub const EXAMPLES: &[(&str, &str, &str)] = &[
+    (
+        "strings",
+        "beginner",
+        include_str!("../examples/strings.stk"),
+    ),
+    ("json", "beginner", include_str!("../examples/json.stk")),
+    (
+        "sorting",
+        "intermediate",
+        include_str!("../examples/sorting.stk"),
+    ),
+    (
+        "fractal",
+        "advanced",
+        include_str!("../examples/fractal.stk"),
+    ),
+];
+
+/// Run the shipped example program by its name
 #[wasm_bindgen]
-pub struct Result {
-    output: String,
-    log: String,
+pub fn run_builtin_example(name: &str) -> Option<Result> {
+    let (_, _, src) = EXAMPLES.iter().find(|(example, _, _)| *example == name)?;
+    Some(run_stack(src))
 }
 
+/// Get JSON array of the shipped examples, as objects of name, title taken from
+/// the leading comment, difficulty, built-in commands they use, and source
 #[wasm_bindgen]
-impl Result {
-    pub fn new(output: String, log: String) -> Self {
-        Result { output, log }
+pub fn examples_catalog() -> String {
+    let mut executor = Executor::new();
+    let examples: Vec<serde_json::Value> = EXAMPLES
+        .iter()
+        .map(|(name, difficulty, src)| {
+            let title = src
+                .trim_start()
+                .strip_prefix('#')
+                .and_then(|rest| rest.split('#').next())
+                .map(|title| title.trim().to_string())
+                .unwrap_or_else(|| name.to_string());
+            let mut commands = BTreeSet::new();
+            for token in executor.analyze_syntax(src.to_string()) {
+                commands_used(&mut executor, &token, &mut commands);
+            }
+            serde_json::json!({
+                "name": name,
+                "title": title,
+                "difficulty": difficulty,
+                "commands": commands,
+                "source": src,
+            })
+        })
+        .collect();
+    serde_json::Value::Array(examples).to_string()
+}
+
+/// Collect the built-in commands the token uses, including inside nested code,
+/// with aliases resolved to their commands
+fn commands_used(executor: &mut Executor, token: &str, commands: &mut BTreeSet<&'static str>) {
+    let name = ALIASES
+        .iter()
+        .find(|alias| alias.name == token)
+        .map_or(token, |alias| alias.command);
+    if let Some((name, _)) = COMMANDS.iter().find(|(command, _)| *command == name) {
+        commands.insert(name);
+        return;
+    }
+    let nested = (token.starts_with('(') && token.ends_with(')'))
+        || (token.starts_with('[') && token.ends_with(']'))
+        || (token.starts_with('{') && token.ends_with('}'));
+    if nested && token.len() >= 2 {
+        for inner in executor.analyze_syntax(token[1..token.len() - 1].to_string()) {
+            commands_used(executor, &inner, commands);
+        }
     }
+}
 
-    pub fn output(&self) -> String {
-        self.output.clone()
+/// Run program against each environment object of the array, whose entries
+/// become variables. The program is parsed once, and results are returned as array
+#[wasm_bindgen]
+pub fn run_batch(src: &str, inputs: JsValue) -> JsValue {
+    let json = js_sys::JSON::stringify(&inputs)
+        .ok()
+        .and_then(|x| x.as_string())
+        .unwrap_or_default();
+    let inputs = match serde_json::from_str(&json) {
+        Ok(serde_json::Value::Array(inputs)) => inputs,
+        _ => Vec::new(),
+    };
+
+    let results = js_sys::Array::new();
+    for env in inputs {
+        results.push(&JsValue::from(run_with_env(src, &env)));
     }
+    results.into()
+}
 
-    pub fn log(&self) -> String {
-        self.log.clone()
+/// Run expression and get the top of the stack as JS value.
+/// It throws an exception on runtime error
+#[wasm_bindgen]
+pub fn eval_value(src: &str) -> std::result::Result<JsValue, JsValue> {
+    let mut executor = Executor::new();
+    executor.config.trace = TraceLevel::Off;
+    executor.evaluate_program(src.to_string());
+    if executor.halted {
+        let message = match executor.status {
+            Status::Exited(code) => format!("the program exited with code {code}"),
+            Status::Aborted => "the run is aborted by the host".to_string(),
+            Status::Timeout => "the time limit is exceeded".to_string(),
+            Status::FuelExhausted => "the fuel is exhausted".to_string(),
+            Status::Completed | Status::Error => "the execution is stopped".to_string(),
+        };
+        return Err(js_sys::Error::new(&message).into());
+    }
+    match executor.stack.pop() {
+        Some(Type::Error(err)) => Err(js_sys::Error::new(&err).into()),
+        Some(value) => Ok(value.to_js()),
+        None => Err(js_sys::Error::new("the stack is empty").into()),
     }
 }
 
-use std::collections::HashMap;
-use std::env;
+/// Run program with variables of the JSON object
+fn run_with_env(src: &str, env: &serde_json::Value) -> Result {
+    let serde_json::Value::Object(env) = env else {
+        return Result::new(
+            String::new(),
+            "Error! invalid input: expected object\n".to_string(),
+        );
+    };
 
-/// Data type
-#[derive(Clone, Debug)]
-enum Type {
-    Number(f64),
-    String(String),
-    Bool(bool),
-    List(Vec<Type>),
-    Object(String, HashMap<String, Type>),
-    Error(String),
+    let mut executor = Executor::new();
+    for (name, value) in env {
+        match Type::from_json(value) {
+            Some(value) => executor.memory.insert(name.clone(), value),
+            None => {
+                let log = format!("Error! invalid input: {name} has no corresponding type\n");
+                return Result::new(String::new(), log);
+            }
+        };
+    }
+    executor.evaluate_program(src.to_string());
+    executor.result()
 }
 
-/// Implement methods
-impl Type {
-    /// Show data to display
-    fn display(&self) -> String {
-        match self {
-            Type::Number(num) => num.to_string(),
-            Type::String(s) => format!("({})", s),
-            Type::Bool(b) => b.to_string(),
-            Type::List(list) => {
-                let result: Vec<String> = list.iter().map(|token| token.display()).collect();
-                format!("[{}]", result.join(" "))
-            }
-            Type::Error(err) => format!("error:{err}"),
-            Type::Object(name, _) => {
-                format!("Object<{name}>")
-            }
+/// Run program with arguments, got by the `args` command
+#[wasm_bindgen]
+pub fn run_stack_with_args(src: &str, args: js_sys::Array) -> Result {
+    let mut executor = Executor::new();
+    executor.config.args = args.iter().filter_map(|x| x.as_string()).collect();
+    executor.evaluate_program(src.to_string());
+    executor.result()
+}
+
+#[wasm_bindgen]
+extern "C" {
+    pub fn prompt(s: &str) -> String;
+}
+
+/// Minify program, keeping a map to the original source
+#[wasm_bindgen]
+pub fn minify_stack(src: &str) -> Minified {
+    let mut executor = Executor::new();
+    let lines = line_starts(src);
+
+    let mut code = String::new();
+    let mut map = Vec::new();
+    for (token, start) in executor.analyze_syntax_spans(src.to_string()) {
+        // Comments are not needed to run
+        if comment_text(&token).is_some() {
+            continue;
+        }
+        if !code.is_empty() {
+            code.push(' ');
         }
+
+        let line = lines.partition_point(|x| *x <= start);
+        let column = start - lines[line - 1] + 1;
+        map.push((code.chars().count(), line, column));
+        code += &token;
     }
 
-    /// Get string form data
-    fn get_string(&mut self) -> String {
-        match self {
-            Type::String(s) => s.to_string(),
-            Type::Number(i) => i.to_string(),
-            Type::Bool(b) => b.to_string(),
-            Type::List(l) => Type::List(l.to_owned()).display(),
-            Type::Error(err) => format!("error:{err}"),
-            Type::Object(name, _) => {
-                format!("Object<{name}>")
+    Minified {
+        code,
+        map: serde_json::to_string(&map).unwrap_or_default(),
+    }
+}
+
+/// Split program into tokens for syntax highlighting, as JSON list of {kind, text, start, end}.
+/// Kind is "number", "string", "list" (also block), "comment", "command" (also true, false
+/// and nil) or "unknown" such as variables. Start and end are positions in characters
+#[wasm_bindgen]
+pub fn tokenize(src: &str) -> String {
+    let tokens: Vec<serde_json::Value> = lex(src, DEFAULT_FULL_WIDTH)
+        .0
+        .into_iter()
+        .map(|token| {
+            let kind = match Op::decode(&token.text, DEFAULT_FULL_WIDTH) {
+                Op::Push(Type::Number(_)) => "number",
+                Op::Push(Type::String(_)) => "string",
+                Op::Push(Type::Bool(_) | Type::Nil) | Op::Word(Some(_)) => "command",
+                Op::List(_) | Op::Block(_) => "list",
+                Op::Comment => "comment",
+                _ => "unknown",
+            };
+            let (start, end) = (token.start.position, token.end.position);
+            serde_json::json!({ "kind": kind, "text": token.text, "start": start, "end": end })
+        })
+        .collect();
+    serde_json::Value::Array(tokens).to_string()
+}
+
+/// Pretty-print program as the tokenizer reads it: tokens are split by one space, line breaks
+/// are kept, lists and blocks spanning lines are indented by four spaces, and comments are
+/// trimmed. Strings are kept as they are. Program with syntax errors is returned as it is
+#[wasm_bindgen]
+pub fn format_stack(src: &str) -> String {
+    let (tokens, errors) = lex(src, DEFAULT_FULL_WIDTH);
+    if !errors.is_empty() {
+        return src.to_string();
+    }
+    let mut formatted = String::new();
+    format_code(src, &tokens, 0, &mut formatted);
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    formatted
+}
+
+/// Indentation of formatted code
+const INDENT: &str = "    ";
+
+/// Write the tokens of the code, each line of them at the indent level
+fn format_code(code: &str, tokens: &[Token], indent: usize, formatted: &mut String) {
+    let chars: Vec<char> = code.chars().collect();
+    let mut last_line = None; // Line where the previous token ends
+    for token in tokens {
+        let original: String = chars[token.start.position..token.end.position]
+            .iter()
+            .collect();
+        match last_line {
+            Some(line) if token.start.line > line => {
+                // Keep line break, and one blank line of the ones between
+                formatted.push('\n');
+                if token.start.line > line + 1 {
+                    formatted.push('\n');
+                }
+                formatted.push_str(&INDENT.repeat(indent));
             }
+            Some(_) => formatted.push(' '),
+            None => formatted.push_str(&INDENT.repeat(indent)),
         }
+        formatted.push_str(&format_token(&original, &token.text, indent));
+        last_line = Some(token.end.line);
+    }
+}
+
+/// Format the token, whose text in the source is the original
+fn format_token(original: &str, text: &str, indent: usize) -> String {
+    if let Some(comment) = comment_text(text).filter(|_| !original.contains('\n')) {
+        let comment = comment.trim();
+        return match text.chars().next() {
+            Some('#') if comment.is_empty() => "##".to_string(),
+            Some('#') => match comment.strip_prefix(':') {
+                Some(doc) => format!("#: {} #", doc.trim()),
+                None => format!("# {comment} #"),
+            },
+            _ if text.starts_with("//") => format!("// {comment}").trim_end().to_string(),
+            _ => text.to_string(),
+        };
     }
 
-    /// Get number from data
-    fn get_number(&mut self) -> f64 {
-        match self {
-            Type::String(s) => s.parse().unwrap_or(0.0),
-            Type::Number(i) => *i,
-            Type::Bool(b) => {
-                if *b {
-                    1.0
-                } else {
-                    0.0
+    let (Some(open), Some(close)) = (text.chars().next(), text.chars().last()) else {
+        return text.to_string();
+    };
+    if open == '(' && close == ')' {
+        // String is kept as it is written, as spaces are part of its value
+        return original.to_string();
+    }
+    let enclosed = matches!((open, close), ('[', ']') | ('{', '}'));
+    if !enclosed || text.len() < 2 {
+        return text.to_string();
+    }
+    let inner: String = {
+        let chars: Vec<char> = original.chars().collect();
+        chars[1..chars.len() - 1].iter().collect()
+    };
+    let (tokens, errors) = lex(&inner, DEFAULT_FULL_WIDTH);
+    let multiline = inner.contains('\n');
+    if !errors.is_empty() || tokens.is_empty() {
+        return text.to_string();
+    }
+
+    let mut formatted = open.to_string();
+    if multiline {
+        formatted.push('\n');
+        format_code(&inner, &tokens, indent + 1, &mut formatted);
+        formatted.push('\n');
+        formatted.push_str(&INDENT.repeat(indent));
+    } else {
+        let chars: Vec<char> = inner.chars().collect();
+        let parts: Vec<String> = tokens
+            .iter()
+            .map(|token| {
+                let original: String = chars[token.start.position..token.end.position]
+                    .iter()
+                    .collect();
+                format_token(&original, &token.text, indent)
+            })
+            .collect();
+        formatted.push_str(&parts.join(" "));
+    }
+    formatted.push(close);
+    formatted
+}
+
+/// Simplify program by constant folding against the known variables,
+/// given as JSON object. Returns the simplified source
+#[wasm_bindgen]
+pub fn specialize(src: &str, env_json: &str) -> String {
+    let mut env: HashMap<String, Type> = match serde_json::from_str(env_json) {
+        Ok(serde_json::Value::Object(object)) => object
+            .iter()
+            .filter_map(|(name, value)| Some((name.clone(), Type::from_json(value)?)))
+            .collect(),
+        _ => HashMap::new(),
+    };
+    let mut executor = Executor::new();
+
+    let mut code: Vec<String> = Vec::new();
+    let mut known: Vec<Type> = Vec::new(); // Values not written to code yet
+    let mut substituted = true;
+    for token in executor.analyze_syntax(src.to_string()) {
+        if comment_text(&token).is_some() {
+            continue;
+        }
+        let value = match env.get(&token) {
+            Some(value) if substituted => Some(value.clone()),
+            _ => literal_value(&token, DEFAULT_FULL_WIDTH),
+        };
+        if let Some(value) = value.filter(|x| x.is_literal()) {
+            known.push(value);
+            continue;
+        }
+
+        // Calculate pure command now, if all of its arguments are known
+        let builtin = Command::parse(&token);
+        if let Some(count) = builtin.and_then(|x| x.pure_arity()) {
+            if known.len() >= count {
+                let mut scratch = Executor::new();
+                scratch.config.trace = TraceLevel::Off;
+                scratch.stack = known[known.len() - count..].to_vec();
+                scratch.execute_command(token.clone(), builtin);
+                if scratch.stack.iter().all(|x| x.is_literal()) {
+                    known.truncate(known.len() - count);
+                    known.extend(scratch.stack);
+                    continue;
                 }
             }
-            Type::List(l) => l.len() as f64,
-            Type::Error(e) => e.parse().unwrap_or(0f64),
-            Type::Object(_, object) => object.len() as f64,
         }
-    }
 
-    /// Get bool from data
-    fn get_bool(&mut self) -> bool {
-        match self {
-            Type::String(s) => !s.is_empty(),
-            Type::Number(i) => *i != 0.0,
-            Type::Bool(b) => *b,
-            Type::List(l) => !l.is_empty(),
-            Type::Error(e) => e.parse().unwrap_or(false),
-            Type::Object(_, object) => object.is_empty(),
+        // The variable may be changed by the program
+        if token == "var" || token == "free" {
+            match known.last() {
+                Some(Type::String(name)) => {
+                    env.remove(name);
+                }
+                _ => substituted = false,
+            }
         }
+        code.extend(known.drain(..).map(|x| x.display()));
+        code.push(token);
     }
+    code.extend(known.drain(..).map(|x| x.display()));
 
-    /// Get list form data
-    fn get_list(&mut self) -> Vec<Type> {
-        match self {
-            Type::String(s) => s
-                .to_string()
-                .chars()
-                .map(|x| Type::String(x.to_string()))
-                .collect::<Vec<Type>>(),
-            Type::Number(i) => vec![Type::Number(*i)],
-            Type::Bool(b) => vec![Type::Bool(*b)],
-            Type::List(l) => l.to_vec(),
-            Type::Error(e) => vec![Type::Error(e.to_string())],
-            Type::Object(_, object) => object.values().map(|x| x.to_owned()).collect::<Vec<Type>>(),
+    // Keep definitions of the variables, that may be used inside code strings
+    let mut prelude: Vec<(String, Type)> = env
+        .into_iter()
+        .filter(|(name, _)| {
+            code.iter()
+                .any(|token| mentions(&mut executor, token, name))
+        })
+        .collect();
+    prelude.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut result: Vec<String> = prelude
+        .into_iter()
+        .map(|(name, value)| format!("{} ({name}) var", value.display()))
+        .collect();
+    result.extend(code);
+    result.join(" ")
+}
+
+/// Whether the token uses the name, including inside code of strings and lists
+fn mentions(executor: &mut Executor, token: &str, name: &str) -> bool {
+    if token == name {
+        return true;
+    }
+    let nested = (token.starts_with('(') && token.ends_with(')'))
+        || (token.starts_with('[') && token.ends_with(']'))
+        || (token.starts_with('{') && token.ends_with('}'));
+    nested
+        && token.len() >= 2
+        && executor
+            .analyze_syntax(token[1..token.len() - 1].to_string())
+            .iter()
+            .any(|inner| mentions(executor, inner, name))
+}
+
+/// Rewrite the code so the names refer to the prefixed ones, including inside nested code
+fn qualify(executor: &mut Executor, code: &str, names: &HashSet<String>, prefix: &str) -> String {
+    let tokens: Vec<String> = executor
+        .analyze_syntax(code.to_string())
+        .into_iter()
+        .map(|token| {
+            if names.contains(&token) {
+                return format!("{prefix}{token}");
+            }
+            let nested = matches!(
+                (token.chars().next(), token.chars().last()),
+                (Some('('), Some(')')) | (Some('['), Some(']')) | (Some('{'), Some('}'))
+            );
+            if !nested || token.len() < 2 {
+                return token;
+            }
+            let inner = qualify(executor, &token[1..token.len() - 1], names, prefix);
+            format!("{}{inner}{}", &token[..1], &token[token.len() - 1..])
+        })
+        .collect();
+    tokens.join(" ")
+}
+
+/// Whether the value matches the pattern of `match`, collecting variables bound by it.
+/// String patterns "_" is any value, ":type" is the type and "$name" binds the value,
+/// list patterns destructure lists, and other patterns are compared as literals
+fn match_pattern(pattern: &Type, value: &Type, bindings: &mut HashMap<String, Type>) -> bool {
+    match pattern {
+        Type::String(s) if s == "_" => true,
+        Type::String(s) if s.len() > 1 && s.starts_with(':') => value.type_name() == s[1..],
+        Type::String(s) if s.len() > 1 && s.starts_with('$') => {
+            bindings.insert(s[1..].to_string(), value.clone());
+            true
         }
+        Type::List(patterns) => match value {
+            Type::List(values) if values.len() == patterns.len() => patterns
+                .iter()
+                .zip(values)
+                .all(|(pattern, value)| match_pattern(pattern, value, bindings)),
+            _ => false,
+        },
+        _ => pattern == value,
     }
 }
 
-/// Manage program execution
+/// Position in the code
+#[derive(Clone, Copy, Debug, Serialize)]
+struct Span {
+    position: usize, // In characters from the start of the code
+    offset: usize,   // In bytes from the start of the code
+    line: usize,
+    column: usize,
+}
+
+/// Token of the code, with where it starts and ends
 #[derive(Clone, Debug)]
-struct Executor {
-    stack: Vec<Type>,              // Data stack
-    memory: HashMap<String, Type>, // Variable's memory
-    output: String,
-    log: String,
+struct Token {
+    text: String,
+    start: Span,
+    end: Span, // Position after the last character
 }
 
-impl Executor {
-    /// Constructor
-    fn new() -> Executor {
-        Executor {
-            stack: Vec::new(),
-            memory: HashMap::new(),
-            output: String::new(),
-            log: String::new(),
+/// Split the code into tokens, and find unbalanced parentheses, brackets, braces,
+/// comments and raw strings. Full-width ones are delimiters too if it's set
+fn lex(code: &str, full_width: bool) -> (Vec<Token>, Vec<SyntaxError>) {
+    Lexer::new(code, full_width).run()
+}
+
+/// Whether full-width brackets and hash are delimiters, unless the options keep them
+const DEFAULT_FULL_WIDTH: bool = true;
+
+/// Tokenizer that walks through the code, keeping track of the position
+struct Lexer {
+    chars: Vec<char>,
+    next: Span, // Position of the next character
+    tokens: Vec<Token>,
+    buffer: String,         // Text of the token being read
+    start: Span,            // Where the token being read starts
+    end: Span,              // Where the text added to the token last ends
+    in_brackets: isize,     // String's nest structure
+    in_parentheses: isize,  // List's nest structure
+    in_braces: isize,       // Block's nest structure
+    in_hash: bool,          // Is it Comment
+    in_block: bool,         // Is it block comment between `/*` and `*/`
+    in_raw: bool,           // Is it raw string between triple quotes
+    opened: [Vec<Span>; 3], // Unclosed ( [ {
+    wide: Vec<bool>,        // Whether each unclosed parenthesis is full-width
+    full_width: bool,       // Read full-width brackets and hash as delimiters
+    quoted: Span,           // Where the comment or raw string started last
+    errors: Vec<SyntaxError>,
+}
+
+impl Lexer {
+    fn new(code: &str, full_width: bool) -> Lexer {
+        let origin = Span {
+            position: 0,
+            offset: 0,
+            line: 1,
+            column: 1,
+        };
+        Lexer {
+            chars: code.chars().collect(),
+            next: origin,
+            tokens: Vec::new(),
+            buffer: String::new(),
+            start: origin,
+            end: origin,
+            in_brackets: 0,
+            in_parentheses: 0,
+            in_braces: 0,
+            in_hash: false,
+            in_block: false,
+            in_raw: false,
+            opened: Default::default(),
+            wide: Vec::new(),
+            full_width,
+            quoted: origin,
+            errors: Vec::new(),
         }
     }
 
-    // Log
-    fn log(&mut self, msg: String) {
-        self.log += &format!("{msg}")
+    /// Take the next character, moving the position after it
+    fn advance(&mut self) -> Option<char> {
+        let c = *self.chars.get(self.next.position)?;
+        self.next.position += 1;
+        self.next.offset += c.len_utf8();
+        if c == '\n' {
+            self.next.line += 1;
+            self.next.column = 1;
+        } else {
+            self.next.column += 1;
+        }
+        Some(c)
     }
 
-    // Print to standard output
-    fn print(&mut self, msg: String) {
-        self.output += &format!("{msg}\n")
+    /// Add text to the token, that starts at the span if it's the first
+    fn push(&mut self, span: Span, text: &str) {
+        if self.buffer.is_empty() {
+            self.start = span;
+        }
+        self.buffer.push_str(text);
+        self.end = self.next;
     }
 
-    /// Show variable inside memory
-    fn show_variables(&mut self) {
-        self.log("Variables {\n".to_string());
-        let max = self.memory.keys().map(|s| s.len()).max().unwrap_or(0);
-        for (name, value) in self.memory.clone() {
-            self.log(format!(
-                " {:>width$}: {}\n",
-                name,
-                value.display(),
-                width = max
-            ))
+    /// Finish the token being read
+    fn split(&mut self) {
+        if !self.buffer.is_empty() {
+            self.tokens.push(Token {
+                text: std::mem::take(&mut self.buffer),
+                start: self.start,
+                end: self.end,
+            });
         }
-        self.log("}\n".to_string())
     }
 
-    /// Show inside the stack
-    fn show_stack(&mut self) -> String {
-        format!(
-            "Stack〔 {} 〕",
-            self.stack
-                .iter()
-                .map(|x| x.display())
-                .collect::<Vec<_>>()
-                .join(" | ")
-        )
+    /// Report syntax error at the span
+    fn error(&mut self, message: String, span: Span) {
+        self.errors.push(SyntaxError { message, span });
     }
 
-    /// Parse token by analyzing syntax
-    fn analyze_syntax(&mut self, code: String) -> Vec<String> {
-        // Convert tabs, line breaks, and full-width spaces to half-width spaces
-        let code = code.replace(['\n', '\t', '\r', '　'], " ");
+    /// Open the bracket of the kind
+    fn open(&mut self, kind: usize, span: Span) {
+        self.opened[kind].push(span);
+    }
+
+    /// Close the innermost bracket of the kind, or report there's no one to close
+    fn close(&mut self, kind: usize, bracket: char, span: Span) {
+        if self.opened[kind].pop().is_none() {
+            self.error(format!("unexpected \"{bracket}\""), span);
+        }
+    }
 
-        let mut syntax = Vec::new(); // Token string
-        let mut buffer = String::new(); // Temporary storage
-        let mut in_brackets = 0; // String's nest structure
-        let mut in_parentheses = 0; // List's nest structure
-        let mut in_hash = false; // Is it Comment
+    /// Whether the lexer is at the top level, outside of lists, strings and blocks
+    fn at_top(&self) -> bool {
+        self.in_parentheses == 0 && self.in_brackets == 0 && self.in_braces == 0
+    }
+
+    /// Skip line comment to the end of the line. It's a token only at the top level,
+    /// as inner code loses its line breaks
+    fn line_comment(&mut self, here: Span) {
+        let mut text = String::new();
+        while let Some(c) = self.chars.get(self.next.position).filter(|x| **x != '\n') {
+            text.push(*c);
+            self.advance();
+        }
+        if self.at_top() {
+            self.split();
+            self.push(here, &text);
+            self.split();
+        }
+    }
+
+    fn run(mut self) -> (Vec<Token>, Vec<SyntaxError>) {
+        let mut escaped = false; // Is the character escaped in string
+        loop {
+            let here = self.next;
+            let rest = &self.chars[here.position..];
+            let raw_quote = rest.starts_with(&['"'; 3]);
+
+            // Comments of slashes start a token, outside of strings and other comments
+            let separated = here.position == 0 || self.chars[here.position - 1].is_whitespace();
+            let commentable =
+                separated && !self.in_hash && !self.in_raw && !escaped && self.in_brackets == 0;
+            if self.in_block && rest.starts_with(&['*', '/']) {
+                self.advance();
+                self.advance();
+                self.in_block = false;
+                self.push(here, "*/");
+                if self.at_top() {
+                    self.split();
+                }
+                continue;
+            } else if !self.in_block && commentable && rest.starts_with(&['/', '*']) {
+                self.advance();
+                self.advance();
+                self.in_block = true;
+                self.quoted = here;
+                self.push(here, "/*");
+                continue;
+            } else if !self.in_block && commentable && rest.starts_with(&['/', '/']) {
+                self.line_comment(here);
+                continue;
+            }
+
+            if !self.in_hash && !self.in_block && !escaped && raw_quote {
+                for _ in 0..RAW_QUOTE.len() {
+                    self.advance();
+                }
+                self.in_raw = !self.in_raw;
+                self.quoted = here;
+                self.push(here, RAW_QUOTE);
+                continue;
+            }
+            let Some(c) = self.advance() else {
+                break;
+            };
+            if self.in_raw || self.in_block {
+                // Raw string and block comment keep every character as it is
+                self.push(here, c.encode_utf8(&mut [0; 4]));
+                continue;
+            }
 
-        for c in code.chars() {
+            // Convert tabs, line breaks, and full-width spaces to half-width spaces
+            let c = match c {
+                '\n' | '\t' | '\r' | '　' => ' ',
+                c => c,
+            };
+            // Full-width brackets written by IME are delimiters too, except in strings.
+            // Ones nested in full-width string are kept as its text
+            let original = c;
+            let in_wide = self.wide.last() == Some(&true);
+            let narrow = match c {
+                _ if !self.full_width || escaped => None,
+                '（' if in_wide => Some('('),
+                '）' if in_wide => Some(')'),
+                _ if self.in_brackets > 0 => None,
+                '＃' => Some('#'),
+                _ if self.in_hash => None,
+                '（' => Some('('),
+                '［' => Some('['),
+                '］' => Some(']'),
+                _ => None,
+            };
+            let c = narrow.unwrap_or(c);
+            let nested = self.in_brackets > if c == ')' { 1 } else { 0 };
+            if escaped {
+                // Escaped character doesn't change the nest structure
+                escaped = false;
+                self.push(here, c.encode_utf8(&mut [0; 4]));
+                continue;
+            }
             match c {
-                '(' => {
-                    in_brackets += 1;
-                    buffer.push('(');
+                '\\' if self.in_brackets > 0 && !self.in_hash => escaped = true,
+                '(' if !self.in_hash => {
+                    self.in_brackets += 1;
+                    self.wide.push(narrow.is_some());
+                    self.open(0, here);
                 }
-                ')' => {
-                    in_brackets -= 1;
-                    buffer.push(')');
+                ')' if !self.in_hash => {
+                    self.in_brackets -= 1;
+                    self.wide.pop();
+                    self.close(0, ')', here);
                 }
-                '#' if !in_hash => {
-                    in_hash = true;
-                    buffer.push('#');
+                // Hash in string is a character, not start of comment
+                '#' if self.in_brackets == 0 && !self.in_hash => {
+                    self.in_hash = true;
+                    self.quoted = here;
                 }
-                '#' if in_hash => {
-                    in_hash = false;
-                    buffer.push('#');
+                '#' if self.in_brackets == 0 => self.in_hash = false,
+                '[' if self.in_brackets == 0 && !self.in_hash => {
+                    self.in_parentheses += 1;
+                    self.open(1, here);
                 }
-                '[' if in_brackets == 0 => {
-                    in_parentheses += 1;
-                    buffer.push('[');
+                ']' if self.in_brackets == 0 && !self.in_hash => {
+                    self.in_parentheses -= 1;
+                    self.close(1, ']', here);
                 }
-                ']' if in_brackets == 0 => {
-                    in_parentheses -= 1;
-                    buffer.push(']');
+                '{' if self.in_brackets == 0 && !self.in_hash => {
+                    self.in_braces += 1;
+                    self.open(2, here);
                 }
-                ' ' if !in_hash && in_parentheses == 0 && in_brackets == 0 => {
-                    if !buffer.is_empty() {
-                        syntax.push(buffer.clone());
-                        buffer.clear();
-                    }
+                '}' if self.in_brackets == 0 && !self.in_hash => {
+                    self.in_braces -= 1;
+                    self.close(2, '}', here);
                 }
-                _ => {
-                    buffer.push(c);
+                ' ' if !self.in_hash
+                    && self.in_parentheses == 0
+                    && self.in_brackets == 0
+                    && self.in_braces == 0 =>
+                {
+                    self.split();
+                    continue;
                 }
+                _ => {}
             }
+            let c = if nested { original } else { c };
+            self.push(here, c.encode_utf8(&mut [0; 4]));
         }
+        self.split();
 
-        if !buffer.is_empty() {
-            syntax.push(buffer);
-        }
-        syntax
-    }
-
-    /// evaluate string as program
-    fn evaluate_program(&mut self, code: String) {
-        // Parse into token string
-        let syntax: Vec<String> = self.analyze_syntax(code);
-
-        for token in syntax {
-            // Show inside stack to debug
-            let stack = self.show_stack();
-            self.log(format!("{} ←  {}\n", stack, token));
-
-            // Character vector for token processing
-            let chars: Vec<char> = token.chars().collect();
-
-            // Judge what the token is
-            if let Ok(i) = token.parse::<f64>() {
-                // Push number value on the stack
-                self.stack.push(Type::Number(i));
-            } else if token == "true" || token == "false" {
-                // Push bool value on the stack
-                self.stack.push(Type::Bool(token.parse().unwrap_or(true)));
-            } else if chars[0] == '(' && chars[chars.len() - 1] == ')' {
-                // Push string value on the stack
-                self.stack
-                    .push(Type::String(token[1..token.len() - 1].to_string()));
-            } else if chars[0] == '[' && chars[chars.len() - 1] == ']' {
-                // Push list value on the stack
-                let old_len = self.stack.len(); // length of old stack
-                let slice = &token[1..token.len() - 1];
-                self.evaluate_program(slice.to_string());
-                // Make increment of stack an element of list
-                let mut list = Vec::new();
-                for _ in old_len..self.stack.len() {
-                    list.push(self.pop_stack());
-                }
-                list.reverse(); // reverse list
-                self.stack.push(Type::List(list));
-            } else if token.starts_with("error:") {
-                // Push error value on the stack
-                self.stack.push(Type::Error(token.replace("error:", "")))
-            } else if let Some(i) = self.memory.get(&token) {
-                // Push variable's data on stack
-                self.stack.push(i.clone());
-            } else if chars[0] == '#' && chars[chars.len() - 1] == '#' {
-                // Processing comments
-                self.log(format!("* Comment \"{}\"\n", token.replace('#', "")));
-            } else {
-                // Else, execute as command
-                self.execute_command(token);
+        // Report what is left open at the end
+        for (kind, bracket) in ['(', '[', '{'].into_iter().enumerate() {
+            if let Some(span) = self.opened[kind].first().copied() {
+                self.error(format!("unclosed \"{bracket}\""), span);
             }
         }
+        if self.in_hash {
+            self.error("unclosed comment".to_string(), self.quoted);
+        }
+        if self.in_raw {
+            self.error("unclosed raw string".to_string(), self.quoted);
+        }
+        if self.in_block {
+            self.error("unclosed block comment".to_string(), self.quoted);
+        }
+        self.errors.sort_by_key(|error| error.span.position);
+        (self.tokens, self.errors)
+    }
+}
 
-        // Show inside stack, after execution
-        let stack = self.show_stack();
-        self.log(format!("{}\n", stack));
+/// Get text of the comment: `#paired#`, `// to the end of line` or `/* block */`
+fn comment_text(token: &str) -> Option<&str> {
+    if token.len() >= 2 && token.starts_with('#') && token.ends_with('#') {
+        Some(&token[1..token.len() - 1])
+    } else if let Some(text) = token.strip_prefix("//") {
+        Some(text)
+    } else {
+        token
+            .strip_prefix("/*")?
+            .strip_suffix("*/")
+            .filter(|_| token.len() >= 4)
     }
+}
 
-    /// execute string as commands
-    fn execute_command(&mut self, command: String) {
-        match command.as_str() {
-            // Commands of calculation
+/// Quotes around raw string
+const RAW_QUOTE: &str = "\"\"\"";
 
-            // addition
-            "add" => {
-                let b = self.pop_stack().get_number();
-                let a = self.pop_stack().get_number();
-                self.stack.push(Type::Number(a + b));
-            }
+/// Get text of the raw string literal, such as `"""it's (#raw)"""`
+fn raw_string(token: &str) -> Option<&str> {
+    token
+        .strip_prefix(RAW_QUOTE)?
+        .strip_suffix(RAW_QUOTE)
+        .filter(|_| token.len() >= RAW_QUOTE.len() * 2)
+}
 
-            // Subtraction
-            "sub" => {
-                let b = self.pop_stack().get_number();
-                let a = self.pop_stack().get_number();
-                self.stack.push(Type::Number(a - b));
-            }
+/// Get value of the number, bool, nil, string or list literal
+fn literal_value(token: &str, full_width: bool) -> Option<Type> {
+    if let Some(num) = parse_number(token) {
+        Some(Type::Number(num))
+    } else if token == "true" || token == "false" {
+        Some(Type::Bool(token == "true"))
+    } else if token == "nil" {
+        Some(Type::Nil)
+    } else if let Some(text) = raw_string(token) {
+        Some(Type::String(text.to_string()))
+    } else if token.len() >= 2 && token.starts_with('(') && token.ends_with(')') {
+        Some(Type::String(unescape(&token[1..token.len() - 1])))
+    } else if token.len() >= 2 && token.starts_with('[') && token.ends_with(']') {
+        // List of literals, whose elements are split by the same tokenizer
+        let (tokens, errors) = lex(&token[1..token.len() - 1], full_width);
+        let list: Option<Vec<Type>> = tokens
+            .iter()
+            .filter(|token| comment_text(&token.text).is_none())
+            .map(|token| literal_value(&token.text, full_width))
+            .collect();
+        list.filter(|_| errors.is_empty()).map(Type::List)
+    } else {
+        None
+    }
+}
 
-            // Multiplication
-            "mul" => {
-                let b = self.pop_stack().get_number();
-                let a = self.pop_stack().get_number();
-                self.stack.push(Type::Number(a * b));
+/// Decode escape sequences `\n`, `\t`, `\\`, `\(`, `\)`, `\[`, `\]`, `\#`
+/// and `\uXXXX` of the string literal.
+/// Ones in nested parentheses are kept, as they belong to the inner string
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut nest = 0; // Nest level of inner parentheses
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '"' && chars.clone().take(2).eq(['"'; 2]) {
+            // Raw string inside is kept as it is
+            result.push(c);
+            result.extend(chars.by_ref().take(2));
+            let mut quotes = 0;
+            for c in chars.by_ref() {
+                result.push(c);
+                quotes = if c == '"' { quotes + 1 } else { 0 };
+                if quotes == 3 {
+                    break;
+                }
+            }
+            continue;
+        }
+        match c {
+            '(' => nest += 1,
+            ')' => nest -= 1,
+            '\\' if nest == 0 => {
+                // Decoded character and length of the sequence after backslash
+                let decoded = match chars.peek() {
+                    Some('n') => Some(('\n', 1)),
+                    Some('t') => Some(('\t', 1)),
+                    Some(c @ ('\\' | '(' | ')' | '[' | ']' | '#')) => Some((*c, 1)),
+                    Some('u') => {
+                        let hex: String = chars.clone().skip(1).take(4).collect();
+                        let valid = hex.len() == 4 && hex.chars().all(|x| x.is_ascii_hexdigit());
+                        valid
+                            .then(|| u32::from_str_radix(&hex, 16).ok())
+                            .flatten()
+                            .and_then(char::from_u32)
+                            .map(|x| (x, 5))
+                    }
+                    _ => None,
+                };
+                if let Some((decoded, length)) = decoded {
+                    for _ in 0..length {
+                        chars.next();
+                    }
+                    result.push(decoded);
+                    continue;
+                }
+            }
+            '\\' => {
+                // Keep the escape of inner string with the escaped character
+                result.push(c);
+                if let Some(escaped) = chars.next() {
+                    result.push(escaped);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Parse numeric literal, such as `-1.5e3`, `1_000`, `0xFF`, `0b1010` and `0o17`
+fn parse_number(token: &str) -> Option<f64> {
+    let (negative, body) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let (radix, digits) = match body.get(..2) {
+        Some("0x" | "0X") => (16, &body[2..]),
+        Some("0b" | "0B") => (2, &body[2..]),
+        Some("0o" | "0O") => (8, &body[2..]),
+        _ => (10, token),
+    };
+
+    // Underscore is allowed only between digits
+    let chars: Vec<char> = digits.chars().collect();
+    for (index, c) in chars.iter().enumerate() {
+        let between = index > 0
+            && chars[index - 1].is_digit(radix)
+            && chars.get(index + 1).is_some_and(|x| x.is_digit(radix));
+        if *c == '_' && !between {
+            return None;
+        }
+    }
+    let digits = digits.replace('_', "");
+
+    if radix == 10 {
+        return digits.parse().ok();
+    }
+    if digits.starts_with(['+', '-']) {
+        return None;
+    }
+    let number = u64::from_str_radix(&digits, radix).ok()? as f64;
+    Some(if negative { -number } else { number })
+}
+
+/// Show the number, rounded by the display precision if it is set
+fn format_number(num: f64) -> String {
+    let Some(precision) = PRECISION.with(|x| x.get()).filter(|_| num.is_finite()) else {
+        return num.to_string();
+    };
+    let text = format!("{num:.precision$}");
+    let text = match text.contains('.') {
+        true => text.trim_end_matches('0').trim_end_matches('.'),
+        false => &text,
+    };
+    match text {
+        "-0" => "0".to_string(),
+        _ => text.to_string(),
+    }
+}
+
+/// Escape text to be put into HTML. ANSI escape sequences of colors become
+/// spans with classes such as "ansi-red" if `ansi` is set, or they are removed
+fn escape_html(text: &str, ansi: bool) -> String {
+    let mut html = String::new();
+    let mut open = 0; // Number of open spans
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            '\x1b' if chars.peek() == Some(&'[') => {
+                chars.next();
+                let mut params = String::new();
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        if c == 'm' && ansi {
+                            for code in params.split(';') {
+                                match ansi_class(code) {
+                                    Some(class) => {
+                                        html.push_str(&format!("<span class=\"{class}\">"));
+                                        open += 1;
+                                    }
+                                    None if code.is_empty() || code == "0" => {
+                                        html.push_str(&"</span>".repeat(open));
+                                        open = 0;
+                                    }
+                                    None => {}
+                                }
+                            }
+                        }
+                        break;
+                    }
+                    params.push(c);
+                }
+            }
+            '\x1b' => {}
+            c => html.push(c),
+        }
+    }
+    html.push_str(&"</span>".repeat(open));
+    html
+}
+
+/// Get class name of the ANSI style code
+fn ansi_class(code: &str) -> Option<String> {
+    const COLORS: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    let code: usize = code.parse().ok()?;
+    match code {
+        1 => Some("ansi-bold".to_string()),
+        4 => Some("ansi-underline".to_string()),
+        30..=37 => Some(format!("ansi-{}", COLORS[code - 30])),
+        40..=47 => Some(format!("ansi-bg-{}", COLORS[code - 40])),
+        90..=97 => Some(format!("ansi-bright-{}", COLORS[code - 90])),
+        _ => None,
+    }
+}
+
+/// Get the current time in milliseconds
+fn now_millis() -> f64 {
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        js_sys::Date::now()
+    } else {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_secs_f64() * 1000.0)
+            .unwrap_or(0.0)
+    }
+}
+
+/// Get positions (in characters) where each line starts
+fn line_starts(src: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    for (index, c) in src.chars().enumerate() {
+        if c == '\n' {
+            starts.push(index + 1);
+        }
+    }
+    starts
+}
+
+/// Minified program and its source map
+#[wasm_bindgen]
+pub struct Minified {
+    code: String,
+    map: String,
+}
+
+#[wasm_bindgen]
+impl Minified {
+    pub fn code(&self) -> String {
+        self.code.clone()
+    }
+
+    /// Source map as JSON, list of [position, line, column] of each token.
+    /// Give it to `source_map` option of `run_stack_opts`
+    pub fn map(&self) -> String {
+        self.map.clone()
+    }
+}
+
+/// Program parsed once, to be run many times by `Session::execute` or `run_program`
+#[wasm_bindgen]
+pub struct Program {
+    source: String,
+    syntax: Rc<Syntax>,
+    nested: Rc<HashMap<String, Rc<Syntax>>>, // Parsed code of its blocks and lists
+    errors: Vec<SyntaxError>,
+}
+
+#[wasm_bindgen]
+impl Program {
+    pub fn source(&self) -> String {
+        self.source.clone()
+    }
+
+    /// Whether the program has no syntax errors
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Syntax errors as JSON, list of objects of message, position, line and column
+    pub fn errors(&self) -> String {
+        serde_json::to_string(&self.errors).unwrap_or_default()
+    }
+}
+
+/// Parse the program once, finding its syntax errors before it runs
+#[wasm_bindgen]
+pub fn compile(src: &str) -> Program {
+    let (tokens, errors) = lex(src, DEFAULT_FULL_WIDTH);
+    let mut nested = HashMap::new();
+    prepare_nested(&tokens, &mut nested);
+    Program {
+        source: src.to_string(),
+        syntax: Rc::new(to_syntax(tokens, DEFAULT_FULL_WIDTH)),
+        nested: Rc::new(nested),
+        errors,
+    }
+}
+
+/// Run the compiled program with options given as JSON, empty for the default ones
+#[wasm_bindgen]
+pub fn run_program(program: &Program, options_json: &str) -> Result {
+    let mut executor = Executor::new();
+    if !options_json.trim().is_empty() {
+        match serde_json::from_str(options_json) {
+            Ok(config) => executor.config = config,
+            Err(err) => return invalid_options(err),
+        }
+    }
+    executor.run(program);
+    executor.result()
+}
+
+/// Pair each token with its command, and lower them into bytecode
+fn to_syntax(tokens: Vec<Token>, full_width: bool) -> Syntax {
+    let mut syntax = Syntax {
+        tokens: decode_tokens(tokens, full_width),
+        ..Syntax::default()
+    };
+    syntax.top = syntax.tokens.len();
+    lower(&mut syntax, 0, full_width);
+    syntax
+}
+
+/// Decode each token into its instruction
+fn decode_tokens(tokens: Vec<Token>, full_width: bool) -> Vec<(String, Op)> {
+    tokens
+        .into_iter()
+        .map(|token| {
+            let op = Op::decode(&token.text, full_width);
+            (token.text, op)
+        })
+        .collect()
+}
+
+/// Emit bytecode of the tokens from the index, inlining the loops whose code is literal
+fn lower(syntax: &mut Syntax, from: usize, full_width: bool) {
+    for index in from..syntax.tokens.len() {
+        // Literal string pushed by the token before the loop command
+        let literal = |offset: usize| match index.checked_sub(offset).filter(|i| *i >= from) {
+            Some(i) => match &syntax.tokens[i].1 {
+                Op::Push(Type::String(code)) => Some(code.clone()),
+                _ => None,
+            },
+            None => None,
+        };
+        let (condition, body) = match syntax.tokens[index].1.command() {
+            Some(Command::While | Command::Until) => match (literal(2), literal(1)) {
+                (Some(body), Some(condition)) => (Some(condition), Some(body)),
+                _ => (None, None),
+            },
+            Some(Command::For | Command::Map) => (None, literal(1)),
+            Some(Command::Times) => (None, literal(2)),
+            _ => (None, None),
+        };
+        let Some(body) = body else {
+            syntax.code.push(Instr::Token(index));
+            continue;
+        };
+
+        let head = syntax.code.len();
+        syntax.code.push(Instr::Token(index));
+        let start = syntax.code.len();
+        let gate = match condition {
+            Some(condition) => {
+                syntax.code.push(Instr::Condition);
+                inline(syntax, &condition, full_width);
+                syntax.code.push(Instr::Test { exit: 0 });
+                syntax.code.len() - 1
+            }
+            None => {
+                syntax.code.push(Instr::Next { exit: 0 });
+                start
+            }
+        };
+        inline(syntax, &body, full_width);
+        let next = syntax.code.len();
+        syntax.code.push(Instr::Iterate { start });
+        let exit = syntax.code.len();
+        syntax.code.push(Instr::Exit);
+
+        // Fill the jump targets, now that they are known
+        syntax.code[head] = Instr::Loop {
+            token: index,
+            next,
+            exit,
+        };
+        syntax.code[gate] = match syntax.code[gate] {
+            Instr::Test { .. } => Instr::Test { exit },
+            _ => Instr::Next { exit },
+        };
+    }
+}
+
+/// Append the tokens of the code, and emit its bytecode in place
+fn inline(syntax: &mut Syntax, code: &str, full_width: bool) {
+    let from = syntax.tokens.len();
+    syntax
+        .tokens
+        .extend(decode_tokens(lex(code, full_width).0, full_width));
+    lower(syntax, from, full_width);
+}
+
+/// Parse the code of blocks and lists ahead, so loops over them don't tokenize it again
+fn prepare_nested(tokens: &[Token], nested: &mut HashMap<String, Rc<Syntax>>) {
+    for token in tokens {
+        let text = &token.text;
+        let code = if raw_string(text).is_some() || text.len() < 2 {
+            continue;
+        } else if text.starts_with('(') && text.ends_with(')') {
+            unescape(&text[1..text.len() - 1])
+        } else if text.starts_with('[') && text.ends_with(']') {
+            text[1..text.len() - 1].to_string()
+        } else {
+            continue;
+        };
+        if nested.contains_key(&code) {
+            continue;
+        }
+        let (inner, _) = lex(&code, DEFAULT_FULL_WIDTH);
+        prepare_nested(&inner, nested);
+        nested.insert(code, Rc::new(to_syntax(inner, DEFAULT_FULL_WIDTH)));
+    }
+}
+
+/// Ask the user for input, by the browser's prompt
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn host_input(message: &str) -> Option<String> {
+    Some(prompt(message))
+}
+
+/// Ask the user for input, by the standard input
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn host_input(message: &str) -> Option<String> {
+    use std::io::Write;
+    print!("{message}");
+    std::io::stdout().flush().ok()?;
+    host_read_line()
+}
+
+/// Read a line of standard input without line break, there is none in the browser
+fn host_read_line() -> Option<String> {
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        return None;
+    }
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+    }
+}
+
+/// Read all of standard input, there is none in the browser
+fn host_read_all() -> Option<String> {
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        return None;
+    }
+    let mut text = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut text).ok()?;
+    Some(text)
+}
+
+#[wasm_bindgen]
+#[derive(Serialize)]
+pub struct Result {
+    output: String,
+    log: String,
+    trace: Vec<TraceStep>,           // Recorded steps, if `record_trace` is set
+    memory: Vec<(String, String)>,   // Variables at the end, if `record_trace` is set
+    audit: Vec<AuditEntry>,          // Nondeterministic commands, if `audit` is set
+    errors: Vec<RecoveredError>,     // Errors of the run, if `recover` is set
+    syntax_errors: Vec<SyntaxError>, // Errors of the program structure
+    warnings: Vec<Warning>,          // Warnings of the run, except suppressed categories
+    entries: Vec<LogEntry>,          // Entries of the log, by level and category
+    prints: Vec<usize>,              // End of the output of each print
+    status: Status,                  // How the run ended
+}
+
+/// How the run ended
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Completed,     // Reached the end without errors
+    Exited(i32),   // Stopped by `exit` with the code
+    Error,         // Reached the end, but runtime error happened
+    Aborted,       // Stopped by exception of the host hook
+    Timeout,       // Stopped by `time_limit`
+    FuelExhausted, // Stopped by `fuel`
+}
+
+/// Seriousness of the warning
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Info,    // Probably intended, but worth knowing
+    Warning, // Probably a mistake
+}
+
+/// Importance of the log entry
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    Trace, // Inside the stack at every step
+    Debug, // Details such as variables and comments
+    Info,  // Events such as definitions and thrown errors
+    Warn,
+    Error,
+}
+
+/// Part of the execution that the log entry is about
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum LogCategory {
+    Parser, // Tokens and comments of the code
+    Stack,  // Evaluation of values and commands
+    Memory, // Variables and definitions
+    Io,     // Input, output, files, processes and network
+}
+
+/// Entry of the log, for hosts that show it by level and category
+#[derive(Clone, Debug, Serialize)]
+struct LogEntry {
+    level: LogLevel,
+    category: LogCategory,
+    message: String,
+}
+
+/// Feedback about the program, that doesn't stop it
+#[derive(Clone, Debug, Serialize)]
+struct Warning {
+    category: &'static str,
+    severity: Severity,
+    message: String,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+/// Error of the code structure, found before running it
+#[derive(Clone, Debug, Serialize)]
+struct SyntaxError {
+    message: String,
+    #[serde(flatten)]
+    span: Span,
+}
+
+/// Runtime error recorded in recovery mode
+#[derive(Clone, Debug, Serialize)]
+struct RecoveredError {
+    message: String,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+/// Use of command whose result depends on outside of the program
+#[derive(Clone, Debug, Serialize)]
+struct AuditEntry {
+    command: String,
+    result: String,
+}
+
+/// Step of execution recorded to compare runs
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct TraceStep {
+    token: String,
+    stack: String, // Inside the stack after the token
+}
+
+/// Compare recorded traces of two runs, and get the first difference as JSON.
+/// It is `null` if there is no difference
+#[wasm_bindgen]
+pub fn diff_runs(result_a: &Result, result_b: &Result) -> String {
+    let difference = |step: usize, kind: &str, a: &str, b: &str| {
+        serde_json::json!({ "step": step, "kind": kind, "a": a, "b": b }).to_string()
+    };
+
+    for (step, (a, b)) in result_a.trace.iter().zip(result_b.trace.iter()).enumerate() {
+        if a.token != b.token {
+            return difference(step, "token", &a.token, &b.token);
+        }
+        if a.stack != b.stack {
+            return difference(step, "stack", &a.stack, &b.stack);
+        }
+    }
+
+    let step = result_a.trace.len().min(result_b.trace.len());
+    if result_a.trace.len() != result_b.trace.len() {
+        let token = |result: &Result| result.trace.get(step).map(|x| x.token.clone());
+        return difference(
+            step,
+            "length",
+            &token(result_a).unwrap_or_default(),
+            &token(result_b).unwrap_or_default(),
+        );
+    }
+
+    if result_a.memory != result_b.memory {
+        let show = |result: &Result| {
+            let memory: Vec<String> = result
+                .memory
+                .iter()
+                .map(|(name, value)| format!("{name}: {value}"))
+                .collect();
+            memory.join(", ")
+        };
+        return difference(step, "memory", &show(result_a), &show(result_b));
+    }
+
+    let lines_a: Vec<&str> = result_a.output.lines().collect();
+    let lines_b: Vec<&str> = result_b.output.lines().collect();
+    for line in 0..lines_a.len().max(lines_b.len()) {
+        let a = lines_a.get(line).copied().unwrap_or_default();
+        let b = lines_b.get(line).copied().unwrap_or_default();
+        if a != b {
+            return difference(line, "output", a, b);
+        }
+    }
+    "null".to_string()
+}
+
+#[wasm_bindgen]
+impl Result {
+    pub fn new(output: String, log: String) -> Self {
+        Result {
+            output,
+            log,
+            trace: Vec::new(),
+            memory: Vec::new(),
+            audit: Vec::new(),
+            errors: Vec::new(),
+            syntax_errors: Vec::new(),
+            warnings: Vec::new(),
+            entries: Vec::new(),
+            prints: Vec::new(),
+            status: Status::Completed,
+        }
+    }
+
+    /// Get how the run ended: "completed", "exited", "error", "aborted",
+    /// "timeout" or "fuel-exhausted"
+    pub fn status(&self) -> String {
+        match self.status {
+            Status::Completed => "completed",
+            Status::Exited(_) => "exited",
+            Status::Error => "error",
+            Status::Aborted => "aborted",
+            Status::Timeout => "timeout",
+            Status::FuelExhausted => "fuel-exhausted",
+        }
+        .to_string()
+    }
+
+    /// Get the code given to `exit`, if the program exited by it
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.status {
+            Status::Exited(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    pub fn output(&self) -> String {
+        self.output.clone()
+    }
+
+    pub fn log(&self) -> String {
+        self.log.clone()
+    }
+
+    /// Get output escaped as HTML, where ANSI colors become spans if `ansi` is set
+    pub fn output_html(&self, ansi: bool) -> String {
+        escape_html(&self.output, ansi)
+    }
+
+    /// Get log escaped as HTML, where ANSI colors become spans if `ansi` is set
+    pub fn log_html(&self, ansi: bool) -> String {
+        escape_html(&self.log, ansi)
+    }
+
+    /// Get output as JSON list of events {kind, text}, each for a print
+    pub fn output_events(&self) -> String {
+        let mut start = 0;
+        let events: Vec<serde_json::Value> = self
+            .prints
+            .iter()
+            .map(|end| {
+                let text = self.output[start..*end].trim_end_matches('\n');
+                start = *end;
+                serde_json::json!({ "kind": "print", "text": text })
+            })
+            .collect();
+        serde_json::Value::Array(events).to_string()
+    }
+
+    /// Get nondeterministic commands used in the run as JSON,
+    /// list of {command, result}. It is recorded by `audit` option
+    pub fn audit(&self) -> String {
+        serde_json::to_string(&self.audit).unwrap_or_default()
+    }
+
+    /// Get all runtime errors of the run as JSON, list of {message, line, column}.
+    /// It is recorded by `recover` option
+    pub fn errors(&self) -> String {
+        serde_json::to_string(&self.errors).unwrap_or_default()
+    }
+
+    /// Get syntax errors of the program as JSON, list of {message, position, offset, line, column},
+    /// such as unbalanced parentheses, brackets and braces, and unclosed comments
+    pub fn syntax_errors(&self) -> String {
+        serde_json::to_string(&self.syntax_errors).unwrap_or_default()
+    }
+
+    /// Get entries of the log as JSON, list of {level, category, message}
+    pub fn log_entries(&self) -> String {
+        serde_json::to_string(&self.entries).unwrap_or_default()
+    }
+
+    /// Get warnings of the run as JSON, list of {category, severity, message, line, column}
+    pub fn warnings(&self) -> String {
+        serde_json::to_string(&self.warnings).unwrap_or_default()
+    }
+
+    /// Get output as UTF-8 bytes, to be decoded by `TextDecoder` in JavaScript.
+    /// It is copied once from the wasm memory, without making a JS string
+    pub fn output_bytes(&self) -> js_sys::Uint8Array {
+        js_sys::Uint8Array::from(self.output.as_bytes())
+    }
+
+    /// Encode the result by MessagePack, that is smaller and faster than JSON
+    #[cfg(feature = "msgpack")]
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).unwrap_or_default()
+    }
+}
+
+/// Maximum number of save slots in a session
+const SLOT_COUNT_LIMIT: usize = 16;
+/// Maximum total size of all save slots (in bytes of displayed values)
+const SLOT_SIZE_LIMIT: usize = 1024 * 1024;
+
+/// Executor that keeps its state between runs
+#[wasm_bindgen]
+pub struct Session {
+    executor: Executor,
+    slots: HashMap<String, Snapshot>,
+    paused: bool, // Events are kept until it's resumed
+}
+
+#[wasm_bindgen]
+impl Session {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Session {
+            executor: Executor::new(),
+            slots: HashMap::new(),
+            paused: false,
+        }
+    }
+
+    /// Make session whose time comes from the clock function, random numbers from the
+    /// random function and `input` answers from the array, for deterministic tests
+    pub fn with_providers(
+        clock: Option<js_sys::Function>,
+        random: Option<js_sys::Function>,
+        inputs: js_sys::Array,
+    ) -> Session {
+        let inputs = inputs.iter().filter_map(|x| x.as_string()).collect();
+        Session {
+            executor: Executor::with_providers(clock, random, inputs),
+            slots: HashMap::new(),
+            paused: false,
+        }
+    }
+
+    /// Run program on the current state
+    pub fn run(&mut self, src: &str) -> Result {
+        self.executor.reset_run();
+        self.executor.evaluate_program(src.to_string());
+        self.executor.result()
+    }
+
+    /// Run the compiled program against the state, without parsing it again.
+    /// The options are used only in this run, or the session's ones if it's empty.
+    /// The program that has syntax errors isn't run
+    pub fn execute(&mut self, program: &Program, options_json: &str) -> Result {
+        let config: Option<Config> = match options_json.trim() {
+            "" => None,
+            json => match serde_json::from_str(json) {
+                Ok(config) => Some(config),
+                Err(err) => return invalid_options(err),
+            },
+        };
+
+        self.executor.reset_run();
+        let outer = config.map(|config| std::mem::replace(&mut self.executor.config, config));
+        self.executor.run(program);
+        if let Some(outer) = outer {
+            self.executor.config = outer;
+        }
+        self.executor.result()
+    }
+
+    /// Apply definitions of the new source, keeping the stack, variables and event handlers.
+    /// Only the definitions by literals, such as `(body) (name) define`, are evaluated,
+    /// on a copy of the state. Constants that already exist are kept
+    pub fn hot_reload(&mut self, src: &str) -> Result {
+        let mut scratch = self.executor.clone();
+        scratch.reset_run();
+        scratch.stack.clear();
+
+        let full_width = scratch.full_width();
+        let tokens = lex(src, full_width).0;
+        for (index, token) in tokens.iter().enumerate() {
+            let command = Command::parse(&token.text);
+            let Some(&(command, arity)) = DEFINITIONS.iter().find(|x| Some(x.0) == command) else {
+                continue;
+            };
+            let Some(args) = index.checked_sub(arity).map(|start| &tokens[start..index]) else {
+                continue;
+            };
+            if !args
+                .iter()
+                .all(|x| literal_value(&x.text, full_width).is_some())
+            {
+                continue;
+            }
+            let name = literal_value(&args[arity - 1].text, full_width).map(|mut x| x.get_string());
+            let constant = matches!(command, Command::Const | Command::Defenum);
+            if constant && name.is_some_and(|x| self.executor.memory.contains_key(&x)) {
+                continue;
+            }
+
+            // Doc comment just before the definition
+            let doc = (index - arity)
+                .checked_sub(1)
+                .map(|x| &tokens[x].text)
+                .filter(|x| comment_text(x).is_some_and(|x| x.starts_with(':')));
+            let code: Vec<&str> = doc
+                .into_iter()
+                .chain(args.iter().map(|x| &x.text))
+                .chain([&token.text])
+                .map(|x| x.as_str())
+                .collect();
+            scratch.evaluate_program(code.join(" "));
+        }
+
+        for (name, value) in scratch.memory.drain() {
+            self.executor.memory.entry(name).or_insert(value);
+        }
+        self.executor.constants = std::mem::take(&mut scratch.constants);
+        self.executor.structs = std::mem::take(&mut scratch.structs);
+        self.executor.docs = std::mem::take(&mut scratch.docs);
+        self.executor.words = std::mem::take(&mut scratch.words);
+        self.executor.signatures = std::mem::take(&mut scratch.signatures);
+        self.executor.memo = std::mem::take(&mut scratch.memo);
+        self.executor.forget_purity();
+
+        scratch.result()
+    }
+
+    /// Run handlers of the events that happened after the last run
+    pub fn dispatch_events(&mut self) -> Result {
+        self.executor.reset_run();
+        if !self.paused {
+            self.executor.dispatch_messages();
+        }
+        self.executor.result()
+    }
+
+    /// Stop handling events, keeping them and the state for `resume`
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Handle events again, including the ones received while it was paused
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Whether handling of events is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Change options of execution by JSON, returns whether it is valid
+    pub fn configure(&mut self, options_json: &str) -> bool {
+        match serde_json::from_str(options_json) {
+            Ok(config) => {
+                self.executor.config = config;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Set function called with the capability name, the first time a program
+    /// uses a capability that is not allowed by options. It returns whether to allow
+    pub fn set_permission_callback(&mut self, callback: js_sys::Function) {
+        self.executor.permission_callback = Some(callback);
+        self.executor.permissions.clear();
+    }
+
+    /// Set function called with URL, that returns text of the resource or null.
+    /// It's used by `import-url`
+    pub fn set_fetch_callback(&mut self, callback: js_sys::Function) {
+        self.executor.fetch_callback = Some(callback);
+    }
+
+    /// Load package by JSON manifest of name, version, exports and source.
+    /// The exports are brought into scope by the `use` command
+    pub fn load_package(&mut self, package_json: &str) -> bool {
+        match serde_json::from_str::<Package>(package_json) {
+            Ok(package) => {
+                self.executor.packages.insert(package.name.clone(), package);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Get JSON array of the words defined by `define`, with their documentation,
+    /// signatures and whether they are memoized. Variables are not included
+    pub fn export_definitions(&self) -> String {
+        let executor = &self.executor;
+        let mut names: Vec<&String> = executor.words.keys().collect();
+        names.sort();
+        let definitions: Vec<Definition> = names
+            .into_iter()
+            .map(|name| Definition {
+                name: name.clone(),
+                code: executor.words[name].clone(),
+                doc: executor.docs.get(name).cloned(),
+                signature: executor
+                    .signatures
+                    .get(name)
+                    .map(|x| format!("{} -- {}", x.inputs.join(" "), x.outputs.join(" "))),
+                memoized: executor.memo.contains_key(name),
+            })
+            .collect();
+        serde_json::to_string(&definitions).unwrap_or_default()
+    }
+
+    /// Define words from JSON made by `export_definitions`, replacing ones of the
+    /// same names. Returns whether they are valid, and nothing is defined if not
+    pub fn import_definitions(&mut self, definitions_json: &str) -> bool {
+        let Ok(definitions) = serde_json::from_str::<Vec<Definition>>(definitions_json) else {
+            return false;
+        };
+        let valid = definitions.iter().all(|x| {
+            Command::parse(&x.name).is_none()
+                && x.signature
+                    .as_deref()
+                    .is_none_or(|x| Signature::parse(x).is_some())
+                && (!x.memoized || x.signature.is_some())
+        });
+        if !valid {
+            return false;
+        }
+
+        let executor = &mut self.executor;
+        executor.forget_purity();
+        for definition in definitions {
+            let name = definition.name;
+            executor.memo.remove(&name);
+            if definition.memoized {
+                executor.memo.insert(name.clone(), HashMap::new());
+            }
+            match definition.signature.as_deref().and_then(Signature::parse) {
+                Some(signature) => executor.signatures.insert(name.clone(), signature),
+                None => executor.signatures.remove(&name),
+            };
+            match definition.doc {
+                Some(doc) => executor.docs.insert(name.clone(), doc),
+                None => executor.docs.remove(&name),
+            };
+            executor.words.insert(name, definition.code);
+        }
+        true
+    }
+
+    /// Register library of words by its source, to be loaded by `import`
+    /// under a prefixed namespace such as "math.clamp"
+    pub fn register_module(&mut self, name: &str, source: &str) {
+        self.executor
+            .modules
+            .insert(name.to_string(), source.to_string());
+    }
+
+    /// Add the path to the virtual file system, which `glob` lists instead of the real one.
+    /// In the browser, it's the only file system
+    pub fn add_file(&mut self, path: &str) {
+        self.executor.files.insert(path.to_string());
+    }
+
+    /// Set function called at the hook point: "before-command", "after-command",
+    /// "on-push", "on-pop", "on-var-write", "on-usage" or "on-output". Returns whether
+    /// the kind is known. An exception thrown by the function aborts the execution.
+    /// "on-usage" is called after each run with JSON object of how many times each
+    /// built-in command ran, without names or values of the program.
+    /// "on-output" receives printed text that doesn't fit in `output_limit`
+    pub fn set_hook(&mut self, kind: &str, callback: js_sys::Function) -> bool {
+        let hooks = &mut self.executor.hooks;
+        let hook = match kind {
+            "before-command" => &mut hooks.before_command,
+            "after-command" => &mut hooks.after_command,
+            "on-push" => &mut hooks.on_push,
+            "on-pop" => &mut hooks.on_pop,
+            "on-var-write" => &mut hooks.on_var_write,
+            "on-usage" => &mut hooks.on_usage,
+            "on-output" => &mut hooks.on_output,
+            _ => return false,
+        };
+        *hook = Some(callback);
+        true
+    }
+
+    /// Define constant variable by JSON value, that scripts can read but not change.
+    /// Returns whether the value is valid
+    pub fn set_const(&mut self, name: &str, value_json: &str) -> bool {
+        let Some(value) = serde_json::from_str(value_json)
+            .ok()
+            .and_then(|value| Type::from_json(&value))
+        else {
+            return false;
+        };
+        self.executor.memory.insert(name.to_string(), value);
+        self.executor.constants.insert(name.to_string());
+        true
+    }
+
+    /// Push string value on the stack
+    pub fn push_string(&mut self, value: &str) {
+        self.executor.stack.push(Type::String(value.to_string()));
+    }
+
+    /// Remove all values from the stack, keeping variables and words
+    pub fn clear_stack(&mut self) {
+        self.executor.stack.clear();
+    }
+
+    /// Save stack and memory to the named slot
+    pub fn save_slot(&mut self, name: &str) -> bool {
+        let snapshot = Snapshot {
+            stack: self.executor.stack.clone(),
+            memory: self.executor.memory.clone(),
+        };
+
+        let used: usize = self
+            .slots
+            .iter()
+            .filter(|(key, _)| key.as_str() != name)
+            .map(|(_, slot)| slot.size())
+            .sum();
+        let is_new = !self.slots.contains_key(name);
+        if (is_new && self.slots.len() >= SLOT_COUNT_LIMIT)
+            || used + snapshot.size() > SLOT_SIZE_LIMIT
+        {
+            return false;
+        }
+
+        self.slots.insert(name.to_string(), snapshot);
+        true
+    }
+
+    /// Restore stack and memory from the named slot
+    pub fn load_slot(&mut self, name: &str) -> bool {
+        if let Some(snapshot) = self.slots.get(name) {
+            self.executor.stack = snapshot.stack.clone();
+            self.executor.memory = snapshot.memory.clone();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Delete the named slot
+    pub fn delete_slot(&mut self, name: &str) -> bool {
+        self.slots.remove(name).is_some()
+    }
+
+    /// Get names of saved slots
+    pub fn slots(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.slots.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Get JSON array of the documented words, as objects of name and doc
+    pub fn command_catalog(&self) -> String {
+        let mut words: Vec<(&String, &String)> = self.executor.docs.iter().collect();
+        words.sort();
+        let words: Vec<serde_json::Value> = words
+            .into_iter()
+            .map(|(name, doc)| serde_json::json!({ "name": name, "doc": doc }))
+            .collect();
+        serde_json::Value::Array(words).to_string()
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cells of program sharing one executor, like a notebook
+#[wasm_bindgen]
+pub struct Notebook {
+    executor: Executor,
+    cells: Vec<Cell>,
+}
+
+/// Cell of notebook
+#[derive(Clone, Debug, Default)]
+struct Cell {
+    src: String,
+    access: Access, // Variables and words used in the last run
+    stale: bool,    // Variables or words it reads are changed after the last run
+}
+
+#[wasm_bindgen]
+impl Notebook {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Notebook {
+            executor: Executor::new(),
+            cells: Vec::new(),
+        }
+    }
+
+    /// Add cell and get its index
+    pub fn add_cell(&mut self, src: &str) -> usize {
+        self.cells.push(Cell {
+            src: src.to_string(),
+            ..Default::default()
+        });
+        self.cells.len() - 1
+    }
+
+    /// Change source of the cell
+    pub fn update_cell(&mut self, index: usize, src: &str) -> bool {
+        if let Some(cell) = self.cells.get_mut(index) {
+            cell.src = src.to_string();
+            cell.stale = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get indices of cells that should be run again
+    pub fn stale_cells(&self) -> Vec<usize> {
+        (0..self.cells.len())
+            .filter(|index| self.cells[*index].stale)
+            .collect()
+    }
+
+    /// Run stale cells again in order, and get their results
+    pub fn run_stale(&mut self) -> Vec<Result> {
+        let mut results = Vec::new();
+        // Running a cell can make later cells stale
+        for index in 0..self.cells.len() {
+            if self.cells[index].stale {
+                results.push(self.run_cell(index));
+            }
+        }
+        results
+    }
+
+    /// Get number of cells
+    pub fn cell_count(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Run the cell on the shared state
+    pub fn run_cell(&mut self, index: usize) -> Result {
+        let Some(src) = self.cells.get(index).map(|cell| cell.src.clone()) else {
+            return Result::new(
+                String::new(),
+                format!("Error! the cell {index} is not found\n"),
+            );
+        };
+
+        self.executor.reset_run();
+        self.executor.access = Some(Access::default());
+        self.executor.evaluate_program(src);
+        let access = self.executor.access.take().unwrap_or_default();
+
+        // Other cells that read the written variables or words become stale
+        for (other, cell) in self.cells.iter_mut().enumerate() {
+            if other != index && !cell.access.reads.is_disjoint(&access.writes) {
+                cell.stale = true;
+            }
+        }
+        self.cells[index].access = access;
+        self.cells[index].stale = false;
+
+        self.executor.result()
+    }
+
+    /// Run all cells in order
+    pub fn run_all(&mut self) -> Vec<Result> {
+        (0..self.cells.len())
+            .map(|index| self.run_cell(index))
+            .collect()
+    }
+}
+
+impl Default for Notebook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Saved state of executor
+#[derive(Clone, Debug)]
+struct Snapshot {
+    stack: Vec<Type>,
+    memory: HashMap<String, Type>,
+}
+
+impl Snapshot {
+    /// Approximate size of saved data
+    fn size(&self) -> usize {
+        let stack: usize = self.stack.iter().map(|x| x.display().len()).sum();
+        let memory: usize = self
+            .memory
+            .iter()
+            .map(|(name, value)| name.len() + value.display().len())
+            .sum();
+        stack + memory
+    }
+}
+
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Maximum number of warnings recorded in a run
+const WARNING_LIMIT: usize = 256;
+
+/// Number of times typed input commands ask, until the answer is valid
+const INPUT_ATTEMPTS: usize = 3;
+
+/// Maximum number of items that range generates
+const RANGE_LIMIT: usize = 1 << 24;
+
+/// Maximum number of values of a lazy range, whose indices are exact in f64
+const LAZY_LIMIT: f64 = (1u64 << 53) as f64;
+
+/// Maximum number of results cached for each memoized word
+const MEMO_LIMIT: usize = 4096;
+
+/// Parsed program, its tokens with the instruction each of them is decoded to,
+/// and the bytecode that runs them
+#[derive(Debug, Default)]
+struct Syntax {
+    tokens: Vec<(String, Op)>,
+    code: Vec<Instr>,
+    top: usize, // Number of tokens of the program itself, before the ones of inlined loops
+    errors: Vec<SyntaxError>, // Structure errors found by the lexer
+}
+
+/// Instruction of the bytecode. Loops over literal code run it inline,
+/// jumping back to the condition or next item instead of evaluating the code again
+#[derive(Clone, Copy, Debug)]
+enum Instr {
+    /// Run the token of the index
+    Token(usize),
+    /// Start the loop command of the token inline, or run it as command and jump after `exit`
+    Loop {
+        token: usize,
+        next: usize,
+        exit: usize,
+    },
+    /// Start the condition of while or until
+    Condition,
+    /// Jump to the exit unless the condition continues the loop
+    Test { exit: usize },
+    /// Bind the next item or counter of the loop, or jump to the exit if it's over
+    Next { exit: usize },
+    /// Finish the iteration, and jump back to the start of the next one
+    Iterate { start: usize },
+    /// Finish the loop
+    Exit,
+}
+
+/// Code of running block or word, its scope, and whether the scope is captured by block
+type Frame = (String, HashMap<String, Type>, bool);
+
+/// Instruction of a token, decided when it's parsed so running it again doesn't inspect the text
+#[derive(Clone, Debug)]
+enum Op {
+    /// Push the literal value
+    Push(Type),
+    /// Evaluate the code, collecting what it pushes into a list
+    List(String),
+    /// Push the tokens as a block, without evaluating them
+    Block(Vec<String>),
+    /// Skip the comment, keeping it as doc of the next definition if it's one
+    Comment,
+    /// Push variable, call word or execute the built-in command that it names
+    Word(Option<Command>),
+}
+
+impl Op {
+    /// Decode the token into its instruction
+    fn decode(token: &str, full_width: bool) -> Op {
+        let enclosed = |open: char, close: char| {
+            token.len() >= 2 && token.starts_with(open) && token.ends_with(close)
+        };
+        let inner = || token[1..token.len() - 1].to_string();
+        if let Some(i) = parse_number(token) {
+            Op::Push(Type::Number(i))
+        } else if token == "true" || token == "false" {
+            Op::Push(Type::Bool(token == "true"))
+        } else if token == "nil" {
+            Op::Push(Type::Nil)
+        } else if let Some(text) = raw_string(token) {
+            Op::Push(Type::String(text.to_string()))
+        } else if enclosed('(', ')') {
+            Op::Push(Type::String(unescape(&inner())))
+        } else if enclosed('[', ']') {
+            Op::List(inner())
+        } else if enclosed('{', '}') {
+            Op::Block(
+                lex(&inner(), full_width)
+                    .0
+                    .into_iter()
+                    .map(|x| x.text)
+                    .collect(),
+            )
+        } else if let Some(kind) = token.strip_prefix("error:") {
+            Op::Push(Type::Error(kind.to_string()))
+        } else if comment_text(token).is_some() {
+            Op::Comment
+        } else {
+            Op::Word(Command::parse(token))
+        }
+    }
+
+    /// Built-in command of the word
+    fn command(&self) -> Option<Command> {
+        match self {
+            Op::Word(builtin) => *builtin,
+            _ => None,
+        }
+    }
+}
+
+/// Maximum number of results cached for pure code
+const PURE_CACHE_LIMIT: usize = 4096;
+
+/// Maximum number of programs kept in the syntax cache
+const SYNTAX_CACHE_LIMIT: usize = 1024;
+
+thread_local! {
+    /// Tokenized programs shared by all executors of the wasm instance,
+    /// so sessions and loop bodies don't parse the same code again,
+    /// by the code and whether full-width delimiters are read
+    static SYNTAX_CACHE: RefCell<HashMap<(String, bool), Rc<Syntax>>> =
+        RefCell::new(HashMap::new());
+
+    /// Digits after the decimal point in number display of the running program
+    static PRECISION: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+/// How much is written into the log
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+enum TraceLevel {
+    Off,    // Nothing
+    Errors, // Only errors and warnings
+    #[default]
+    Full, // Also inside the stack at every step
+}
+
+/// Options of program execution
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct Config {
+    trace: TraceLevel,
+    fuel: Option<u64>,             // Maximum number of evaluated tokens
+    strict: bool,                  // Unknown commands are errors, not strings
+    inputs: VecDeque<String>,      // Answers to the input command
+    disabled: HashSet<String>,     // Commands that can't be used
+    capabilities: HashSet<String>, // Allowed access to the host, such as "fs"
+    source_map: Option<Vec<(usize, usize, usize)>>, // Position, line and column of tokens
+    record_trace: bool,            // Record every step to compare runs
+    audit: bool,                   // Record nondeterministic commands and their results
+    checked: bool,                 // Validate values against signatures of words
+    optimize: bool,                // Cache results of pure code such as bodies of map
+    recover: bool,                 // Collect errors, replacing error values with 0
+    suppress: HashSet<String>,     // Categories of warnings not to be reported
+    args: Vec<String>,             // Arguments of the program, got by `args`
+    time_limit: Option<f64>,       // Maximum time of a run in milliseconds
+    precision: Option<usize>,      // Digits after the decimal point when numbers are shown
+    checked_math: bool,            // Division by zero, NaN and infinite results are errors
+    log_level: Option<LogLevel>,   // Lowest level written in the log, instead of `trace`
+    log_categories: HashSet<LogCategory>, // Categories written in the log, or all if empty
+    output_limit: Option<usize>,   // Maximum bytes of output kept, beyond which it spills
+    keep_full_width: bool,         // Full-width brackets and hash are characters, not delimiters
+    seed: Option<u64>,             // Seed of random numbers, to repeat the same run
+    #[serde(flatten)]
+    unknown: BTreeMap<String, serde_json::Value>, // Options that don't exist, which are warned
+}
+
+/// Result of the run that isn't started, because options can't be parsed
+fn invalid_options(err: serde_json::Error) -> Result {
+    let mut result = Result::new(String::new(), format!("Error! invalid options: {err}\n"));
+    result.status = Status::Error;
+    result
+}
+
+/// Stack effect of a word, such as `n n -- n`
+#[derive(Clone, Debug)]
+struct Signature {
+    inputs: Vec<String>,  // Types of consumed values, from the deepest
+    outputs: Vec<String>, // Types of produced values, from the deepest
+}
+
+impl Signature {
+    /// Parse the declaration, whose types are names or initials of types
+    fn parse(declaration: &str) -> Option<Signature> {
+        let (inputs, outputs) = declaration.split_once("--")?;
+        let types = |part: &str| -> Vec<String> {
+            part.split_whitespace()
+                .map(|name| match name {
+                    "n" => "number".to_string(),
+                    "s" => "string".to_string(),
+                    "b" => "bool".to_string(),
+                    "l" => "list".to_string(),
+                    other => other.to_string(),
+                })
+                .collect()
+        };
+        Some(Signature {
+            inputs: types(inputs),
+            outputs: types(outputs),
+        })
+    }
+
+    /// Split the stack effect declared at the start of the body of `define`,
+    /// such as `( n n -- n ) add`, from the code
+    fn split_declared(body: &str, full_width: bool) -> Option<(Signature, String)> {
+        let (tokens, _) = lex(body, full_width);
+        let first = tokens.first()?;
+        let Op::Push(Type::String(declaration)) = Op::decode(&first.text, full_width) else {
+            return None;
+        };
+        if !declaration.split_whitespace().any(|x| x == "--") {
+            return None;
+        }
+        let signature = Signature::parse(&declaration)?;
+        Some((signature, body[first.end.offset..].trim_start().to_string()))
+    }
+
+    /// Whether the value matches the type, where unknown types match anything
+    fn accepts(expected: &str, value: &Type) -> bool {
+        match expected {
+            "number" | "string" | "bool" | "list" | "error" | "set" | "nil" | "bytes" => {
+                value.type_name() == expected
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Another name of a command
+struct Alias {
+    name: &'static str,
+    command: &'static str,
+    deprecated: bool, // Warn when it is used
+}
+
+/// Built-in command, resolved once when the program is parsed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Round,
+    Neg,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    Sin,
+    Cos,
+    Tan,
+    Random,
+    NowTime,
+    Sleep,
+    And,
+    Or,
+    Not,
+    Equal,
+    ApproxEqual,
+    Precision,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    NotEqual,
+    Repeat,
+    Decode,
+    Encode,
+    Concat,
+    Interp,
+    Replace,
+    Split,
+    Tokenize,
+    Join,
+    Find,
+    Contains,
+    SbNew,
+    SbPush,
+    SbBuild,
+    Bytes,
+    BytesToString,
+    BytesToList,
+    BytesSlice,
+    BytesConcat,
+    Input,
+    InputNumber,
+    InputBool,
+    InputChoice,
+    ReadLine,
+    ReadAll,
+    Print,
+    ArgsCmd,
+    Args,
+    Exit,
+    IsNil,
+    IsNan,
+    IsFinite,
+    Throw,
+    Try,
+    ConcatList,
+    Prepend,
+    Spread,
+    CollectN,
+    PathJoin,
+    PathBasename,
+    PathExt,
+    Glob,
+    Exec,
+    WsConnect,
+    WsSend,
+    WsOnMessage,
+    ImportUrl,
+    Use,
+    Import,
+    WithPrefix,
+    Eval,
+    Call,
+    Recurse,
+    If,
+    Select,
+    Match,
+    Case,
+    While,
+    Until,
+    LoopIndex,
+    Break,
+    Continue,
+    Get,
+    Set,
+    Del,
+    Append,
+    Insert,
+    Sort,
+    SortWith,
+    Reverse,
+    For,
+    Times,
+    QueueNew,
+    Enqueue,
+    Dequeue,
+    StackNew,
+    PushTo,
+    PopFrom,
+    HeapNew,
+    HeapPush,
+    HeapPop,
+    HeapPeek,
+    Map,
+    Filter,
+    GroupBy,
+    Frequencies,
+    DictMerge,
+    DictMapValues,
+    DictFilter,
+    DictToList,
+    ListToDict,
+    Reduce,
+    Range,
+    RangeInclusive,
+    FindAllIndices,
+    LazyRange,
+    LazyMap,
+    LazyTake,
+    Collect,
+    Len,
+    SetNew,
+    SetAdd,
+    SetHas,
+    SetRemove,
+    ListToSet,
+    SetToList,
+    Pop,
+    Depth,
+    Var,
+    Local,
+    Const,
+    Type,
+    Cast,
+    OnlyNumber,
+    Mem,
+    NsList,
+    NsCopy,
+    NsClear,
+    Signature,
+    Define,
+    Defstruct,
+    Defenum,
+    Memoize,
+    Help,
+    Free,
+    Dup,
+    Swap,
+    Instance,
+    Property,
+    Method,
+    Modify,
+    Struct,
+    New,
+    FieldGet,
+    FieldSet,
+    All,
+}
+
+/// Names of the built-in commands
+const COMMANDS: &[(&str, Command)] = &[
+    ("add", Command::Add),
+    ("sub", Command::Sub),
+    ("mul", Command::Mul),
+    ("div", Command::Div),
+    ("mod", Command::Mod),
+    ("pow", Command::Pow),
+    ("round", Command::Round),
+    ("neg", Command::Neg),
+    ("bit-and", Command::BitAnd),
+    ("bit-or", Command::BitOr),
+    ("bit-xor", Command::BitXor),
+    ("bit-not", Command::BitNot),
+    ("shl", Command::Shl),
+    ("shr", Command::Shr),
+    ("sin", Command::Sin),
+    ("cos", Command::Cos),
+    ("tan", Command::Tan),
+    ("random", Command::Random),
+    ("now-time", Command::NowTime),
+    ("sleep", Command::Sleep),
+    ("and", Command::And),
+    ("or", Command::Or),
+    ("not", Command::Not),
+    ("equal", Command::Equal),
+    ("approx-equal", Command::ApproxEqual),
+    ("precision", Command::Precision),
+    ("less", Command::Less),
+    ("greater", Command::Greater),
+    ("less-equal", Command::LessEqual),
+    ("greater-equal", Command::GreaterEqual),
+    ("not-equal", Command::NotEqual),
+    ("repeat", Command::Repeat),
+    ("decode", Command::Decode),
+    ("encode", Command::Encode),
+    ("concat", Command::Concat),
+    ("interp", Command::Interp),
+    ("replace", Command::Replace),
+    ("split", Command::Split),
+    ("tokenize", Command::Tokenize),
+    ("join", Command::Join),
+    ("find", Command::Find),
+    ("contains", Command::Contains),
+    ("sb-new", Command::SbNew),
+    ("sb-push", Command::SbPush),
+    ("sb-build", Command::SbBuild),
+    ("bytes", Command::Bytes),
+    ("bytes-to-string", Command::BytesToString),
+    ("bytes-to-list", Command::BytesToList),
+    ("bytes-slice", Command::BytesSlice),
+    ("bytes-concat", Command::BytesConcat),
+    ("input", Command::Input),
+    ("input-number", Command::InputNumber),
+    ("input-bool", Command::InputBool),
+    ("input-choice", Command::InputChoice),
+    ("read-line", Command::ReadLine),
+    ("read-all", Command::ReadAll),
+    ("print", Command::Print),
+    ("args-cmd", Command::ArgsCmd),
+    ("args", Command::Args),
+    ("exit", Command::Exit),
+    ("is-nil", Command::IsNil),
+    ("nan?", Command::IsNan),
+    ("finite?", Command::IsFinite),
+    ("throw", Command::Throw),
+    ("try", Command::Try),
+    ("concat-list", Command::ConcatList),
+    ("prepend", Command::Prepend),
+    ("spread", Command::Spread),
+    ("collect-n", Command::CollectN),
+    ("path-join", Command::PathJoin),
+    ("path-basename", Command::PathBasename),
+    ("path-ext", Command::PathExt),
+    ("glob", Command::Glob),
+    ("exec", Command::Exec),
+    ("ws-connect", Command::WsConnect),
+    ("ws-send", Command::WsSend),
+    ("ws-on-message", Command::WsOnMessage),
+    ("import-url", Command::ImportUrl),
+    ("use", Command::Use),
+    ("import", Command::Import),
+    ("with-prefix", Command::WithPrefix),
+    ("eval", Command::Eval),
+    ("call", Command::Call),
+    ("recurse", Command::Recurse),
+    ("if", Command::If),
+    ("select", Command::Select),
+    ("match", Command::Match),
+    ("case", Command::Case),
+    ("while", Command::While),
+    ("until", Command::Until),
+    ("loop-index", Command::LoopIndex),
+    ("break", Command::Break),
+    ("continue", Command::Continue),
+    ("get", Command::Get),
+    ("set", Command::Set),
+    ("del", Command::Del),
+    ("append", Command::Append),
+    ("insert", Command::Insert),
+    ("sort", Command::Sort),
+    ("sort-with", Command::SortWith),
+    ("reverse", Command::Reverse),
+    ("for", Command::For),
+    ("times", Command::Times),
+    ("queue-new", Command::QueueNew),
+    ("enqueue", Command::Enqueue),
+    ("dequeue", Command::Dequeue),
+    ("stack-new", Command::StackNew),
+    ("push-to", Command::PushTo),
+    ("pop-from", Command::PopFrom),
+    ("heap-new", Command::HeapNew),
+    ("heap-push", Command::HeapPush),
+    ("heap-pop", Command::HeapPop),
+    ("heap-peek", Command::HeapPeek),
+    ("map", Command::Map),
+    ("filter", Command::Filter),
+    ("group-by", Command::GroupBy),
+    ("frequencies", Command::Frequencies),
+    ("dict-merge", Command::DictMerge),
+    ("dict-map-values", Command::DictMapValues),
+    ("dict-filter", Command::DictFilter),
+    ("dict-to-list", Command::DictToList),
+    ("list-to-dict", Command::ListToDict),
+    ("reduce", Command::Reduce),
+    ("range", Command::Range),
+    ("range-inclusive", Command::RangeInclusive),
+    ("find-all-indices", Command::FindAllIndices),
+    ("lazy-range", Command::LazyRange),
+    ("lazy-map", Command::LazyMap),
+    ("lazy-take", Command::LazyTake),
+    ("collect", Command::Collect),
+    ("len", Command::Len),
+    ("set-new", Command::SetNew),
+    ("set-add", Command::SetAdd),
+    ("set-has", Command::SetHas),
+    ("set-remove", Command::SetRemove),
+    ("list-to-set", Command::ListToSet),
+    ("set-to-list", Command::SetToList),
+    ("pop", Command::Pop),
+    ("depth", Command::Depth),
+    ("var", Command::Var),
+    ("local", Command::Local),
+    ("const", Command::Const),
+    ("type", Command::Type),
+    ("cast", Command::Cast),
+    ("only-number", Command::OnlyNumber),
+    ("mem", Command::Mem),
+    ("ns-list", Command::NsList),
+    ("ns-copy", Command::NsCopy),
+    ("ns-clear", Command::NsClear),
+    ("signature", Command::Signature),
+    ("define", Command::Define),
+    ("defstruct", Command::Defstruct),
+    ("defenum", Command::Defenum),
+    ("memoize", Command::Memoize),
+    ("help", Command::Help),
+    ("free", Command::Free),
+    ("dup", Command::Dup),
+    ("swap", Command::Swap),
+    ("instance", Command::Instance),
+    ("property", Command::Property),
+    ("method", Command::Method),
+    ("modify", Command::Modify),
+    ("struct", Command::Struct),
+    ("new", Command::New),
+    ("field-get", Command::FieldGet),
+    ("field-set", Command::FieldSet),
+    ("all", Command::All),
+];
+
+impl Command {
+    /// Get the built-in command of the name
+    fn parse(name: &str) -> Option<Command> {
+        COMMANDS
+            .iter()
+            .find(|(command, _)| *command == name)
+            .map(|(_, command)| *command)
+    }
+
+    /// Get the category of log entries about the command
+    fn category(self) -> LogCategory {
+        use Command::*;
+        match self {
+            Input | InputNumber | InputBool | InputChoice | ReadLine | ReadAll | Print
+            | PathJoin | PathBasename | PathExt | Glob | Exec | WsConnect | WsSend
+            | WsOnMessage | ImportUrl | Use | Import | WithPrefix => LogCategory::Io,
+            Var | Local | Const | Free | Mem | NsList | NsCopy | NsClear | Signature | Define
+            | Defstruct | Defenum | Memoize => LogCategory::Memory,
+            _ => LogCategory::Stack,
+        }
+    }
+
+    /// Get the number of values it takes, if it has no side effects
+    fn pure_arity(self) -> Option<usize> {
+        PURE_COMMANDS
+            .iter()
+            .find(|(command, _)| *command == self)
+            .map(|(_, count)| *count)
+    }
+}
+
+/// Commands that define words, types and constants, and the number of literals they take
+const DEFINITIONS: &[(Command, usize)] = &[
+    (Command::Define, 2),
+    (Command::Signature, 2),
+    (Command::Const, 2),
+    (Command::Defstruct, 2),
+    (Command::Defenum, 2),
+    (Command::Memoize, 1),
+];
+
+/// Commands without side effects, and the number of values they take
+const PURE_COMMANDS: &[(Command, usize)] = &[
+    (Command::Add, 2),
+    (Command::Sub, 2),
+    (Command::Mul, 2),
+    (Command::Div, 2),
+    (Command::Mod, 2),
+    (Command::Pow, 2),
+    (Command::Round, 1),
+    (Command::Neg, 1),
+    (Command::BitAnd, 2),
+    (Command::BitOr, 2),
+    (Command::BitXor, 2),
+    (Command::BitNot, 1),
+    (Command::Shl, 2),
+    (Command::Shr, 2),
+    (Command::Sin, 1),
+    (Command::Cos, 1),
+    (Command::Tan, 1),
+    (Command::And, 2),
+    (Command::Or, 2),
+    (Command::Not, 1),
+    (Command::Select, 3),
+    (Command::Equal, 2),
+    (Command::ApproxEqual, 3),
+    (Command::Less, 2),
+    (Command::Greater, 2),
+    (Command::LessEqual, 2),
+    (Command::GreaterEqual, 2),
+    (Command::NotEqual, 2),
+    (Command::Concat, 2),
+    (Command::Bytes, 1),
+    (Command::BytesToString, 1),
+    (Command::BytesToList, 1),
+    (Command::BytesSlice, 3),
+    (Command::BytesConcat, 2),
+    (Command::Replace, 3),
+    (Command::Split, 2),
+    (Command::Tokenize, 3),
+    (Command::Join, 2),
+    (Command::Find, 2),
+    (Command::Contains, 2),
+    (Command::Reverse, 1),
+    (Command::Sort, 1),
+    (Command::DictMerge, 3),
+    (Command::DictToList, 1),
+    (Command::ListToDict, 1),
+    (Command::Len, 1),
+    (Command::IsNil, 1),
+    (Command::IsNan, 1),
+    (Command::IsFinite, 1),
+    (Command::ConcatList, 2),
+    (Command::Prepend, 2),
+];
+
+/// Other commands that the underflow check follows, by the number of values
+/// they take and push
+const STACK_EFFECTS: &[(Command, usize, usize)] = &[
+    (Command::Print, 1, 0),
+    (Command::Pop, 1, 0),
+    (Command::Var, 2, 0),
+    (Command::Const, 2, 0),
+    (Command::Dup, 1, 2),
+    (Command::Swap, 2, 2),
+];
+
+/// Commands whose result depends on outside of the program
+const NONDETERMINISTIC: &[&str] = &[
+    "random",
+    "now-time",
+    "input",
+    "input-number",
+    "input-bool",
+    "input-choice",
+    "read-line",
+    "read-all",
+    "args-cmd",
+    "args",
+    "glob",
+    "exec",
+    "ws-connect",
+    "ws-send",
+    "ws-on-message",
+];
+
+/// Table of command aliases
+const ALIASES: &[Alias] = &[
+    Alias {
+        name: "size-stack",
+        command: "depth",
+        deprecated: true,
+    },
+    Alias {
+        name: "copy",
+        command: "dup",
+        deprecated: true,
+    },
+    // Only `-` alone, because `-5` and `-.5` are number literals
+    Alias {
+        name: "-",
+        command: "sub",
+        deprecated: false,
+    },
+    // Symbolic operators, as in other concatenative languages
+    Alias {
+        name: "+",
+        command: "add",
+        deprecated: false,
+    },
+    Alias {
+        name: "*",
+        command: "mul",
+        deprecated: false,
+    },
+    Alias {
+        name: "/",
+        command: "div",
+        deprecated: false,
+    },
+    Alias {
+        name: "%",
+        command: "mod",
+        deprecated: false,
+    },
+    Alias {
+        name: "<",
+        command: "less",
+        deprecated: false,
+    },
+    Alias {
+        name: ">",
+        command: "greater",
+        deprecated: false,
+    },
+    Alias {
+        name: "<=",
+        command: "less-equal",
+        deprecated: false,
+    },
+    Alias {
+        name: ">=",
+        command: "greater-equal",
+        deprecated: false,
+    },
+    Alias {
+        name: "=",
+        command: "equal",
+        deprecated: false,
+    },
+    Alias {
+        name: "!=",
+        command: "not-equal",
+        deprecated: false,
+    },
+];
+
+/// Data type
+#[derive(Clone, Debug, PartialEq)]
+enum Type {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    List(Vec<Type>),
+    Object(String, HashMap<String, Type>),
+    Set(HashMap<String, Type>),
+    Builder(String),
+    Lazy(Lazy),
+    Block(Vec<String>, HashMap<String, Type>), // Tokens of code run by `call`, and captured variables
+    Bytes(Vec<u8>),                            // Binary data
+    Enum(String, String),                      // Names of the enum type and its variant
+    Error(String),
+    Nil, // Absence of value, such as result of failed lookup
+}
+
+/// Lazy sequence that generates values when they are needed
+#[derive(Clone, Debug, PartialEq)]
+struct Lazy {
+    start: f64,
+    end: f64,
+    step: f64,
+    maps: Vec<(String, String)>, // Variable name and code of each mapping
+    take: Option<usize>,         // Maximum number of values
+}
+
+impl Lazy {
+    /// Number of values in the sequence
+    fn len(&self) -> usize {
+        let count = if self.step == 0.0 {
+            0
+        } else {
+            ((self.end - self.start) / self.step).ceil().max(0.0) as usize
+        };
+        self.take.map_or(count, |take| count.min(take))
+    }
+
+    /// Get value of range before mapping
+    fn nth(&self, index: usize) -> Option<f64> {
+        if index < self.len() {
+            Some(self.start + self.step * index as f64)
+        } else {
+            None
+        }
+    }
+}
+
+/// Implement methods
+impl Type {
+    /// Show data to display
+    fn display(&self) -> String {
+        match self {
+            Type::Number(num) => format_number(*num),
+            Type::String(s) => format!("({})", s),
+            Type::Bool(b) => b.to_string(),
+            Type::List(list) => {
+                let result: Vec<String> = list.iter().map(|token| token.display()).collect();
+                format!("[{}]", result.join(" "))
+            }
+            Type::Error(err) => format!("error:{err}"),
+            Type::Object(name, _) => {
+                format!("Object<{name}>")
+            }
+            Type::Set(set) => {
+                let result: Vec<String> = set_values(set).iter().map(|x| x.display()).collect();
+                format!("Set{{{}}}", result.join(" "))
+            }
+            // Don't show the whole buffer, it would be copied at every step
+            Type::Builder(buffer) => format!("Builder<{}>", buffer.chars().count()),
+            Type::Lazy(lazy) => format!("Lazy<{}..{}:{}>", lazy.start, lazy.end, lazy.step),
+            Type::Block(tokens, _) => format!("{{{}}}", tokens.join(" ")),
+            Type::Bytes(bytes) => {
+                let result: Vec<String> = bytes.iter().map(|x| format!("{x:02x}")).collect();
+                format!("Bytes<{}>", result.join(" "))
+            }
+            Type::Enum(name, variant) => format!("{name}.{variant}"),
+            Type::Nil => "nil".to_string(),
+        }
+    }
+
+    /// Get string form data
+    fn get_string(&mut self) -> String {
+        match self {
+            Type::String(s) => s.to_string(),
+            Type::Number(i) => format_number(*i),
+            Type::Bool(b) => b.to_string(),
+            Type::List(l) => Type::List(l.to_owned()).display(),
+            Type::Error(err) => format!("error:{err}"),
+            Type::Object(name, _) => {
+                format!("Object<{name}>")
+            }
+            Type::Set(_) => self.display(),
+            Type::Builder(buffer) => buffer.to_string(),
+            // Code of block, so commands that take code accept blocks
+            Type::Block(tokens, _) => tokens.join(" "),
+            Type::Bytes(bytes) => String::from_utf8_lossy(bytes).to_string(),
+            Type::Lazy(_) | Type::Enum(..) | Type::Nil => self.display(),
+        }
+    }
+
+    /// Get number from data
+    fn get_number(&mut self) -> f64 {
+        match self {
+            Type::String(s) => parse_number(s).unwrap_or(0.0),
+            Type::Number(i) => *i,
+            Type::Bool(b) => {
+                if *b {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Type::List(l) => l.len() as f64,
+            Type::Error(e) => e.parse().unwrap_or(0f64),
+            Type::Object(_, object) => object.len() as f64,
+            Type::Set(set) => set.len() as f64,
+            Type::Builder(buffer) => buffer.chars().count() as f64,
+            Type::Lazy(lazy) => lazy.len() as f64,
+            Type::Block(tokens, _) => tokens.len() as f64,
+            Type::Bytes(bytes) => bytes.len() as f64,
+            Type::Enum(..) | Type::Nil => 0.0,
+        }
+    }
+
+    /// Get bool from data
+    fn get_bool(&mut self) -> bool {
+        match self {
+            Type::String(s) => !s.is_empty(),
+            Type::Number(i) => *i != 0.0,
+            Type::Bool(b) => *b,
+            Type::List(l) => !l.is_empty(),
+            Type::Error(e) => e.parse().unwrap_or(false),
+            Type::Object(_, object) => object.is_empty(),
+            Type::Set(set) => !set.is_empty(),
+            Type::Builder(buffer) => !buffer.is_empty(),
+            Type::Lazy(lazy) => lazy.len() != 0,
+            Type::Block(tokens, _) => !tokens.is_empty(),
+            Type::Bytes(bytes) => !bytes.is_empty(),
+            Type::Enum(..) => true,
+            Type::Nil => false,
+        }
+    }
+
+    /// Compare values, numbers by numeric and lists by lexicographic order
+    fn compare(&self, other: &Type) -> Ordering {
+        match (self, other) {
+            (Type::Number(a), Type::Number(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            (Type::List(a), Type::List(b)) => {
+                for (x, y) in a.iter().zip(b.iter()) {
+                    let order = x.compare(y);
+                    if order != Ordering::Equal {
+                        return order;
+                    }
+                }
+                a.len().cmp(&b.len())
+            }
+            (a, b) => a.clone().get_string().cmp(&b.clone().get_string()),
+        }
+    }
+
+    /// Whether values are equal, numbers by numeric and lists, objects, sets and bytes
+    /// by their elements. Enum values equal only the same variant, and other values
+    /// are compared as strings
+    fn equals(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Number(a), Type::Number(b)) => a == b,
+            (Type::List(a), Type::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.equals(y))
+            }
+            (Type::Object(a_name, a), Type::Object(b_name, b)) => {
+                a_name == b_name
+                    && a.len() == b.len()
+                    && a.iter()
+                        .all(|(key, x)| b.get(key).is_some_and(|y| x.equals(y)))
+            }
+            (Type::Set(a), Type::Set(b)) => {
+                a.len() == b.len() && a.keys().all(|x| b.contains_key(x))
+            }
+            (Type::Bytes(a), Type::Bytes(b)) => a == b,
+            (Type::Enum(..), _) | (_, Type::Enum(..)) => self == other,
+            (Type::List(_) | Type::Object(..) | Type::Set(_) | Type::Bytes(_), _)
+            | (_, Type::List(_) | Type::Object(..) | Type::Set(_) | Type::Bytes(_)) => false,
+            (a, b) => a.clone().get_string() == b.clone().get_string(),
+        }
+    }
+
+    /// Get list form data
+    fn get_list(&mut self) -> Vec<Type> {
+        match self {
+            Type::String(s) => s
+                .to_string()
+                .chars()
+                .map(|x| Type::String(x.to_string()))
+                .collect::<Vec<Type>>(),
+            Type::Number(i) => vec![Type::Number(*i)],
+            Type::Bool(b) => vec![Type::Bool(*b)],
+            Type::List(l) => l.to_vec(),
+            Type::Error(e) => vec![Type::Error(e.to_string())],
+            Type::Object(_, object) => object.values().map(|x| x.to_owned()).collect::<Vec<Type>>(),
+            Type::Set(set) => set_values(set),
+            Type::Builder(buffer) => Type::String(buffer.to_string()).get_list(),
+            // Values are generated by `collect`, because mapping needs executor
+            Type::Lazy(lazy) => vec![Type::Lazy(lazy.clone())],
+            Type::Block(tokens, _) => tokens.iter().map(|x| Type::String(x.clone())).collect(),
+            Type::Bytes(bytes) => bytes.iter().map(|x| Type::Number(*x as f64)).collect(),
+            Type::Enum(..) => vec![self.clone()],
+            Type::Nil => Vec::new(),
+        }
+    }
+
+    /// Start iteration over the values, if it's a collection of them
+    fn items(self) -> Option<Items> {
+        if !self.is_iterable() {
+            return None;
+        }
+        Some(match self {
+            Type::Lazy(lazy) => Items::Lazy(lazy, 0),
+            // Object is iterated as pairs of key and value
+            Type::Object(_, object) => {
+                let mut pairs: Vec<(String, Type)> = object.into_iter().collect();
+                pairs.sort_by(|a, b| a.0.cmp(&b.0));
+                Items::Values(
+                    pairs
+                        .into_iter()
+                        .map(|(key, value)| Type::List(vec![Type::String(key), value]))
+                        .collect::<Vec<Type>>()
+                        .into_iter(),
+                )
+            }
+            mut other => Items::Values(other.get_list().into_iter()),
+        })
+    }
+
+    /// Whether `items` can iterate over the values
+    fn is_iterable(&self) -> bool {
+        !matches!(
+            self,
+            Type::Number(_)
+                | Type::Bool(_)
+                | Type::Block(..)
+                | Type::Error(_)
+                | Type::Enum(..)
+                | Type::Nil
+        )
+    }
+
+    /// Get set form data
+    fn get_set(&mut self) -> HashMap<String, Type> {
+        match self {
+            Type::Set(set) => set.clone(),
+            other => other
+                .get_list()
+                .into_iter()
+                .map(|x| (x.canonical(), x))
+                .collect(),
+        }
+    }
+
+    /// Convert JSON value, if it has corresponding type
+    fn from_json(value: &serde_json::Value) -> Option<Type> {
+        match value {
+            serde_json::Value::Number(num) => Some(Type::Number(num.as_f64()?)),
+            serde_json::Value::String(s) => Some(Type::String(s.clone())),
+            serde_json::Value::Bool(b) => Some(Type::Bool(*b)),
+            serde_json::Value::Null => Some(Type::Nil),
+            serde_json::Value::Array(list) => {
+                let list: Option<Vec<Type>> = list.iter().map(Type::from_json).collect();
+                Some(Type::List(list?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Convert to JS value, where structs are objects, sets are arrays and bytes are Uint8Array
+    fn to_js(&self) -> JsValue {
+        match self {
+            Type::Number(num) => JsValue::from_f64(*num),
+            Type::String(s) | Type::Builder(s) => JsValue::from_str(s),
+            Type::Bool(b) => JsValue::from_bool(*b),
+            Type::List(list) => list
+                .iter()
+                .map(|x| x.to_js())
+                .collect::<js_sys::Array>()
+                .into(),
+            Type::Set(set) => set_values(set)
+                .iter()
+                .map(|x| x.to_js())
+                .collect::<js_sys::Array>()
+                .into(),
+            Type::Object(_, fields) => {
+                let object = js_sys::Object::new();
+                for (key, value) in fields {
+                    let _ = js_sys::Reflect::set(&object, &JsValue::from_str(key), &value.to_js());
+                }
+                object.into()
+            }
+            Type::Bytes(bytes) => js_sys::Uint8Array::from(bytes.as_slice()).into(),
+            Type::Lazy(_) | Type::Block(..) | Type::Enum(..) | Type::Error(_) => {
+                JsValue::from_str(&self.display())
+            }
+            Type::Nil => JsValue::NULL,
+        }
+    }
+
+    /// Whether the value can be written back as source code
+    fn is_literal(&self) -> bool {
+        match self {
+            Type::Number(num) => num.is_finite(),
+            Type::Bool(_) | Type::Nil => true,
+            // Captured values can not be written in the code
+            Type::Block(_, captured) => captured.is_empty(),
+            Type::String(s) => {
+                let mut depth = 0;
+                for c in s.chars() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' if depth == 0 => return false,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                }
+                depth == 0
+            }
+            Type::List(list) => list.iter().all(|x| x.is_literal()),
+            _ => false,
+        }
+    }
+
+    /// Get name of the data type
+    fn type_name(&self) -> String {
+        match self {
+            Type::Number(_) => "number".to_string(),
+            Type::String(_) => "string".to_string(),
+            Type::Bool(_) => "bool".to_string(),
+            Type::List(_) => "list".to_string(),
+            Type::Error(_) => "error".to_string(),
+            Type::Set(_) => "set".to_string(),
+            Type::Builder(_) => "builder".to_string(),
+            Type::Lazy(_) => "lazy".to_string(),
+            Type::Block(..) => "block".to_string(),
+            Type::Bytes(_) => "bytes".to_string(),
+            Type::Enum(name, _) => name.clone(),
+            Type::Nil => "nil".to_string(),
+            Type::Object(name, _) => name.clone(),
+        }
+    }
+
+    /// Encode value uniquely including its type, used as key of set
+    fn canonical(&self) -> String {
+        match self {
+            Type::Number(num) => format!("number:{num}"),
+            Type::String(s) => format!("string:{s:?}"),
+            Type::Bool(b) => format!("bool:{b}"),
+            Type::List(list) => {
+                let result: Vec<String> = list.iter().map(|x| x.canonical()).collect();
+                format!("list:[{}]", result.join(","))
+            }
+            Type::Object(name, object) => {
+                let mut result: Vec<String> = object
+                    .iter()
+                    .map(|(key, value)| format!("{key:?}={}", value.canonical()))
+                    .collect();
+                result.sort();
+                format!("object:{name:?}{{{}}}", result.join(","))
+            }
+            Type::Set(set) => {
+                let mut result: Vec<String> = set.keys().cloned().collect();
+                result.sort();
+                format!("set:{{{}}}", result.join(","))
+            }
+            Type::Builder(buffer) => format!("builder:{buffer:?}"),
+            Type::Lazy(lazy) => format!("lazy:{lazy:?}"),
+            Type::Block(tokens, captured) => {
+                let mut result: Vec<String> = captured
+                    .iter()
+                    .map(|(name, value)| format!("{name:?}={}", value.canonical()))
+                    .collect();
+                result.sort();
+                format!("block:{tokens:?}{{{}}}", result.join(","))
+            }
+            Type::Bytes(bytes) => format!("bytes:{bytes:?}"),
+            Type::Enum(name, variant) => format!("enum:{name:?}.{variant:?}"),
+            Type::Nil => "nil".to_string(),
+            Type::Error(err) => format!("error:{err:?}"),
+        }
+    }
+}
+
+/// Values to be iterated by `for`, `map`, `filter` and `reduce`
+enum Items {
+    Values(std::vec::IntoIter<Type>),
+    Lazy(Lazy, usize),
+}
+
+impl Items {
+    /// Get next value, generating it if the sequence is lazy
+    fn next(&mut self, executor: &mut Executor) -> Option<Type> {
+        if executor.stopped() {
+            return None;
+        }
+        match self {
+            Items::Values(values) => values.next(),
+            Items::Lazy(lazy, index) => {
+                let value = executor.lazy_nth(lazy, *index)?;
+                *index += 1;
+                Some(value)
+            }
+        }
+    }
+}
+
+/// Code running in the bytecode loop, which returns to the code below it when it ends
+struct Activation {
+    syntax: Rc<Syntax>,
+    pc: usize,              // Index of the next instruction
+    loops: Vec<InlineLoop>, // Loops running inline in the code, the innermost last
+    scoped: bool,           // Whether it runs in a frame, which is left when it returns
+    nested: bool,           // Whether it's called by the code below, rather than replacing it
+    outer: LogCategory,     // Category of the code that it returns to
+}
+
+/// Loop command running its code inline in the bytecode
+struct InlineLoop {
+    name: String, // Command of the loop, as it's written
+    command: Command,
+    iteration: Iteration,
+    results: Vec<Type>, // Values produced by the iterations of map
+    next: usize,        // Instruction to continue with the next iteration
+    exit: usize,        // Instruction to finish the loop
+    outer: LogCategory,
+}
+
+/// What the inline loop iterates by
+enum Iteration {
+    /// Condition of while or until, with the stack length before it while it runs
+    Condition(Option<usize>),
+    /// Items of for or map, and the variable they are bound to
+    Items(Items, String),
+    /// Counter of times, and the count it runs up to
+    Count(f64, f64),
+}
+
+/// WebSocket connection and its handler of messages
+#[derive(Clone, Debug)]
+struct Socket {
+    socket: web_sys::WebSocket,
+    state: Rc<RefCell<SocketState>>,
+    handler: Option<String>, // Code evaluated with each message
+}
+
+/// State of WebSocket shared with its event callbacks
+#[derive(Debug, Default)]
+struct SocketState {
+    open: bool,
+    inbox: VecDeque<String>, // Received messages not handled yet
+    outbox: Vec<String>,     // Messages to send when it is opened
+}
+
+impl Socket {
+    /// Connect to the server
+    fn connect(url: &str) -> std::result::Result<Socket, JsValue> {
+        let socket = web_sys::WebSocket::new(url)?;
+        let state = Rc::new(RefCell::new(SocketState::default()));
+
+        let inbox = state.clone();
+        let on_message = Closure::<dyn FnMut(web_sys::MessageEvent)>::new(
+            move |event: web_sys::MessageEvent| {
+                if let Some(text) = event.data().as_string() {
+                    inbox.borrow_mut().inbox.push_back(text);
+                }
+            },
+        );
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        on_message.forget();
+
+        let outbox = state.clone();
+        let sender = socket.clone();
+        let on_open = Closure::<dyn FnMut()>::new(move || {
+            let mut state = outbox.borrow_mut();
+            state.open = true;
+            for message in state.outbox.drain(..) {
+                sender.send_with_str(&message).ok();
+            }
+        });
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        on_open.forget();
+
+        Ok(Socket {
+            socket,
+            state,
+            handler: None,
+        })
+    }
+
+    /// Send message, or keep it until the connection is opened
+    fn send(&self, message: String) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.open {
+            self.socket.send_with_str(&message).is_ok()
+        } else {
+            state.outbox.push(message);
+            true
+        }
+    }
+}
+
+/// Library of words shared as a package
+#[derive(Clone, Debug, Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+    exports: Vec<String>, // Variables brought into scope by `use`
+    source: String,
+}
+
+/// User-defined word, exported to be imported into another session
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Definition {
+    name: String,
+    code: String,
+    #[serde(default)]
+    doc: Option<String>,
+    #[serde(default)]
+    signature: Option<String>, // Stack effect such as "n n -- n"
+    #[serde(default)]
+    memoized: bool,
+}
+
+/// Loop control, that stops the code until the innermost `for` or `while` handles it
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Signal {
+    Break,    // Leave the loop
+    Continue, // Go to the next iteration
+}
+
+/// Host functions called at points of execution, for instrumentation
+#[derive(Clone, Debug, Default)]
+struct Hooks {
+    before_command: Option<js_sys::Function>, // Called with command name
+    after_command: Option<js_sys::Function>,  // Called with command name
+    on_push: Option<js_sys::Function>,        // Called with pushed value
+    on_pop: Option<js_sys::Function>,         // Called with popped value
+    on_var_write: Option<js_sys::Function>,   // Called with name and value, null if freed
+    on_usage: Option<js_sys::Function>,       // Called with counts of commands after the run
+    on_output: Option<js_sys::Function>,      // Called with printed text beyond output limit
+}
+
+/// What pure code takes to calculate the result
+#[derive(Clone, Debug, Default)]
+struct Purity {
+    needs: usize,       // Number of values taken from the stack
+    reads: Vec<String>, // Variables that may be read
+}
+
+/// Variables and words that are read and written
+#[derive(Clone, Debug, Default)]
+struct Access {
+    reads: HashSet<String>,
+    writes: HashSet<String>,
+}
+
+/// Manage program execution
+#[derive(Clone, Debug)]
+struct Executor {
+    stack: Vec<Type>,                      // Data stack
+    memory: HashMap<String, Type>,         // Variable's memory
+    structs: HashMap<String, Vec<String>>, // Field names of struct types
+    warned: HashSet<String>,               // Deprecated names already warned
+    config: Config,
+    steps: u64,                                        // Number of evaluated tokens
+    halted: bool,                                      // Execution is stopped
+    truncated: bool,                 // Output reached the limit, so the rest spills
+    thrown: Option<String>,          // Error thrown and not caught yet
+    trying: usize,                   // Number of running `try`, whose code handles errors itself
+    signal: Option<Signal>,          // Loop control not handled yet
+    loops: Vec<usize>,               // Iteration index of running loops
+    frames: Vec<Frame>,              // Running blocks and words
+    captures: Vec<usize>, // Indexes of scopes captured by blocks, which `var` doesn't assign
+    access: Option<Access>, // Record of variable access, if it is tracked
+    sockets: Vec<Socket>, // WebSocket connections
+    depth: usize,         // Nest level of evaluation
+    position: Option<usize>, // Position of the top level token being evaluated
+    history: Vec<TraceStep>, // Recorded steps of execution
+    audit: Vec<AuditEntry>, // Recorded nondeterministic commands
+    errors: Vec<RecoveredError>, // Recorded errors in recovery mode
+    syntax_errors: Vec<SyntaxError>, // Structure errors of the running program
+    warnings: Vec<Warning>, // Recorded warnings
+    entries: Vec<LogEntry>, // Entries of the log
+    category: LogCategory, // Category of the command being executed
+    prints: Vec<usize>,   // End of the output of each print
+    lines: Option<Vec<usize>>, // Line starts of the program in recovery mode
+    lowest: usize,        // Lowest length of the stack while a command runs
+    permission_callback: Option<js_sys::Function>, // Host asked for capabilities
+    permissions: HashMap<String, bool>, // Answers of the host
+    fetch_callback: Option<js_sys::Function>, // Host bridge to fetch text
+    clock: Option<js_sys::Function>, // Host clock in milliseconds, instead of the system one
+    random: Option<js_sys::Function>, // Host random numbers, instead of the generator
+    answers: VecDeque<String>, // Answers of the input provider, after ones of options
+    slept: f64,           // Milliseconds of sleep that the clock doesn't wait
+    fetched: HashMap<String, String>, // Sources fetched by URL
+    packages: HashMap<String, Package>, // Loaded packages by name
+    modules: HashMap<String, String>, // Sources of modules registered by the host
+    files: BTreeSet<String>, // Paths of the virtual file system given by the host
+    constants: HashSet<String>, // Variables that can't be changed
+    docs: HashMap<String, String>, // Documentation of user-defined words
+    pending_doc: Option<String>, // Doc comment for the next definition
+    signatures: HashMap<String, Signature>, // Declared stack effects of words
+    memo: HashMap<String, HashMap<String, Vec<Type>>>, // Results of memoized words by arguments
+    hooks: Hooks,
+    usage: HashMap<String, usize>, // Times each built-in command ran, if it's reported
+    status: Status,                // How the run ended
+    failed: bool,                  // Runtime error happened in the run
+    started: f64,                  // Time the run started in milliseconds
+    rng: u64,                      // State of random numbers, 0 until the first one
+    words: HashMap<String, String>, // Code of the words defined by `define`
+    scopes: Vec<HashMap<String, Type>>, // Local variables of running loops and words
+    purity: HashMap<String, Option<Purity>>, // What code takes, if it's pure
+    pure_results: HashMap<String, Vec<Type>>, // Results of pure code by its arguments
+    prepared: Rc<HashMap<String, Rc<Syntax>>>, // Code parsed by `compile` of the running program
+    output: String,
+    log: String,
+}
+
+impl Executor {
+    /// Constructor with the providers of time, random numbers and input, so runs are deterministic
+    fn with_providers(
+        clock: Option<js_sys::Function>,
+        random: Option<js_sys::Function>,
+        inputs: VecDeque<String>,
+    ) -> Executor {
+        let mut executor = Executor::new();
+        executor.clock = clock;
+        executor.random = random;
+        executor.answers = inputs;
+        executor
+    }
+
+    /// Get the current time in milliseconds, from the host clock if it is given,
+    /// including the sleep that isn't waited
+    fn now(&self) -> f64 {
+        let time = match &self.clock {
+            Some(clock) => clock
+                .call0(&JsValue::NULL)
+                .ok()
+                .and_then(|x| x.as_f64())
+                .unwrap_or(0.0),
+            None => now_millis(),
+        };
+        time + self.slept
+    }
+
+    /// Get the next random number from 0 to less than 1, from the host function if it's given,
+    /// or generated by xorshift
+    fn next_random(&mut self) -> f64 {
+        if let Some(random) = &self.random {
+            return random
+                .call0(&JsValue::NULL)
+                .ok()
+                .and_then(|x| x.as_f64())
+                .unwrap_or(0.0);
+        }
+        if self.rng == 0 {
+            // Mix the seed by splitmix, so near seeds don't start similar sequences
+            let seed = self.config.seed.unwrap_or_else(|| self.now().to_bits());
+            let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            self.rng = (z ^ (z >> 31)).max(1);
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Constructor
+    fn new() -> Executor {
+        Executor {
+            stack: Vec::new(),
+            memory: HashMap::new(),
+            structs: HashMap::new(),
+            warned: HashSet::new(),
+            config: Config::default(),
+            steps: 0,
+            halted: false,
+            truncated: false,
+            thrown: None,
+            trying: 0,
+            signal: None,
+            loops: Vec::new(),
+            frames: Vec::new(),
+            captures: Vec::new(),
+            access: None,
+            sockets: Vec::new(),
+            depth: 0,
+            position: None,
+            history: Vec::new(),
+            audit: Vec::new(),
+            errors: Vec::new(),
+            syntax_errors: Vec::new(),
+            warnings: Vec::new(),
+            entries: Vec::new(),
+            category: LogCategory::Stack,
+            prints: Vec::new(),
+            lines: None,
+            lowest: 0,
+            permission_callback: None,
+            permissions: HashMap::new(),
+            fetch_callback: None,
+            clock: None,
+            random: None,
+            answers: VecDeque::new(),
+            slept: 0.0,
+            fetched: HashMap::new(),
+            packages: HashMap::new(),
+            modules: HashMap::new(),
+            files: BTreeSet::new(),
+            constants: HashSet::new(),
+            docs: HashMap::new(),
+            pending_doc: None,
+            signatures: HashMap::new(),
+            memo: HashMap::new(),
+            hooks: Hooks::default(),
+            usage: HashMap::new(),
+            status: Status::Completed,
+            failed: false,
+            started: 0.0,
+            rng: 0,
+            words: HashMap::new(),
+            scopes: Vec::new(),
+            purity: HashMap::new(),
+            pure_results: HashMap::new(),
+            prepared: Rc::new(HashMap::new()),
+            output: String::new(),
+            log: String::new(),
+        }
+    }
+
+    /// Clear state of the previous run, keeping stack and memory
+    fn reset_run(&mut self) {
+        self.output.clear();
+        self.log.clear();
+        self.warned.clear();
+        self.steps = 0;
+        self.rng = 0;
+        self.halted = false;
+        self.truncated = false;
+        self.thrown = None;
+        self.signal = None;
+        self.loops.clear();
+        self.frames.clear();
+        self.captures.clear();
+        self.status = Status::Completed;
+        self.failed = false;
+        self.position = None;
+        self.history.clear();
+        self.audit.clear();
+        self.errors.clear();
+        self.syntax_errors.clear();
+        self.warnings.clear();
+        self.entries.clear();
+        self.prints.clear();
+        self.scopes.clear();
+        self.usage.clear();
+    }
+
+    /// Make result of the run
+    fn result(&self) -> Result {
+        let mut result = Result::new(self.output.clone(), self.log.clone());
+        if self.config.record_trace {
+            result.trace = self.history.clone();
+            let mut memory: Vec<(String, String)> = self
+                .memory
+                .iter()
+                .map(|(name, value)| (name.clone(), value.display()))
+                .collect();
+            memory.sort();
+            result.memory = memory;
+        }
+        result.audit = self.audit.clone();
+        result.errors = self.errors.clone();
+        result.syntax_errors = self.syntax_errors.clone();
+        result.warnings = self.warnings.clone();
+        result.entries = self.entries.clone();
+        result.prints = self.prints.clone();
+        result.status = match self.status {
+            Status::Completed if self.failed => Status::Error,
+            status => status,
+        };
+        result
+    }
+
+    // Log
+    fn log(&mut self, msg: String) {
+        if msg.starts_with("Error!") {
+            self.failed = true;
+        }
+        if self.config.recover && msg.starts_with("Error!") {
+            let position = self.source_position();
+            self.errors.push(RecoveredError {
+                message: msg.trim_end().to_string(),
+                line: position.map(|(line, _)| line),
+                column: position.map(|(_, column)| column),
+            });
+        }
+        let level = match msg.starts_with("Error!") {
+            true => LogLevel::Error,
+            false => LogLevel::Warn,
+        };
+        if !self.logs(level, self.category) {
+            return;
+        }
+        let msg = match self.source_position() {
+            Some((line, column)) => {
+                format!("{} (at line {line}, column {column})\n", msg.trim_end())
+            }
+            None => msg,
+        };
+        self.record(level, self.category, msg);
+    }
+
+    /// Whether the entry of the level and category is written in the log
+    fn logs(&self, level: LogLevel, category: LogCategory) -> bool {
+        let lowest = match (self.config.log_level, self.config.trace) {
+            (Some(lowest), _) => lowest,
+            (None, TraceLevel::Off) => return false,
+            (None, TraceLevel::Errors) => LogLevel::Warn,
+            (None, TraceLevel::Full) => LogLevel::Trace,
+        };
+        let categories = &self.config.log_categories;
+        level >= lowest && (categories.is_empty() || categories.contains(&category))
+    }
+
+    /// Write the entry in the log, if it passes the filter
+    fn record(&mut self, level: LogLevel, category: LogCategory, msg: String) {
+        if !self.logs(level, category) {
+            return;
+        }
+        self.log += &msg;
+        self.entries.push(LogEntry {
+            level,
+            category,
+            message: msg.trim_end().to_string(),
+        });
+    }
+
+    /// Get line and column in the original source of the token being evaluated
+    fn source_position(&self) -> Option<(usize, usize)> {
+        let position = self.position?;
+        if let Some(map) = &self.config.source_map {
+            return map
+                .iter()
+                .rev()
+                .find(|(start, _, _)| *start <= position)
+                .map(|(_, line, column)| (*line, *column));
+        }
+        let lines = self.lines.as_ref()?;
+        let line = lines.partition_point(|x| *x <= position);
+        Some((line, position - lines[line - 1] + 1))
+    }
+
+    /// Record warning, unless its category is suppressed
+    fn warn(&mut self, category: &'static str, severity: Severity, message: String) {
+        if self.config.suppress.contains(category) || self.warnings.len() >= WARNING_LIMIT {
+            return;
+        }
+        let position = self.source_position();
+        self.warnings.push(Warning {
+            category,
+            severity,
+            message,
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+        });
+    }
+
+    // Log execution trace
+    fn trace(&mut self, msg: String) {
+        self.record(LogLevel::Trace, LogCategory::Stack, msg)
+    }
+
+    // Print to standard output
+    fn print(&mut self, msg: String) {
+        let limit = self.config.output_limit.unwrap_or(usize::MAX);
+        // Once output spills, later prints follow it to keep the order
+        if self.truncated || self.output.len() + msg.len() + 1 > limit {
+            self.spill(msg + "\n");
+            return;
+        }
+        self.output.push_str(&msg);
+        self.output.push('\n');
+        self.prints.push(self.output.len());
+    }
+
+    /// Pass the output beyond the limit to the host, or drop it with notice
+    fn spill(&mut self, text: String) {
+        let first = !self.truncated;
+        self.truncated = true;
+        if let Some(hook) = &self.hooks.on_output {
+            if hook
+                .call1(&JsValue::NULL, &JsValue::from_str(&text))
+                .is_err()
+            {
+                self.halt(Status::Aborted);
+            }
+            return;
+        }
+        if first {
+            let message = "the output exceeds the limit, and the rest is dropped".to_string();
+            self.warn("output-truncated", Severity::Warning, message);
+        }
+    }
+
+    /// Show variable inside memory
+    fn show_variables(&mut self) {
+        if !self.logs(LogLevel::Debug, LogCategory::Memory) {
+            return;
+        }
+        let mut text = "Variables {\n".to_string();
+        let max = self.memory.keys().map(|s| s.len()).max().unwrap_or(0);
+        for (name, value) in &self.memory {
+            text += &format!(" {:>width$}: {}\n", name, value.display(), width = max);
+        }
+        text += "}\n";
+        self.record(LogLevel::Debug, LogCategory::Memory, text)
+    }
+
+    /// Show inside the stack
+    fn show_stack(&mut self) -> String {
+        format!(
+            "Stack〔 {} 〕",
+            self.stack
+                .iter()
+                .map(|x| x.display())
+                .collect::<Vec<_>>()
+                .join(" | ")
+        )
+    }
+
+    /// Parse token by analyzing syntax
+    fn analyze_syntax(&mut self, code: String) -> Vec<String> {
+        self.analyze_syntax_spans(code)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    /// Parse token with its start position (in characters) in the code
+    fn analyze_syntax_spans(&mut self, code: String) -> Vec<(String, usize)> {
+        lex(&code, self.full_width())
+            .0
+            .into_iter()
+            .map(|token| (token.text, token.start.position))
+            .collect()
+    }
+
+    /// Parse token, reusing the result if the same code was parsed before
+    fn parse_cached(&mut self, code: String) -> Rc<Syntax> {
+        if let Some(syntax) = self.prepared.get(&code) {
+            return syntax.clone();
+        }
+        let key = (code, self.full_width());
+        if let Some(syntax) = SYNTAX_CACHE.with(|cache| cache.borrow().get(&key).cloned()) {
+            return syntax;
+        }
+
+        let (tokens, errors) = lex(&key.0, key.1);
+        let syntax = Rc::new(Syntax {
+            errors,
+            ..to_syntax(tokens, key.1)
+        });
+        SYNTAX_CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            if cache.len() >= SYNTAX_CACHE_LIMIT {
+                cache.clear();
+            }
+            cache.insert(key, syntax.clone());
+        });
+        syntax
+    }
+
+    /// Whether full-width brackets and hash are delimiters in the code that runs
+    fn full_width(&self) -> bool {
+        !self.config.keep_full_width
+    }
+
+    /// Analyze whether the code consists of literals, variables and commands
+    /// without side effects, and what it takes
+    fn purity(&mut self, code: &str) -> Option<Purity> {
+        if let Some(purity) = self.purity.get(code) {
+            return purity.clone();
+        }
+
+        let mut depth: isize = 0; // Increment of the stack
+        let mut purity = Some(Purity::default());
+        let syntax = self.parse_cached(code.to_string());
+        for (token, op) in &syntax.tokens[..syntax.top] {
+            let Some(result) = &mut purity else {
+                break;
+            };
+            let builtin = op.command();
+            let count = if comment_text(token).is_some() {
+                continue;
+            } else if literal_value(token, self.full_width()).is_some() {
+                0
+            } else if token.starts_with('[') && token.ends_with(']') && token.len() >= 2 {
+                // Elements of list must not take values from outside
+                match self.purity(&token[1..token.len() - 1]) {
+                    Some(inner) if inner.needs == 0 => {
+                        result.reads.extend(inner.reads);
+                        0
+                    }
+                    _ => {
+                        purity = None;
+                        continue;
+                    }
+                }
+            } else if let Some(count) = builtin
+                .and_then(|x| x.pure_arity())
+                .filter(|_| self.lookup(token).is_none())
+            {
+                count
+            } else if builtin.is_none()
+                && !token.starts_with("error:")
+                && !self.words.contains_key(token)
+            {
+                // Variable, or string if it is not defined
+                result.reads.push(token.clone());
+                0
+            } else {
+                purity = None;
+                continue;
+            };
+            depth -= count as isize;
+            result.needs = result.needs.max((-depth).max(0) as usize);
+            depth += 1;
+        }
+
+        if self.purity.len() >= PURE_CACHE_LIMIT {
+            self.purity.clear();
+        }
+        self.purity.insert(code.to_string(), purity.clone());
+        purity
+    }
+
+    /// Evaluate pure code, reusing the result of the same arguments and variables
+    fn evaluate_pure(&mut self, code: String, purity: Purity) {
+        let base = self.stack.len() - purity.needs;
+        let mut key = code.clone();
+        for value in &self.stack[base..] {
+            key.push(' ');
+            key.push_str(&value.canonical());
+        }
+        for name in &purity.reads {
+            let value = self.lookup(name).map(|x| x.canonical());
+            key.push_str(&format!(" {name}={value:?}"));
+        }
+        if let Some(result) = self.pure_results.get(&key).cloned() {
+            self.stack.truncate(base);
+            self.stack.extend(result);
+            return;
+        }
+
+        self.evaluate_tokens(code, None);
+        if !self.stopped() && self.stack.len() >= base {
+            if self.pure_results.len() >= PURE_CACHE_LIMIT {
+                self.pure_results.clear();
+            }
+            self.pure_results.insert(key, self.stack[base..].to_vec());
+        }
+    }
+
+    /// Run the compiled program, using its parsed code instead of tokenizing it again
+    fn run(&mut self, program: &Program) {
+        if self.full_width() != DEFAULT_FULL_WIDTH {
+            // The program is compiled by the other setting, which tokenizes it differently
+            self.evaluate_tokens(program.source.clone(), None);
+            return;
+        }
+        if self.reject_syntax(&program.errors) {
+            return;
+        }
+        let outer = std::mem::replace(&mut self.prepared, program.nested.clone());
+        self.evaluate_tokens(program.source.clone(), Some(program.syntax.clone()));
+        self.prepared = outer;
+    }
+
+    /// Warn options that don't exist, such as misspelled ones, once in a run
+    fn warn_unknown_options(&mut self) {
+        if self.warnings.iter().any(|x| x.category == "unknown-option") {
+            return;
+        }
+        let names: Vec<String> = self.config.unknown.keys().cloned().collect();
+        for name in names {
+            let message = format!("the option {name} doesn't exist, so it's ignored");
+            self.log(format!("Warning! {message}\n"));
+            self.warn("unknown-option", Severity::Warning, message);
+        }
+    }
+
+    /// Warn the call of a word that takes more values than the stack has, by the declared
+    /// stack effects, before running the code. Checking ends at the first token whose
+    /// effect isn't known
+    fn check_underflow(&mut self, syntax: &Syntax) {
+        let full_width = self.full_width();
+        let mut signatures = self.signatures.clone();
+        // Values on the stack, which are known if they're literals
+        let mut stack: Vec<Option<Type>> = vec![None; self.stack.len()];
+        for (token, op) in &syntax.tokens[..syntax.top] {
+            let builtin = match op {
+                Op::Push(value) => {
+                    stack.push(Some(value.clone()));
+                    continue;
+                }
+                Op::List(_) | Op::Block(_) => {
+                    stack.push(None);
+                    continue;
+                }
+                Op::Comment => continue,
+                Op::Word(builtin) => *builtin,
+            };
+            if let Some(signature) = signatures.get(token).filter(|_| builtin.is_none()) {
+                let (count, produced) = (signature.inputs.len(), signature.outputs.len());
+                if stack.len() < count {
+                    let message = format!(
+                        "{token} takes {count} values, but the stack will have {}",
+                        stack.len()
+                    );
+                    self.log(format!("Warning! {message}\n"));
+                    self.warn("stack-underflow", Severity::Warning, message);
+                    return;
+                }
+                stack.truncate(stack.len() - count);
+                stack.extend(vec![None; produced]);
+                continue;
+            }
+            match builtin.filter(|_| self.lookup(token).is_none()) {
+                Some(command @ (Command::Define | Command::Signature)) if stack.len() >= 2 => {
+                    let name = stack.pop().flatten().map(|mut x| x.get_string());
+                    let value = stack.pop().flatten().map(|mut x| x.get_string());
+                    let (Some(name), Some(value)) = (name, value) else {
+                        return;
+                    };
+                    let signature = match command {
+                        Command::Define => Signature::split_declared(&value, full_width)
+                            .map(|(signature, _)| signature),
+                        _ => Signature::parse(&value),
+                    };
+                    if let Some(signature) = signature {
+                        signatures.insert(name, signature);
+                    }
+                }
+                Some(command) => {
+                    let effect = STACK_EFFECTS.iter().find(|x| x.0 == command);
+                    let (count, produced) = match (command.pure_arity(), effect) {
+                        (Some(count), _) => (count, 1),
+                        (None, Some(&(_, count, produced))) => (count, produced),
+                        (None, None) => return,
+                    };
+                    if stack.len() < count {
+                        return;
+                    }
+                    stack.truncate(stack.len() - count);
+                    stack.extend(vec![None; produced]);
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Keep the structure errors of the program, and report the first one if there are any
+    fn reject_syntax(&mut self, errors: &[SyntaxError]) -> bool {
+        self.syntax_errors = errors.to_vec();
+        let Some(error) = errors.first() else {
+            return false;
+        };
+        self.log(format!(
+            "Error! {} at line {}, column {}\n",
+            error.message, error.span.line, error.span.column
+        ));
+        self.push_stack(Type::Error("syntax-error".to_string()));
+        true
+    }
+
+    /// evaluate string as program
+    fn evaluate_program(&mut self, code: String) {
+        if self.config.optimize && self.depth > 0 {
+            if let Some(purity) = self.purity(&code).filter(|x| x.needs <= self.stack.len()) {
+                self.evaluate_pure(code, purity);
+                return;
+            }
+        }
+        self.evaluate_tokens(code, None)
+    }
+
+    /// Evaluate each token of the program, which is already parsed if it's given
+    fn evaluate_tokens(&mut self, code: String, parsed: Option<Rc<Syntax>>) {
+        // Positions of top level tokens, to report them in errors
+        let tracked =
+            self.config.source_map.is_some() || self.config.recover || self.config.checked_math;
+        if self.depth == 0 && (self.config.recover || self.config.checked_math) {
+            self.lines = Some(line_starts(&code));
+        }
+        let positions: Option<Vec<usize>> = if self.depth == 0 && tracked {
+            Some(
+                self.analyze_syntax_spans(code.clone())
+                    .into_iter()
+                    .map(|(_, start)| start)
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
+        // Parse into token string
+        let syntax: Rc<Syntax> = match parsed {
+            Some(syntax) => syntax,
+            None => self.parse_cached(code),
+        };
+        // Report the structure errors of the whole program, instead of running it
+        if self.depth == 0 && self.reject_syntax(&syntax.errors) {
+            return;
+        }
+        if self.depth == 0 {
+            self.warn_unknown_options();
+            self.check_underflow(&syntax);
+        }
+
+        if self.depth == 0 && self.config.time_limit.is_some() {
+            self.started = self.now();
+        }
+        if self.depth == 0 {
+            PRECISION.with(|x| x.set(self.config.precision));
+        }
+        self.depth += 1;
+        // Running code and the code of calls made by it, evaluated in this loop without nesting
+        let mut calls = vec![Activation {
+            syntax,
+            pc: 0,
+            loops: Vec::new(),
+            scoped: false,
+            nested: false,
+            outer: self.category,
+        }];
+        loop {
+            let top = calls.len() - 1;
+            if self.stopped() {
+                // Unwind the inline loops and calls, until one of them handles the stop
+                if !calls[top].loops.is_empty() {
+                    self.unwind_loop(&mut calls[top]);
+                } else if top == 0 {
+                    break;
+                } else if let Some(call) = calls.pop() {
+                    self.return_call(call);
+                }
+                continue;
+            }
+            let call = &mut calls[top];
+            let Some(instr) = call.syntax.code.get(call.pc).copied() else {
+                if top == 0 {
+                    break;
+                }
+                if let Some(call) = calls.pop() {
+                    self.return_call(call);
+                }
+                continue;
+            };
+            call.pc += 1;
+            let index = match instr {
+                Instr::Token(index) | Instr::Loop { token: index, .. } => index,
+                control => {
+                    self.control(call, control);
+                    continue;
+                }
+            };
+            let (token, op) = call.syntax.tokens[index].clone();
+            let previous = match call.pc.checked_sub(2).map(|i| call.syntax.code[i]) {
+                Some(Instr::Token(i)) => Some(call.syntax.tokens[i].0.clone()),
+                _ => None,
+            };
+            if let Some(positions) = &positions {
+                if top == 0 && index < call.syntax.top {
+                    self.position = positions.get(index).copied();
+                }
+            }
+
+            // Consume fuel
+            self.steps += 1;
+            if self.config.fuel.is_some_and(|fuel| self.steps > fuel) {
+                self.log("Error! the fuel is exhausted, execution is stopped\n".to_string());
+                self.halt(Status::FuelExhausted);
+                continue;
+            }
+            if let Some(limit) = self.config.time_limit {
+                if self.steps.is_multiple_of(256) && self.now() - self.started > limit {
+                    self.log(
+                        "Error! the time limit is exceeded, execution is stopped\n".to_string(),
+                    );
+                    self.halt(Status::Timeout);
+                    continue;
+                }
+            }
+
+            // Show inside stack to debug
+            if self.logs(LogLevel::Trace, LogCategory::Stack) {
+                let stack = self.show_stack();
+                self.trace(format!("{} ←  {}\n", stack, token));
+            }
+            let recorded = self.config.record_trace.then(|| token.clone());
+
+            // Run what the token is decoded to, unless it's a word
+            let word = match op {
+                Op::Word(builtin) => Some(builtin),
+                Op::Push(value) => {
+                    // Push literal value on the stack
+                    self.push_stack(value);
+                    None
+                }
+                Op::List(slice) => {
+                    // Push list value on the stack
+                    let old_len = self.stack.len(); // length of old stack
+                    self.evaluate_program(slice);
+                    // Make increment of stack an element of list
+                    let mut list = Vec::new();
+                    for _ in old_len..self.stack.len() {
+                        list.push(self.pop_stack());
+                    }
+                    list.reverse(); // reverse list
+                    self.push_stack(Type::List(list));
+                    None
+                }
+                Op::Block(tokens) => {
+                    // Push block value on the stack, without evaluating it
+                    let captured = self.capture(&tokens);
+                    self.push_stack(Type::Block(tokens, captured));
+                    None
+                }
+                Op::Comment => {
+                    // Processing comments
+                    let text = comment_text(&token).unwrap_or_default();
+                    let comment = format!("* Comment \"{text}\"\n");
+                    self.record(LogLevel::Debug, LogCategory::Parser, comment);
+                    if let Some(doc) = text.strip_prefix(':') {
+                        // Documentation comment of the next definition
+                        self.pending_doc = Some(doc.trim().to_string());
+                    }
+                    None
+                }
+            };
+
+            let builtin = word.flatten();
+            if word.is_some()
+                && !matches!(
+                    builtin,
+                    Some(Command::Define | Command::Var | Command::Const)
+                )
+            {
+                // Doc comment belongs only to the definition right after it
+                self.pending_doc = None;
+            }
+            let tail =
+                calls[top].pc == calls[top].syntax.code.len() && (top > 0 || positions.is_none());
+            let mut inlined = false;
+            if let Instr::Loop { next, exit, .. } = instr {
+                match self.start_loop(&token, builtin, &calls[top].syntax, index, next, exit) {
+                    Some(inline) => {
+                        calls[top].loops.push(inline);
+                        inlined = true;
+                    }
+                    // Run it as command, skipping the inline code
+                    None => calls[top].pc = exit + 1,
+                }
+            }
+            if word.is_none() || inlined {
+                // Literal, list, block, comment or inline loop is already run
+            } else if let Some(i) = self.lookup(&token) {
+                let value = i.clone();
+                if let Some(access) = &mut self.access {
+                    access.reads.insert(token.clone());
+                }
+                if let Some(signature) = self.signatures.get(&token).cloned() {
+                    // Call the word that has declared stack effect
+                    let mut code = value;
+                    self.call_word(&token, code.get_string(), signature);
+                } else {
+                    // Push variable's data on stack
+                    self.push_stack(value);
+                }
+            } else if let Some((code, frame)) = self.call_code(&token, builtin, tail) {
+                // Run the code of the call in this loop, instead of nesting the evaluation.
+                // The call in tail position replaces the running code
+                let syntax = self.parse_cached(code.clone());
+                let replaced = if tail { calls.pop() } else { None };
+                let (mut scoped, nested, outer) = match replaced {
+                    Some(call) => {
+                        if call.scoped && frame.is_some() {
+                            self.leave_frame();
+                        }
+                        (call.scoped, call.nested, call.outer)
+                    }
+                    None => {
+                        self.depth += 1;
+                        (false, true, self.category)
+                    }
+                };
+                if let Some((_, scope, captured)) = frame {
+                    self.enter_frame(code, scope, captured);
+                    scoped = true;
+                }
+                self.category = builtin.map_or(LogCategory::Stack, |x| x.category());
+                calls.push(Activation {
+                    syntax,
+                    pc: 0,
+                    loops: Vec::new(),
+                    scoped,
+                    nested,
+                    outer,
+                });
+            } else {
+                // Else, execute as command
+                if token == "pop" {
+                    let pure = |name: &&str| {
+                        Command::parse(name).is_some_and(|x| x.pure_arity().is_some())
+                    };
+                    if let Some(previous) = previous.as_deref().filter(pure) {
+                        let message = format!("the result of {previous} is discarded");
+                        self.warn("unused-result", Severity::Info, message);
+                    }
+                }
+                self.execute_command(token.clone(), builtin);
+                if self.config.checked_math && builtin.is_some_and(|x| x.pure_arity().is_some()) {
+                    self.check_number(&token);
+                }
+                if self.config.recover
+                    && self.trying == 0
+                    && builtin != Some(Command::Try)
+                    && matches!(self.stack.last(), Some(Type::Error(_)))
+                {
+                    // Continue with placeholder value instead of the error, unless try handles it
+                    self.stack.pop();
+                    self.push_stack(Type::Number(0.0));
+                }
+            }
+
+            if let Some(token) = recorded {
+                let stack = self.show_stack();
+                self.history.push(TraceStep { token, stack });
+            }
+        }
+        if let Some(call) = calls.pop() {
+            self.return_call(call);
+        }
+        self.depth -= 1;
+        if self.depth == 0 {
+            self.pending_doc = None;
+            if let Some(signal) = self.signal.take() {
+                let name = match signal {
+                    Signal::Break => "break",
+                    Signal::Continue => "continue",
+                };
+                self.log(format!("Error! {name} is used outside of loop\n"));
+                self.push_stack(Type::Error("outside-loop".to_string()));
+            }
+            if let Some(error) = self.thrown.take() {
+                self.log(format!(
+                    "Error! the thrown error \"{error}\" is not caught\n"
+                ));
+                self.push_stack(Type::Error(error));
+            }
+            self.report_usage();
+        }
+
+        // Show inside stack, after execution
+        if self.logs(LogLevel::Trace, LogCategory::Stack) {
+            let stack = self.show_stack();
+            self.trace(format!("{}\n", stack));
+        }
+    }
+
+    /// Whether the command can run in the bytecode loop, without its hooks and the checks after it
+    fn runs_inline(&self, token: &str) -> bool {
+        let hooked = self.hooks.before_command.is_some() || self.hooks.after_command.is_some();
+        let checked = self.config.recover || self.config.checked_math || self.config.record_trace;
+        !hooked && !checked && !self.config.disabled.contains(token)
+    }
+
+    /// Get code that the token evaluates, to run it in the bytecode loop instead of recursing,
+    /// and the frame it runs in if it's a word or block
+    fn call_code(
+        &mut self,
+        token: &str,
+        builtin: Option<Command>,
+        tail: bool,
+    ) -> Option<(String, Option<Frame>)> {
+        // Optimized code is evaluated by its purity, except the tail call that keeps looping
+        if !self.runs_inline(token) || (self.config.optimize && !tail) {
+            return None;
+        }
+        match builtin {
+            Some(Command::Eval) if !self.stack.is_empty() => {
+                self.count_usage(token);
+                Some((self.pop_stack().get_string(), None))
+            }
+            Some(Command::If) if self.stack.len() >= 3 => {
+                self.count_usage(token);
+                let condition = self.pop_stack().get_bool();
+                let code_else = self.pop_stack().get_string();
+                let code_if = self.pop_stack().get_string();
+                Some((if condition { code_if } else { code_else }, None))
+            }
+            Some(Command::Recurse) => {
+                let frame = self.frames.last()?.clone();
+                self.count_usage(token);
+                Some((frame.0.clone(), Some(frame)))
+            }
+            Some(Command::Call) if matches!(self.stack.last(), Some(Type::Block(..))) => {
+                let Type::Block(tokens, captured) = self.pop_stack() else {
+                    return None;
+                };
+                self.count_usage(token);
+                let code = tokens.join(" ");
+                Some((code.clone(), Some((code, captured, true))))
+            }
+            None if !self.signatures.contains_key(token)
+                && !ALIASES.iter().any(|alias| alias.name == token) =>
+            {
+                let code = self.words.get(token)?.clone();
+                if let Some(access) = &mut self.access {
+                    access.reads.insert(token.to_string());
+                }
+                Some((code.clone(), Some((code, HashMap::new(), false))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Start running the loop command inline,
+    /// if the code on the stack is the literal that the bytecode inlines
+    fn start_loop(
+        &mut self,
+        token: &str,
+        builtin: Option<Command>,
+        syntax: &Syntax,
+        index: usize,
+        next: usize,
+        exit: usize,
+    ) -> Option<InlineLoop> {
+        if !self.runs_inline(token) || self.config.optimize || self.lookup(token).is_some() {
+            return None;
+        }
+        let command = builtin?;
+        // Place of the inlined code on the stack, from the top
+        let offset = match command {
+            Command::While | Command::Until | Command::Times => 2,
+            _ => 1,
+        };
+        let Op::Push(literal) = &syntax.tokens[index.checked_sub(offset)?].1 else {
+            return None;
+        };
+        if self.stack.len() < offset || &self.stack[self.stack.len() - offset] != literal {
+            return None;
+        }
+
+        self.count_usage(token);
+        let iteration = match command {
+            Command::While | Command::Until => {
+                self.pop_stack();
+                self.pop_stack();
+                self.loops.push(0);
+                Iteration::Condition(None)
+            }
+            Command::Times => {
+                let count = self.pop_number();
+                self.pop_stack();
+                self.scopes.push(HashMap::new());
+                self.loops.push(0);
+                Iteration::Count(0.0, count)
+            }
+            _ => {
+                // Values that can't be iterated are reported by the command
+                if !self
+                    .stack
+                    .iter()
+                    .rev()
+                    .nth(2)
+                    .is_some_and(Type::is_iterable)
+                {
+                    return None;
+                }
+                self.pop_stack();
+                let vars = self.pop_stack().get_string();
+                let items = self.pop_stack().items()?;
+                self.scopes.push(HashMap::new());
+                if command == Command::For {
+                    self.loops.push(0);
+                }
+                Iteration::Items(items, vars)
+            }
+        };
+        let outer = std::mem::replace(&mut self.category, command.category());
+        Some(InlineLoop {
+            name: token.to_string(),
+            command,
+            iteration,
+            results: Vec::new(),
+            next,
+            exit,
+            outer,
+        })
+    }
+
+    /// Run the instruction of the innermost inline loop, jumping in the running code
+    fn control(&mut self, call: &mut Activation, instr: Instr) {
+        let Some(inline) = call.loops.last_mut() else {
+            return;
+        };
+        match instr {
+            Instr::Condition => inline.iteration = Iteration::Condition(Some(self.stack.len())),
+            Instr::Test { exit } => {
+                let Iteration::Condition(base) = &mut inline.iteration else {
+                    return;
+                };
+                let old_len = base.take().unwrap_or(self.stack.len());
+                let until = inline.command == Command::Until;
+                match self.condition_result(old_len, &inline.name) {
+                    Some(condition) if condition != until => {}
+                    _ => call.pc = exit,
+                }
+            }
+            Instr::Next { exit } => {
+                let item = match &mut inline.iteration {
+                    Iteration::Items(items, vars) => items.next(self).map(|x| (vars.clone(), x)),
+                    Iteration::Count(index, count) => {
+                        (*index < *count).then(|| ("i".to_string(), Type::Number(*index)))
+                    }
+                    Iteration::Condition(_) => None,
+                };
+                match item {
+                    Some((vars, x)) => self.bind(&vars, x),
+                    None => call.pc = exit,
+                }
+            }
+            Instr::Iterate { start } => {
+                match &mut inline.iteration {
+                    _ if inline.command == Command::Map => {
+                        let value = self.pop_stack();
+                        inline.results.push(value);
+                    }
+                    Iteration::Count(index, _) => {
+                        self.next_iteration();
+                        *index += 1.0;
+                    }
+                    _ => self.next_iteration(),
+                }
+                call.pc = start;
+            }
+            Instr::Exit => {
+                if let Some(inline) = call.loops.pop() {
+                    self.finish_loop(inline);
+                }
+            }
+            Instr::Token(_) | Instr::Loop { .. } => {}
+        }
+    }
+
+    /// Handle the stop in the innermost inline loop of the code,
+    /// continuing it by the signal, or finishing it and letting the stop go on
+    fn unwind_loop(&mut self, call: &mut Activation) {
+        let Some(mut inline) = call.loops.pop() else {
+            return;
+        };
+        let condition = matches!(inline.iteration, Iteration::Condition(Some(_)));
+        if inline.command == Command::Map {
+            // What the stopped iteration left is the last result
+            let value = self.pop_stack();
+            inline.results.push(value);
+        } else if !condition && self.signal.take() == Some(Signal::Continue) && !self.stopped() {
+            call.pc = inline.next;
+            call.loops.push(inline);
+            return;
+        }
+        call.pc = inline.exit + 1;
+        self.finish_loop(inline);
+    }
+
+    /// Finish the inline loop, as the loop command does when it ends
+    fn finish_loop(&mut self, inline: InlineLoop) {
+        if inline.command != Command::Map {
+            self.loops.pop();
+        }
+        if !matches!(inline.iteration, Iteration::Condition(_)) {
+            self.scopes.pop();
+        }
+        if inline.command == Command::Map {
+            self.push_stack(Type::List(inline.results));
+        }
+        self.category = inline.outer;
+    }
+
+    /// Finish the code called in the bytecode loop, returning to the code below it
+    fn return_call(&mut self, call: Activation) {
+        if call.scoped {
+            self.leave_frame();
+        }
+        if call.nested {
+            self.depth -= 1;
+        }
+        self.category = call.outer;
+    }
+
+    /// Count the built-in command, if the usage is reported
+    fn count_usage(&mut self, command: &str) {
+        if self.hooks.on_usage.is_some() {
+            *self.usage.entry(command.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Call the hook with the command counts of the finished run, and clear them
+    fn report_usage(&mut self) {
+        let Some(hook) = &self.hooks.on_usage else {
+            return;
+        };
+        let usage: BTreeMap<String, usize> = self.usage.drain().collect();
+        let usage = serde_json::to_string(&usage).unwrap_or_default();
+        // The run is already finished, so exception of the hook is ignored
+        let _ = hook.call1(&JsValue::NULL, &JsValue::from_str(&usage));
+    }
+
+    /// execute string as commands
+    fn execute_command(&mut self, command: String, builtin: Option<Command>) {
+        let (command, builtin) = match builtin {
+            Some(builtin) => (command, Some(builtin)),
+            None => {
+                let command = self.resolve_alias(command);
+                let builtin = Command::parse(&command);
+                (command, builtin)
+            }
+        };
+        if self.config.disabled.contains(&command) {
+            self.log(format!("Error! the command \"{command}\" is disabled\n"));
+            self.push_stack(Type::Error("disabled-command".to_string()));
+            return;
+        }
+
+        if let Some(hook) = &self.hooks.before_command {
+            if hook
+                .call1(&JsValue::NULL, &JsValue::from_str(&command))
+                .is_err()
+            {
+                self.halt(Status::Aborted);
+                return;
+            }
+        }
+        if builtin.is_some() {
+            self.count_usage(&command);
+        }
+        let hooked = self.hooks.after_command.is_some().then(|| command.clone());
+        let category = builtin.map_or(LogCategory::Stack, |x| x.category());
+        let outer = std::mem::replace(&mut self.category, category);
+
+        if self.config.audit && NONDETERMINISTIC.contains(&command.as_str()) {
+            self.lowest = self.stack.len();
+            self.execute_builtin(command.clone(), builtin);
+            // Values pushed by the command are its result
+            let result: Vec<String> = self.stack[self.lowest.min(self.stack.len())..]
+                .iter()
+                .map(|x| x.display())
+                .collect();
+            self.audit.push(AuditEntry {
+                command,
+                result: result.join(" "),
+            });
+        } else {
+            self.execute_builtin(command, builtin);
+        }
+        self.category = outer;
+
+        if let (Some(hook), Some(command)) = (&self.hooks.after_command, hooked) {
+            if hook
+                .call1(&JsValue::NULL, &JsValue::from_str(&command))
+                .is_err()
+            {
+                self.halt(Status::Aborted);
+            }
+        }
+    }
+
+    /// Replace NaN or infinite number produced by the command with error, in checked math
+    fn check_number(&mut self, command: &str) {
+        let Some(Type::Number(number)) = self.stack.last() else {
+            return;
+        };
+        let (message, kind) = if number.is_nan() {
+            ("NaN", "nan-result")
+        } else if number.is_infinite() {
+            ("an infinite number", "infinite-result")
+        } else {
+            return;
+        };
+        self.stack.pop();
+        self.log(format!("Error! {command} produced {message}\n"));
+        self.push_stack(Type::Error(kind.to_string()));
+    }
+
+    /// Throw error if the global variable is constant, and tell whether it's thrown
+    fn reject_constant(&mut self, name: &str) -> bool {
+        if self.owner_scope(name).is_some() || !self.constants.contains(name) {
+            return false;
+        }
+        let message = format!("* The constant \"{name}\" can't be changed\n");
+        self.record(LogLevel::Info, LogCategory::Memory, message);
+        self.thrown = Some("const-reassign".to_string());
+        true
+    }
+
+    /// Count up the iteration index of the innermost loop
+    fn next_iteration(&mut self) {
+        if let Some(index) = self.loops.last_mut() {
+            *index += 1;
+        }
+    }
+
+    /// Whether the execution is stopped, or unwinding by `throw`, `break` or `continue`
+    fn stopped(&self) -> bool {
+        self.halted || self.thrown.is_some() || self.signal.is_some()
+    }
+
+    /// Stop the execution, by the reason
+    fn halt(&mut self, status: Status) {
+        self.halted = true;
+        self.status = status;
+    }
+
+    /// Call the hook of variable writing, with null value if it's freed
+    fn hook_var_write(&mut self, name: &str, value: Option<&Type>) {
+        if let Some(hook) = &self.hooks.on_var_write {
+            let value = match value {
+                Some(value) => JsValue::from_str(&value.display()),
+                None => JsValue::NULL,
+            };
+            if hook
+                .call2(&JsValue::NULL, &JsValue::from_str(name), &value)
+                .is_err()
+            {
+                self.halt(Status::Aborted);
+            }
+        }
+    }
+
+    /// execute string as built-in commands
+    fn execute_builtin(&mut self, command: String, builtin: Option<Command>) {
+        match builtin {
+            // Commands of calculation
+
+            // addition
+            Some(Command::Add) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Number(a + b));
+            }
+
+            // Subtraction
+            Some(Command::Sub) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Number(a - b));
+            }
+
+            // Multiplication
+            Some(Command::Mul) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Number(a * b));
             }
 
             // Division
-            "div" => {
-                let b = self.pop_stack().get_number();
-                let a = self.pop_stack().get_number();
-                self.stack.push(Type::Number(a / b));
+            Some(Command::Div) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                if self.config.checked_math && b == 0.0 {
+                    self.log("Error! division by zero\n".to_string());
+                    self.push_stack(Type::Error("division-by-zero".to_string()));
+                    return;
+                }
+                self.push_stack(Type::Number(a / b));
             }
 
             // Remainder of division
-            "mod" => {
-                let b = self.pop_stack().get_number();
-                let a = self.pop_stack().get_number();
-                self.stack.push(Type::Number(a % b));
+            Some(Command::Mod) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                if self.config.checked_math && b == 0.0 {
+                    self.log("Error! division by zero\n".to_string());
+                    self.push_stack(Type::Error("division-by-zero".to_string()));
+                    return;
+                }
+                self.push_stack(Type::Number(a % b));
             }
 
             // Exponentiation
-            "pow" => {
-                let b = self.pop_stack().get_number();
-                let a = self.pop_stack().get_number();
-                self.stack.push(Type::Number(a.powf(b)));
+            Some(Command::Pow) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Number(a.powf(b)));
             }
 
             // Rounding off
-            "round" => {
-                let a = self.pop_stack().get_number();
-                self.stack.push(Type::Number(a.round()));
+            Some(Command::Round) => {
+                let a = self.pop_number();
+                self.push_stack(Type::Number(a.round()));
+            }
+
+            // Negate the number
+            Some(Command::Neg) => {
+                let a = self.pop_number();
+                self.push_stack(Type::Number(-a));
+            }
+
+            // Bitwise operation on integer parts of the numbers
+            Some(
+                Command::BitAnd | Command::BitOr | Command::BitXor | Command::Shl | Command::Shr,
+            ) => {
+                let b = self.pop_number() as i64;
+                let a = self.pop_number() as i64;
+                let shift = u32::try_from(b).ok();
+                let result = match builtin {
+                    Some(Command::BitAnd) => a & b,
+                    Some(Command::BitOr) => a | b,
+                    Some(Command::BitXor) => a ^ b,
+                    // Shifting by 64 or more, or by a negative amount, moves every bit out
+                    Some(Command::Shl) => shift.and_then(|x| a.checked_shl(x)).unwrap_or(0),
+                    _ => shift.and_then(|x| a.checked_shr(x)).unwrap_or(a >> 63),
+                };
+                self.push_stack(Type::Number(result as f64));
+            }
+
+            // Invert bits of integer part of the number
+            Some(Command::BitNot) => {
+                let a = self.pop_number() as i64;
+                self.push_stack(Type::Number(!a as f64));
             }
 
             // Trigonometric sine
-            "sin" => {
-                let number = self.pop_stack().get_number();
-                self.stack.push(Type::Number(number.sin()))
+            Some(Command::Sin) => {
+                let number = self.pop_number();
+                self.push_stack(Type::Number(number.sin()))
             }
 
             // Trigonometric cosine
-            "cos" => {
-                let number = self.pop_stack().get_number();
-                self.stack.push(Type::Number(number.cos()))
+            Some(Command::Cos) => {
+                let number = self.pop_number();
+                self.push_stack(Type::Number(number.cos()))
             }
 
             // Trigonometric tangent
-            "tan" => {
-                let number = self.pop_stack().get_number();
-                self.stack.push(Type::Number(number.tan()))
+            Some(Command::Tan) => {
+                let number = self.pop_number();
+                self.push_stack(Type::Number(number.tan()))
+            }
+
+            // Random number from 0 to less than 1, the same sequence for the same seed
+            Some(Command::Random) => {
+                let number = self.next_random();
+                self.push_stack(Type::Number(number))
+            }
+
+            // Current time in milliseconds since the Unix epoch
+            Some(Command::NowTime) => {
+                let time = self.now();
+                self.push_stack(Type::Number(time))
+            }
+
+            // Wait for the milliseconds. The host clock and the browser don't wait,
+            // and the time goes on by them instead
+            Some(Command::Sleep) => {
+                let millis = self.pop_number();
+                if !(millis.is_finite() && millis >= 0.0) {
+                    self.log(format!("Error! can't sleep for {millis} milliseconds\n"));
+                    self.push_stack(Type::Error("invalid-duration".to_string()));
+                    return;
+                }
+                if self.clock.is_some() || cfg!(all(target_arch = "wasm32", target_os = "unknown"))
+                {
+                    self.slept += millis;
+                } else {
+                    std::thread::sleep(std::time::Duration::from_secs_f64(millis / 1000.0));
+                }
             }
 
             // Logical operations of AND
-            "and" => {
+            Some(Command::And) => {
                 let b = self.pop_stack().get_bool();
                 let a = self.pop_stack().get_bool();
-                self.stack.push(Type::Bool(a && b));
+                self.push_stack(Type::Bool(a && b));
             }
 
             // Logical operations of OR
-            "or" => {
+            Some(Command::Or) => {
                 let b = self.pop_stack().get_bool();
                 let a = self.pop_stack().get_bool();
-                self.stack.push(Type::Bool(a || b));
+                self.push_stack(Type::Bool(a || b));
             }
 
             // Logical operations of NOT
-            "not" => {
+            Some(Command::Not) => {
                 let b = self.pop_stack().get_bool();
-                self.stack.push(Type::Bool(!b));
+                self.push_stack(Type::Bool(!b));
             }
 
             // Is it equal
-            "equal" => {
-                let b = self.pop_stack().get_string();
-                let a = self.pop_stack().get_string();
-                self.stack.push(Type::Bool(a == b));
+            Some(Command::Equal) => {
+                let b = self.pop_stack();
+                let a = self.pop_stack();
+                self.push_stack(Type::Bool(a.equals(&b)));
+            }
+
+            // Is it not equal
+            Some(Command::NotEqual) => {
+                let b = self.pop_stack();
+                let a = self.pop_stack();
+                self.push_stack(Type::Bool(!a.equals(&b)));
+            }
+
+            // Is the difference of numbers within the tolerance
+            Some(Command::ApproxEqual) => {
+                let tolerance = self.pop_number();
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Bool((a - b).abs() <= tolerance));
+            }
+
+            // Set digits after the decimal point to show numbers, negative to show all
+            Some(Command::Precision) => {
+                let digits = self.pop_number();
+                let precision = (digits >= 0.0).then_some(digits as usize);
+                self.config.precision = precision;
+                PRECISION.with(|x| x.set(precision));
+            }
+
+            // Is it less
+            Some(Command::Less) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Bool(a < b));
+            }
+
+            // Is it greater
+            Some(Command::Greater) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Bool(a > b));
+            }
+
+            // Is it less or equal
+            Some(Command::LessEqual) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Bool(a <= b));
             }
 
-            // Is it less
-            "less" => {
-                let b = self.pop_stack().get_number();
-                let a = self.pop_stack().get_number();
-                self.stack.push(Type::Bool(a < b));
+            // Is it greater or equal
+            Some(Command::GreaterEqual) => {
+                let b = self.pop_number();
+                let a = self.pop_number();
+                self.push_stack(Type::Bool(a >= b));
             }
 
             // Commands of string processing
 
             // Repeat string a number of times
-            "repeat" => {
-                let count = self.pop_stack().get_number(); // 回数
+            Some(Command::Repeat) => {
+                let count = self.pop_number(); // 回数
                 let text = self.pop_stack().get_string(); // 文字列
-                self.stack.push(Type::String(text.repeat(count as usize)));
+                self.push_stack(Type::String(text.repeat(count as usize)));
             }
 
             // Get unicode character form number
-            "decode" => {
-                let code = self.pop_stack().get_number();
+            Some(Command::Decode) => {
+                let code = self.pop_number();
                 let result = char::from_u32(code as u32);
                 match result {
-                    Some(c) => self.stack.push(Type::String(c.to_string())),
+                    Some(c) => self.push_stack(Type::String(c.to_string())),
                     None => {
                         self.log("Error! failed of number decoding\n".to_string());
-                        self.stack.push(Type::Error("number-decoding".to_string()));
+                        self.push_stack(Type::Error("number-decoding".to_string()));
                     }
                 }
             }
 
             // Encode string by UTF-8
-            "encode" => {
+            Some(Command::Encode) => {
                 let string = self.pop_stack().get_string();
                 if let Some(first_char) = string.chars().next() {
-                    self.stack.push(Type::Number((first_char as u32) as f64));
+                    self.push_stack(Type::Number((first_char as u32) as f64));
                 } else {
                     self.log("Error! failed of string encoding\n".to_string());
-                    self.stack.push(Type::Error("string-encoding".to_string()));
+                    self.push_stack(Type::Error("string-encoding".to_string()));
                 }
             }
 
             // Concatenate the string
-            "concat" => {
+            Some(Command::Concat) => {
                 let b = self.pop_stack().get_string();
                 let a = self.pop_stack().get_string();
-                self.stack.push(Type::String(a + &b));
+                self.push_stack(Type::String(a + &b));
+            }
+
+            // Replace "{name}" in the string with value of the variable,
+            // where "{{" and "}}" are braces themselves
+            Some(Command::Interp) => {
+                let text = self.pop_stack().get_string();
+                let mut result = String::new();
+                let mut rest = text.as_str();
+                while let Some(index) = rest.find(['{', '}']) {
+                    result.push_str(&rest[..index]);
+                    let tail = &rest[index..];
+                    if tail.starts_with("{{") || tail.starts_with("}}") {
+                        result.push_str(&tail[..1]);
+                        rest = &tail[2..];
+                        continue;
+                    }
+                    let Some(end) = tail.find('}').filter(|_| tail.starts_with('{')) else {
+                        result.push_str(&tail[..1]);
+                        rest = &tail[1..];
+                        continue;
+                    };
+                    let name = tail[1..end].trim();
+                    match self.lookup(name).cloned() {
+                        Some(mut value) => result.push_str(&value.get_string()),
+                        None => {
+                            let message =
+                                format!("the variable \"{name}\" to interpolate is not found");
+                            self.warn("interp-undefined", Severity::Warning, message);
+                            result.push_str(&tail[..=end]);
+                        }
+                    }
+                    rest = &tail[end + 1..];
+                }
+                result.push_str(rest);
+                self.push_stack(Type::String(result));
             }
 
             // Replacing string
-            "replace" => {
+            Some(Command::Replace) => {
                 let after = self.pop_stack().get_string();
                 let before = self.pop_stack().get_string();
                 let text = self.pop_stack().get_string();
-                self.stack.push(Type::String(text.replace(&before, &after)))
+                self.push_stack(Type::String(text.replace(&before, &after)))
             }
 
             // split string by key
-            "split" => {
+            Some(Command::Split) => {
                 let key = self.pop_stack().get_string();
                 let text = self.pop_stack().get_string();
-                self.stack.push(Type::List(
+                self.push_stack(Type::List(
                     text.split(&key)
                         .map(|x| Type::String(x.to_string()))
                         .collect::<Vec<Type>>(),
                 ));
             }
 
+            // Split string into tokens by delimiters, respecting quotes
+            Some(Command::Tokenize) => {
+                let keep = self.pop_stack().get_bool();
+                let delimiters = self.pop_stack().get_string();
+                let text = self.pop_stack().get_string();
+                self.push_stack(Type::List(
+                    split_quoted(&text, &delimiters, keep)
+                        .into_iter()
+                        .map(Type::String)
+                        .collect::<Vec<Type>>(),
+                ));
+            }
+
             // Generate a string by concat list
-            "join" => {
+            Some(Command::Join) => {
                 let key = self.pop_stack().get_string();
                 let mut list = self.pop_stack().get_list();
-                self.stack.push(Type::String(
+                self.push_stack(Type::String(
                     list.iter_mut()
                         .map(|x| x.get_string())
                         .collect::<Vec<String>>()
@@ -472,28 +4990,204 @@ impl Executor {
             }
 
             // Is it finding in string
-            "find" => {
+            Some(Command::Find) => {
                 let word = self.pop_stack().get_string();
                 let text = self.pop_stack().get_string();
-                self.stack.push(Type::Bool(text.contains(&word)))
+                self.push_stack(Type::Bool(text.contains(&word)))
+            }
+
+            // Is it containing the value (substring, list element or object key)
+            Some(Command::Contains) => {
+                let mut value = self.pop_stack();
+                match self.pop_stack() {
+                    Type::String(text) => {
+                        self.push_stack(Type::Bool(text.contains(&value.get_string())))
+                    }
+                    Type::List(list) => self.push_stack(Type::Bool(list.contains(&value))),
+                    Type::Object(_, data) => {
+                        self.push_stack(Type::Bool(data.contains_key(&value.get_string())))
+                    }
+                    Type::Set(set) => {
+                        self.push_stack(Type::Bool(set.contains_key(&value.canonical())))
+                    }
+                    _ => self.push_stack(Type::Error("not-container".to_string())),
+                }
+            }
+
+            // Generate a empty string builder
+            Some(Command::SbNew) => self.push_stack(Type::Builder(String::new())),
+
+            // Append string to the string builder
+            Some(Command::SbPush) => {
+                let text = self.pop_stack().get_string();
+                match self.pop_stack() {
+                    Type::Builder(mut buffer) => {
+                        buffer.push_str(&text);
+                        self.push_stack(Type::Builder(buffer));
+                    }
+                    mut other => {
+                        let mut buffer = other.get_string();
+                        buffer.push_str(&text);
+                        self.push_stack(Type::Builder(buffer));
+                    }
+                }
+            }
+
+            // Get built string from the string builder
+            Some(Command::SbBuild) => {
+                let text = self.pop_stack().get_string();
+                self.push_stack(Type::String(text));
+            }
+
+            // Commands of binary data
+
+            // Make bytes from UTF-8 of the string, or list of numbers from 0 to 255
+            Some(Command::Bytes) => match self.pop_stack() {
+                Type::Bytes(bytes) => self.push_stack(Type::Bytes(bytes)),
+                Type::String(text) => self.push_stack(Type::Bytes(text.into_bytes())),
+                mut other => {
+                    let bytes: Option<Vec<u8>> = other
+                        .get_list()
+                        .iter()
+                        .map(|x| match x {
+                            Type::Number(n) if n.fract() == 0.0 && (0.0..256.0).contains(n) => {
+                                Some(*n as u8)
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    match bytes {
+                        Some(bytes) => self.push_stack(Type::Bytes(bytes)),
+                        None => {
+                            self.log("Error! bytes should be integers from 0 to 255\n".to_string());
+                            self.push_stack(Type::Error("not-byte".to_string()));
+                        }
+                    }
+                }
+            },
+
+            // Decode the bytes as UTF-8 string
+            Some(Command::BytesToString) => {
+                let bytes = match self.pop_stack() {
+                    Type::Bytes(bytes) => bytes,
+                    mut other => other.get_string().into_bytes(),
+                };
+                match String::from_utf8(bytes) {
+                    Ok(text) => self.push_stack(Type::String(text)),
+                    Err(_) => {
+                        self.log("Error! the bytes are not valid UTF-8\n".to_string());
+                        self.push_stack(Type::Error("invalid-utf8".to_string()));
+                    }
+                }
+            }
+
+            // Get list of numbers of the bytes
+            Some(Command::BytesToList) => {
+                let list = self.pop_stack().get_list();
+                self.push_stack(Type::List(list));
+            }
+
+            // Get bytes from the start index until the end index
+            Some(Command::BytesSlice) => {
+                let end = self.pop_number();
+                let start = self.pop_number();
+                let Type::Bytes(bytes) = self.pop_stack() else {
+                    self.log("Error! bytes-slice needs bytes\n".to_string());
+                    self.push_stack(Type::Error("not-bytes".to_string()));
+                    return;
+                };
+                let end = (end.max(0.0) as usize).min(bytes.len());
+                let start = (start.max(0.0) as usize).min(end);
+                self.push_stack(Type::Bytes(bytes[start..end].to_vec()));
+            }
+
+            // Concatenate the bytes
+            Some(Command::BytesConcat) => {
+                let b = self.pop_stack();
+                let a = self.pop_stack();
+                match (a, b) {
+                    (Type::Bytes(mut a), Type::Bytes(b)) => {
+                        a.extend(b);
+                        self.push_stack(Type::Bytes(a));
+                    }
+                    _ => {
+                        self.log("Error! bytes-concat needs bytes\n".to_string());
+                        self.push_stack(Type::Error("not-bytes".to_string()));
+                    }
+                }
             }
 
             // Commands of I/O
 
             // Standard input
-            "input" => {
+            Some(Command::Input) => {
                 let promp = self.pop_stack().get_string();
-                self.stack.push(Type::String(prompt(promp.as_str())));
+                let answer = match self.next_input() {
+                    Some(answer) => answer,
+                    None => host_input(promp.as_str()).unwrap_or_default(),
+                };
+                self.push_stack(Type::String(answer));
+            }
+
+            // Get number from user input, asking again if it is not a number
+            Some(Command::InputNumber) => {
+                let prompt = self.pop_stack().get_string();
+                self.ask_typed(&prompt, "a number", |answer| {
+                    parse_number(answer.trim()).map(Type::Number)
+                });
+            }
+
+            // Get bool from user input, such as "yes" or "false"
+            Some(Command::InputBool) => {
+                let prompt = self.pop_stack().get_string();
+                self.ask_typed(&prompt, "yes or no", |answer| {
+                    match answer.trim().to_lowercase().as_str() {
+                        "true" | "yes" | "y" => Some(Type::Bool(true)),
+                        "false" | "no" | "n" => Some(Type::Bool(false)),
+                        _ => None,
+                    }
+                });
+            }
+
+            // Get one of the options from user input, by itself or its number from 1
+            Some(Command::InputChoice) => {
+                let options = self.pop_stack().get_list();
+                let prompt = self.pop_stack().get_string();
+                let names: Vec<String> = options.iter().map(|x| x.clone().get_string()).collect();
+                let hint = format!("one of {}", names.join(", "));
+                self.ask_typed(&prompt, &hint, |answer| {
+                    // Name of the option, or else its number from 1
+                    let answer = answer.trim();
+                    let index = names.iter().position(|name| name == answer).or_else(|| {
+                        let number = answer.parse::<usize>().ok()?;
+                        number.checked_sub(1)
+                    });
+                    options.get(index?).cloned()
+                });
+            }
+
+            // Read a line of standard input
+            Some(Command::ReadLine) => match self.next_input().or_else(host_read_line) {
+                Some(line) => self.push_stack(Type::String(line)),
+                None => self.push_stack(Type::Error("end-of-input".to_string())),
+            },
+
+            // Read all of standard input
+            Some(Command::ReadAll) => {
+                let mut text: Vec<String> = self.config.inputs.drain(..).collect();
+                text.extend(self.answers.drain(..));
+                text.extend(host_read_all());
+                self.push_stack(Type::String(text.join("\n")));
             }
 
             // Standard output
-            "print" => {
+            Some(Command::Print) => {
                 let a = self.pop_stack().get_string();
-                self.print(format!("{a}"));
+                self.print(a);
             }
 
             // Get command-line arguments
-            "args-cmd" => self.stack.push(Type::List(
+            Some(Command::ArgsCmd) => self.push_stack(Type::List(
                 env::args()
                     .collect::<Vec<_>>()
                     .iter()
@@ -501,16 +5195,301 @@ impl Executor {
                     .collect::<Vec<Type>>(),
             )),
 
+            // Stop the program with the exit code
+            Some(Command::Exit) => {
+                let code = self.pop_number() as i32;
+                self.halt(Status::Exited(code));
+            }
+
+            // Get arguments given to the program
+            Some(Command::Args) => {
+                let args = self.config.args.iter().cloned().map(Type::String).collect();
+                self.push_stack(Type::List(args));
+            }
+
+            // Join paths
+            Some(Command::PathJoin) => {
+                let child = self.pop_stack().get_string();
+                let parent = self.pop_stack().get_string();
+                let path = Path::new(&parent).join(child);
+                self.push_stack(Type::String(path.to_string_lossy().to_string()));
+            }
+
+            // Get file name of the path
+            Some(Command::PathBasename) => {
+                let path = self.pop_stack().get_string();
+                let name = Path::new(&path).file_name().unwrap_or_default();
+                self.push_stack(Type::String(name.to_string_lossy().to_string()));
+            }
+
+            // Get extension of the path
+            Some(Command::PathExt) => {
+                let path = self.pop_stack().get_string();
+                let ext = Path::new(&path).extension().unwrap_or_default();
+                self.push_stack(Type::String(ext.to_string_lossy().to_string()));
+            }
+
+            // Get paths of files matching the pattern
+            Some(Command::Glob) => {
+                let pattern = self.pop_stack().get_string();
+                if !self.require("fs") {
+                    return;
+                }
+
+                let browser = cfg!(all(target_arch = "wasm32", target_os = "unknown"));
+                let mut paths = if browser || !self.files.is_empty() {
+                    glob_virtual(&pattern, &self.files)
+                } else {
+                    glob(&pattern)
+                };
+                paths.sort();
+                self.push_stack(Type::List(
+                    paths.into_iter().map(Type::String).collect::<Vec<Type>>(),
+                ));
+            }
+
+            // Execute other program, and get its output and exit code
+            Some(Command::Exec) => {
+                let args: Vec<String> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter_mut()
+                    .map(|x| x.get_string())
+                    .collect();
+                let program = self.pop_stack().get_string();
+                if cfg!(target_arch = "wasm32") {
+                    self.log("Error! exec is not available on wasm\n".to_string());
+                    self.push_stack(Type::Error("permission-denied".to_string()));
+                    return;
+                }
+                if !self.require("exec") {
+                    return;
+                }
+
+                match std::process::Command::new(&program).args(&args).output() {
+                    Ok(result) => {
+                        self.push_stack(Type::String(
+                            String::from_utf8_lossy(&result.stdout).to_string(),
+                        ));
+                        self.push_stack(Type::Number(result.status.code().unwrap_or(-1) as f64));
+                    }
+                    Err(err) => {
+                        self.log(format!("Error! failed to execute {program}: {err}\n"));
+                        self.push_stack(Type::Error("exec-failed".to_string()));
+                    }
+                }
+            }
+
+            // Connect to WebSocket server, and get the connection number
+            Some(Command::WsConnect) => {
+                let url = self.pop_stack().get_string();
+                if !self.require_browser("ws-connect") || !self.require("network") {
+                    return;
+                }
+                match Socket::connect(&url) {
+                    Ok(socket) => {
+                        self.sockets.push(socket);
+                        self.push_stack(Type::Number((self.sockets.len() - 1) as f64));
+                    }
+                    Err(_) => {
+                        self.log(format!("Error! failed to connect to {url}\n"));
+                        self.push_stack(Type::Error("ws-connect".to_string()));
+                    }
+                }
+            }
+
+            // Send message by WebSocket
+            Some(Command::WsSend) => {
+                let message = self.pop_stack().get_string();
+                let index = self.pop_number() as usize;
+                let sent = self.sockets.get(index).map(|socket| socket.send(message));
+                if sent != Some(true) {
+                    self.log("Error! failed to send message by WebSocket\n".to_string());
+                    self.push_stack(Type::Error("ws-send".to_string()));
+                }
+            }
+
+            // Set the code evaluated with each received message
+            Some(Command::WsOnMessage) => {
+                let code = self.pop_stack().get_string();
+                let index = self.pop_number() as usize;
+                match self.sockets.get_mut(index) {
+                    Some(socket) => socket.handler = Some(code),
+                    None => {
+                        self.log("Error! the WebSocket connection is not found\n".to_string());
+                        self.push_stack(Type::Error("ws-not-found".to_string()));
+                    }
+                }
+            }
+
+            // Fetch Stack source from URL and evaluate it, caching per session
+            Some(Command::ImportUrl) => {
+                let url = self.pop_stack().get_string();
+                if !self.require("network") {
+                    return;
+                }
+                let code = match self.fetched.get(&url) {
+                    Some(code) => code.clone(),
+                    None => {
+                        let fetched = self.fetch_callback.as_ref().and_then(|callback| {
+                            callback
+                                .call1(&JsValue::NULL, &JsValue::from_str(&url))
+                                .ok()
+                                .and_then(|x| x.as_string())
+                        });
+                        match fetched {
+                            Some(code) => {
+                                self.fetched.insert(url.clone(), code.clone());
+                                code
+                            }
+                            None => {
+                                self.log(format!("Error! failed to fetch {url}\n"));
+                                self.push_stack(Type::Error("import-url".to_string()));
+                                return;
+                            }
+                        }
+                    }
+                };
+                self.evaluate_program(code)
+            }
+
+            // Bring exported words of the package into scope, also as "package:word"
+            Some(Command::Use) => {
+                let name = self.pop_stack().get_string();
+                self.use_package(&name, &[format!("{name}:"), String::new()]);
+            }
+
+            // Bring all definitions of the module into scope as "module.word"
+            Some(Command::Import) => {
+                let name = self.pop_stack().get_string();
+                self.import_module(&name);
+            }
+
+            // Bring exported words of the package into scope as "prefix:word"
+            Some(Command::WithPrefix) => {
+                let prefix = self.pop_stack().get_string();
+                let name = self.pop_stack().get_string();
+                self.use_package(&name, &[format!("{prefix}:")]);
+            }
+
             // Commands of control
 
             // evaluate string as program
-            "eval" => {
+            Some(Command::Eval) => {
                 let code = self.pop_stack().get_string();
                 self.evaluate_program(code)
             }
 
+            // Run the innermost running block or word again
+            Some(Command::Recurse) => match self.frames.last().cloned() {
+                Some((code, scope, captured)) => {
+                    self.enter_frame(code.clone(), scope, captured);
+                    self.evaluate_program(code);
+                    self.leave_frame();
+                }
+                None => {
+                    self.log("Error! recurse is used outside of block or word\n".to_string());
+                    self.push_stack(Type::Error("outside-block".to_string()));
+                }
+            },
+
+            // Run the code of block
+            Some(Command::Call) => match self.pop_stack() {
+                Type::Block(tokens, captured) => {
+                    // Variables as they were when the block was created
+                    let code = tokens.join(" ");
+                    self.enter_frame(code.clone(), captured, true);
+                    self.evaluate_program(code);
+                    self.leave_frame();
+                }
+                other => {
+                    let name = other.type_name();
+                    self.log(format!("Error! call needs a block, but got {name}\n"));
+                    self.push_stack(Type::Error("not-block".to_string()));
+                }
+            },
+
+            // Leave the innermost loop
+            Some(Command::Break) => self.signal = Some(Signal::Break),
+
+            // Go to the next iteration of the innermost loop
+            Some(Command::Continue) => self.signal = Some(Signal::Continue),
+
+            // Throw error, that stops the code until it is caught by `try`
+            Some(Command::Throw) => {
+                let error = match self.pop_stack() {
+                    Type::Error(err) => err,
+                    mut other => other.get_string(),
+                };
+                let message = format!("* Thrown error:{error}\n");
+                self.record(LogLevel::Info, LogCategory::Stack, message);
+                self.thrown = Some(error);
+            }
+
+            // Evaluate code, and get its result or the error thrown in it
+            Some(Command::Try) => {
+                let code = self.pop_stack().get_string();
+                let old_len = self.stack.len();
+                self.trying += 1;
+                self.evaluate_program(code);
+                self.trying -= 1;
+                if let Some(error) = self.thrown.take() {
+                    self.stack.truncate(old_len);
+                    self.push_stack(Type::Error(error));
+                }
+            }
+
+            // Run code of the first pattern that matches the value
+            Some(Command::Match) => {
+                let arms = self.pop_stack().get_list();
+                let value = self.pop_stack();
+                for arm in arms {
+                    let arm = match arm {
+                        Type::List(arm) => <[Type; 2]>::try_from(arm).ok(),
+                        _ => None,
+                    };
+                    let Some([pattern, mut code]) = arm else {
+                        self.log("Error! match needs pairs of pattern and code\n".to_string());
+                        self.push_stack(Type::Error("match-arm".to_string()));
+                        return;
+                    };
+                    let mut bindings = HashMap::new();
+                    if match_pattern(&pattern, &value, &mut bindings) {
+                        self.scopes.push(bindings);
+                        self.evaluate_program(code.get_string());
+                        self.scopes.pop();
+                        return;
+                    }
+                }
+                self.log(format!("Error! no pattern matches {}\n", value.display()));
+                self.push_stack(Type::Error("no-match".to_string()));
+            }
+
+            // Run code of the arm whose value equals, or the default code
+            Some(Command::Case) => {
+                let mut default = self.pop_stack();
+                let arms = self.pop_stack().get_list();
+                let value = self.pop_stack();
+                for arm in arms {
+                    let arm = match arm {
+                        Type::List(arm) => <[Type; 2]>::try_from(arm).ok(),
+                        _ => None,
+                    };
+                    let Some([expected, mut code]) = arm else {
+                        self.log("Error! case needs pairs of value and code\n".to_string());
+                        self.push_stack(Type::Error("case-arm".to_string()));
+                        return;
+                    };
+                    if expected == value {
+                        self.evaluate_program(code.get_string());
+                        return;
+                    }
+                }
+                self.evaluate_program(default.get_string());
+            }
+
             // Conditional branch
-            "if" => {
+            Some(Command::If) => {
                 let condition = self.pop_stack().get_bool(); // condition
                 let code_else = self.pop_stack().get_string(); // else code
                 let code_if = self.pop_stack().get_string(); // if code
@@ -521,308 +5500,1122 @@ impl Executor {
                 };
             }
 
-            // Loop while condition is true
-            "while" => {
+            // Choose the first value if the condition is true, or the second one,
+            // without evaluating them
+            Some(Command::Select) => {
+                let condition = self.pop_stack().get_bool();
+                let value_else = self.pop_stack();
+                let value_if = self.pop_stack();
+                self.push_stack(if condition { value_if } else { value_else });
+            }
+
+            // Loop while condition is true, or until it's true
+            Some(Command::While | Command::Until) => {
                 let cond = self.pop_stack().get_string();
                 let code = self.pop_stack().get_string();
-                while {
-                    self.evaluate_program(cond.clone());
-                    self.pop_stack().get_bool()
-                } {
+                let until = builtin == Some(Command::Until);
+                self.loops.push(0);
+                while let Some(condition) = self.evaluate_condition(&cond, &command) {
+                    if condition == until {
+                        break;
+                    }
                     self.evaluate_program(code.clone());
+                    if self.signal.take() == Some(Signal::Break) {
+                        break;
+                    }
+                    self.next_iteration();
                 }
+                self.loops.pop();
             }
 
+            // Get index of the iteration of the innermost loop, from 0
+            Some(Command::LoopIndex) => match self.loops.last() {
+                Some(index) => self.push_stack(Type::Number(*index as f64)),
+                None => {
+                    self.log("Error! loop-index is used outside of loop\n".to_string());
+                    self.push_stack(Type::Error("outside-loop".to_string()));
+                }
+            },
+
             // Commands of list processing
 
             // Get list value by index
-            "get" => {
-                let index = self.pop_stack().get_number() as usize;
+            Some(Command::Get) => {
+                let index = self.pop_number() as usize;
                 let list: Vec<Type> = self.pop_stack().get_list();
-                if list.len() > index {
-                    self.stack.push(list[index].clone());
-                } else {
-                    self.log("Error! Index specification is out of range\n".to_string());
-                    self.stack.push(Type::Error("index-out-range".to_string()));
+                match list.get(index) {
+                    Some(value) => self.push_stack(value.clone()),
+                    None => self.push_stack(Type::Nil),
                 }
             }
 
             // Set list value by index
-            "set" => {
+            Some(Command::Set) => {
                 let value = self.pop_stack();
-                let index = self.pop_stack().get_number() as usize;
+                let index = self.pop_number() as usize;
                 let mut list: Vec<Type> = self.pop_stack().get_list();
                 if list.len() > index {
                     list[index] = value;
-                    self.stack.push(Type::List(list));
+                    self.push_stack(Type::List(list));
                 } else {
                     self.log("Error! Index specification is out of range\n".to_string());
-                    self.stack.push(Type::Error("index-out-range".to_string()));
+                    self.push_stack(Type::Error("index-out-range".to_string()));
                 }
             }
 
             // Delete list value by index
-            "del" => {
-                let index = self.pop_stack().get_number() as usize;
+            Some(Command::Del) => {
+                let index = self.pop_number() as usize;
                 let mut list = self.pop_stack().get_list();
                 if list.len() > index {
                     list.remove(index);
-                    self.stack.push(Type::List(list));
+                    self.push_stack(Type::List(list));
                 } else {
                     self.log("Error! Index specification is out of range\n".to_string());
-                    self.stack.push(Type::Error("index-out-range".to_string()));
+                    self.push_stack(Type::Error("index-out-range".to_string()));
                 }
             }
 
             // Append value in the list
-            "append" => {
+            Some(Command::Append) => {
                 let data = self.pop_stack();
                 let mut list = self.pop_stack().get_list();
                 list.push(data);
-                self.stack.push(Type::List(list));
+                self.push_stack(Type::List(list));
+            }
+
+            // Put value at the front of the list
+            Some(Command::Prepend) => {
+                let data = self.pop_stack();
+                let mut list = self.pop_stack().get_list();
+                list.insert(0, data);
+                self.push_stack(Type::List(list));
+            }
+
+            // Join two lists
+            Some(Command::ConcatList) => {
+                let back = self.pop_stack().get_list();
+                let mut list = self.pop_stack().get_list();
+                list.extend(back);
+                self.push_stack(Type::List(list));
+            }
+
+            // Push all elements of the list on the stack
+            Some(Command::Spread) => {
+                let list = self.pop_stack().get_list();
+                for value in list {
+                    self.push_stack(value);
+                }
+            }
+
+            // Make list of the top N values of the stack
+            Some(Command::CollectN) => {
+                let count = self.pop_number().max(0.0) as usize;
+                if count > self.stack.len() {
+                    self.log(format!(
+                        "Error! collect-n needs {count} values, but the stack has {}\n",
+                        self.stack.len()
+                    ));
+                    self.push_stack(Type::Error("stack-underflow".to_string()));
+                    return;
+                }
+                let mut list = Vec::new();
+                for _ in 0..count {
+                    list.push(self.pop_stack());
+                }
+                list.reverse();
+                self.push_stack(Type::List(list));
+            }
+
+            // Insert value in the list
+            Some(Command::Insert) => {
+                let data = self.pop_stack();
+                let index = self.pop_number();
+                let mut list = self.pop_stack().get_list();
+                list.insert(index as usize, data);
+                self.push_stack(Type::List(list));
+            }
+
+            // Sorting in the list
+            Some(Command::Sort) => {
+                let mut list: Vec<String> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.to_owned().get_string())
+                    .collect();
+                list.sort();
+                self.push_stack(Type::List(
+                    list.iter()
+                        .map(|x| Type::String(x.to_string()))
+                        .collect::<Vec<_>>(),
+                ));
+            }
+
+            // Stable sorting by the comparator code
+            Some(Command::SortWith) => {
+                let code = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+                let list = self.sort_with(list, &code);
+                self.push_stack(Type::List(list));
+            }
+
+            // reverse in the list
+            Some(Command::Reverse) => {
+                let mut list = self.pop_stack().get_list();
+                list.reverse();
+                self.push_stack(Type::List(list));
+            }
+
+            // Iteration
+            Some(Command::For) => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+
+                let Some(mut items) = self.pop_items("for") else {
+                    return;
+                };
+
+                self.scopes.push(HashMap::new());
+                self.loops.push(0);
+                while let Some(x) = items.next(self) {
+                    self.bind(&vars, x.clone());
+                    self.evaluate_program(code.clone());
+                    if self.signal.take() == Some(Signal::Break) {
+                        break;
+                    }
+                    self.next_iteration();
+                }
+                self.loops.pop();
+                self.scopes.pop();
+            }
+
+            // Run the code a number of times, with counter "i" from 0.
+            // The code is parsed once for every iteration
+            Some(Command::Times) => {
+                let count = self.pop_number();
+                let code = self.pop_stack().get_string();
+                let syntax = self.parse_cached(code.clone());
+
+                self.scopes.push(HashMap::new());
+                self.loops.push(0);
+                let mut index = 0.0;
+                while index < count {
+                    self.bind("i", Type::Number(index));
+                    self.evaluate_tokens(code.clone(), Some(syntax.clone()));
+                    if self.signal.take() == Some(Signal::Break) || self.stopped() {
+                        break;
+                    }
+                    self.next_iteration();
+                    index += 1.0;
+                }
+                self.loops.pop();
+                self.scopes.pop();
+            }
+
+            // Generate a empty queue
+            Some(Command::QueueNew) => self.push_stack(Type::List(Vec::new())),
+
+            // Add value to the back of queue
+            Some(Command::Enqueue) => {
+                let data = self.pop_stack();
+                let mut queue = self.pop_stack().get_list();
+                queue.push(data);
+                self.push_stack(Type::List(queue));
+            }
+
+            // Take value from the front of queue
+            Some(Command::Dequeue) => {
+                let mut queue = self.pop_stack().get_list();
+                if queue.is_empty() {
+                    self.log("Error! the queue is empty\n".to_string());
+                    self.push_stack(Type::List(queue));
+                    self.push_stack(Type::Error("queue-empty".to_string()));
+                } else {
+                    let front = queue.remove(0);
+                    self.push_stack(Type::List(queue));
+                    self.push_stack(front);
+                }
+            }
+
+            // Generate a empty stack
+            Some(Command::StackNew) => self.push_stack(Type::List(Vec::new())),
+
+            // Push value to the top of stack
+            Some(Command::PushTo) => {
+                let data = self.pop_stack();
+                let mut stack = self.pop_stack().get_list();
+                stack.push(data);
+                self.push_stack(Type::List(stack));
+            }
+
+            // Pop value from the top of stack
+            Some(Command::PopFrom) => {
+                let mut stack = self.pop_stack().get_list();
+                match stack.pop() {
+                    Some(top) => {
+                        self.push_stack(Type::List(stack));
+                        self.push_stack(top);
+                    }
+                    None => {
+                        self.log("Error! the stack is empty\n".to_string());
+                        self.push_stack(Type::List(stack));
+                        self.push_stack(Type::Error("stack-empty".to_string()));
+                    }
+                }
             }
 
-            // Insert value in the list
-            "insert" => {
+            // Generate a empty heap
+            Some(Command::HeapNew) => self.push_stack(Type::List(Vec::new())),
+
+            // Push value in the heap
+            Some(Command::HeapPush) => {
                 let data = self.pop_stack();
-                let index = self.pop_stack().get_number();
-                let mut list = self.pop_stack().get_list();
-                list.insert(index as usize, data);
-                self.stack.push(Type::List(list));
+                let mut heap = self.pop_stack().get_list();
+                heap.push(data);
+                sift_up(&mut heap);
+                self.push_stack(Type::List(heap));
             }
 
-            // Sorting in the list
-            "sort" => {
-                let mut list: Vec<String> = self
-                    .pop_stack()
-                    .get_list()
-                    .iter()
-                    .map(|x| x.to_owned().get_string())
-                    .collect();
-                list.sort();
-                self.stack.push(Type::List(
-                    list.iter()
-                        .map(|x| Type::String(x.to_string()))
-                        .collect::<Vec<_>>(),
-                ));
+            // Pop minimum value of the heap
+            Some(Command::HeapPop) => {
+                let mut heap = self.pop_stack().get_list();
+                if heap.is_empty() {
+                    self.log("Error! the heap is empty\n".to_string());
+                    self.push_stack(Type::List(heap));
+                    self.push_stack(Type::Error("heap-empty".to_string()));
+                } else {
+                    let last = heap.len() - 1;
+                    heap.swap(0, last);
+                    let min = heap.pop();
+                    sift_down(&mut heap);
+                    self.push_stack(Type::List(heap));
+                    self.stack.extend(min);
+                }
             }
 
-            // reverse in the list
-            "reverse" => {
-                let mut list = self.pop_stack().get_list();
-                list.reverse();
-                self.stack.push(Type::List(list));
+            // Get minimum value of the heap
+            Some(Command::HeapPeek) => {
+                let heap = self.pop_stack().get_list();
+                let min = heap.first().cloned();
+                self.push_stack(Type::List(heap));
+                match min {
+                    Some(min) => self.push_stack(min),
+                    None => {
+                        self.log("Error! the heap is empty\n".to_string());
+                        self.push_stack(Type::Error("heap-empty".to_string()));
+                    }
+                }
             }
 
-            // Iteration
-            "for" => {
+            // Mapping a list
+            Some(Command::Map) => {
                 let code = self.pop_stack().get_string();
                 let vars = self.pop_stack().get_string();
-                let list = self.pop_stack().get_list();
+                let Some(mut items) = self.pop_items("map") else {
+                    return;
+                };
+
+                let mut result_list = Vec::new();
+                self.scopes.push(HashMap::new());
+                while let Some(x) = items.next(self) {
+                    self.bind(&vars, x.clone());
 
-                list.iter().for_each(|x| {
-                    self.memory
-                        .entry(vars.clone())
-                        .and_modify(|value| *value = x.clone())
-                        .or_insert(x.clone());
                     self.evaluate_program(code.clone());
-                });
+                    result_list.push(self.pop_stack());
+                }
+                self.scopes.pop();
+
+                self.push_stack(Type::List(result_list));
             }
 
-            // Mapping a list
-            "map" => {
+            // Filtering a list value
+            Some(Command::Filter) => {
                 let code = self.pop_stack().get_string();
                 let vars = self.pop_stack().get_string();
-                let list = self.pop_stack().get_list();
+                let Some(mut items) = self.pop_items("filter") else {
+                    return;
+                };
 
                 let mut result_list = Vec::new();
-                for x in list.iter() {
-                    self.memory
-                        .entry(vars.clone())
-                        .and_modify(|value| *value = x.clone())
-                        .or_insert(x.clone());
+
+                self.scopes.push(HashMap::new());
+                while let Some(x) = items.next(self) {
+                    self.bind(&vars, x.clone());
 
                     self.evaluate_program(code.clone());
-                    result_list.push(self.pop_stack());
+                    if self.pop_stack().get_bool() {
+                        result_list.push(x.clone());
+                    }
                 }
+                self.scopes.pop();
 
-                self.stack.push(Type::List(result_list));
+                self.push_stack(Type::List(result_list));
             }
 
-            // Filtering a list value
-            "filter" => {
+            // Grouping list values by the key
+            Some(Command::GroupBy) => {
                 let code = self.pop_stack().get_string();
                 let vars = self.pop_stack().get_string();
+                let Some(mut items) = self.pop_items("group-by") else {
+                    return;
+                };
+
+                let mut groups: HashMap<String, Type> = HashMap::new();
+                self.scopes.push(HashMap::new());
+                while let Some(x) = items.next(self) {
+                    self.bind(&vars, x.clone());
+
+                    self.evaluate_program(code.clone());
+                    let key = self.pop_stack().get_string();
+                    if let Type::List(group) = groups.entry(key).or_insert(Type::List(Vec::new())) {
+                        group.push(x.clone());
+                    }
+                }
+                self.scopes.pop();
+
+                self.push_stack(Type::Object("dict".to_string(), groups));
+            }
+
+            // Count how many times each value appears
+            Some(Command::Frequencies) => {
                 let list = self.pop_stack().get_list();
 
-                let mut result_list = Vec::new();
+                let mut counts: HashMap<String, Type> = HashMap::new();
+                for mut x in list {
+                    counts
+                        .entry(x.get_string())
+                        .and_modify(|value| *value = Type::Number(value.get_number() + 1.0))
+                        .or_insert(Type::Number(1.0));
+                }
+
+                self.push_stack(Type::Object("dict".to_string(), counts));
+            }
+
+            // Merge two dicts, resolving shared keys by the policy
+            Some(Command::DictMerge) => {
+                let policy = self.pop_stack().get_string();
+                let right = self.pop_stack();
+                let left = self.pop_stack();
+                let (Type::Object(name, mut merged), Type::Object(_, other)) = (left, right) else {
+                    self.push_stack(Type::Error("not-object".to_string()));
+                    return;
+                };
+                if !["left", "right", "error"].contains(&policy.as_str()) {
+                    self.log(format!("Error! the merge policy \"{policy}\" is unknown\n"));
+                    self.push_stack(Type::Error("merge-policy".to_string()));
+                    return;
+                }
+
+                let mut keys: Vec<String> = other.keys().cloned().collect();
+                keys.sort();
+                for key in keys {
+                    let value = other[&key].clone();
+                    if merged.contains_key(&key) {
+                        match policy.as_str() {
+                            "left" => continue,
+                            "error" => {
+                                self.log(format!("Error! the key \"{key}\" is in both dicts\n"));
+                                self.push_stack(Type::Error("dict-conflict".to_string()));
+                                return;
+                            }
+                            _ => {}
+                        }
+                    }
+                    merged.insert(key, value);
+                }
+
+                self.push_stack(Type::Object(name, merged));
+            }
+
+            // Transform each value of dict, keeping the keys
+            Some(Command::DictMapValues) => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let Type::Object(name, dict) = self.pop_stack() else {
+                    self.push_stack(Type::Error("not-object".to_string()));
+                    return;
+                };
+
+                let mut pairs: Vec<(String, Type)> = dict.into_iter().collect();
+                pairs.sort_by(|a, b| a.0.cmp(&b.0));
+
+                let mut result = HashMap::new();
+                self.scopes.push(HashMap::new());
+                for (key, value) in pairs {
+                    if self.stopped() {
+                        break;
+                    }
+                    self.bind(&vars, value);
+                    self.evaluate_program(code.clone());
+                    let value = self.pop_stack();
+                    result.insert(key, value);
+                }
+                self.scopes.pop();
 
-                for x in list.iter() {
-                    self.memory
-                        .entry(vars.clone())
-                        .and_modify(|value| *value = x.clone())
-                        .or_insert(x.clone());
+                self.push_stack(Type::Object(name, result));
+            }
+
+            // Keep the entries of dict that the predicate over key and value accepts
+            Some(Command::DictFilter) => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let Type::Object(name, dict) = self.pop_stack() else {
+                    self.push_stack(Type::Error("not-object".to_string()));
+                    return;
+                };
+                let names: Vec<&str> = vars.split_whitespace().collect();
+                let [key_var, value_var] = names[..] else {
+                    self.log(format!(
+                        "Error! dict-filter needs key and value names, but got \"{vars}\"\n"
+                    ));
+                    self.push_stack(Type::Error("filter-vars".to_string()));
+                    return;
+                };
+                let (key_var, value_var) = (key_var.to_string(), value_var.to_string());
 
+                let mut pairs: Vec<(String, Type)> = dict.into_iter().collect();
+                pairs.sort_by(|a, b| a.0.cmp(&b.0));
+
+                let mut result = HashMap::new();
+                self.scopes.push(HashMap::new());
+                for (key, value) in pairs {
+                    if self.stopped() {
+                        break;
+                    }
+                    self.bind(&key_var, Type::String(key.clone()));
+                    self.bind(&value_var, value.clone());
                     self.evaluate_program(code.clone());
                     if self.pop_stack().get_bool() {
-                        result_list.push(x.clone());
+                        result.insert(key, value);
+                    }
+                }
+                self.scopes.pop();
+
+                self.push_stack(Type::Object(name, result));
+            }
+
+            // Convert dict to the list of key and value pairs
+            Some(Command::DictToList) => match self.pop_stack() {
+                dict @ Type::Object(..) => {
+                    let mut items = dict.items();
+                    let mut pairs = Vec::new();
+                    while let Some(pair) = items.as_mut().and_then(|x| x.next(self)) {
+                        pairs.push(pair);
                     }
+                    self.push_stack(Type::List(pairs))
                 }
+                _ => self.push_stack(Type::Error("not-object".to_string())),
+            },
 
-                self.stack.push(Type::List(result_list));
+            // Convert the list of key and value pairs to dict
+            Some(Command::ListToDict) => {
+                let list = self.pop_stack().get_list();
+                let mut dict = HashMap::new();
+                for pair in list {
+                    let pair = match pair {
+                        Type::List(pair) => <[Type; 2]>::try_from(pair).ok(),
+                        _ => None,
+                    };
+                    let Some([mut key, value]) = pair else {
+                        self.log("Error! list-to-dict needs pairs of key and value\n".to_string());
+                        self.push_stack(Type::Error("not-pair".to_string()));
+                        return;
+                    };
+                    dict.insert(key.get_string(), value);
+                }
+                self.push_stack(Type::Object("dict".to_string(), dict));
             }
 
             // Generate value from list
-            "reduce" => {
+            Some(Command::Reduce) => {
                 let code = self.pop_stack().get_string();
                 let now = self.pop_stack().get_string();
                 let acc = self.pop_stack().get_string();
-                let list = self.pop_stack().get_list();
+                let Some(mut items) = self.pop_items("reduce") else {
+                    return;
+                };
 
-                self.memory
-                    .entry(acc.clone())
-                    .and_modify(|value| *value = Type::String("".to_string()))
-                    .or_insert(Type::String("".to_string()));
+                self.scopes.push(HashMap::new());
+                self.bind(&acc, Type::String("".to_string()));
 
-                for x in list.iter() {
-                    self.memory
-                        .entry(now.clone())
-                        .and_modify(|value| *value = x.clone())
-                        .or_insert(x.clone());
+                while let Some(x) = items.next(self) {
+                    self.bind(&now, x.clone());
 
                     self.evaluate_program(code.clone());
                     let result = self.pop_stack();
 
-                    self.memory
-                        .entry(acc.clone())
-                        .and_modify(|value| *value = result.clone())
-                        .or_insert(result);
+                    self.bind(&acc, result);
                 }
 
-                let result = self.memory.get(&acc);
-                self.stack
-                    .push(result.unwrap_or(&Type::String("".to_string())).clone());
+                let result = self.scopes.pop().and_then(|mut scope| scope.remove(&acc));
+                self.push_stack(result.unwrap_or(Type::String("".to_string())));
+            }
+
+            // Generate a range from min to max by the step, that goes down if min is
+            // larger. range-inclusive also has max at the end
+            Some(Command::Range | Command::RangeInclusive) => {
+                let step = self.pop_number();
+                let max = self.pop_number();
+                let min = self.pop_number();
+                if !(step.is_finite() && step != 0.0 && min.is_finite() && max.is_finite()) {
+                    self.log(format!(
+                        "Error! the range from {min} to {max} by {step} can't be generated\n"
+                    ));
+                    self.push_stack(Type::Error("invalid-range".to_string()));
+                    return;
+                }
 
-                self.memory
-                    .entry(acc.clone())
-                    .and_modify(|value| *value = Type::String("".to_string()))
-                    .or_insert(Type::String("".to_string()));
+                let step = if max < min { -step.abs() } else { step.abs() };
+                // Tolerance of rounding error of fractional step, such as 0.1
+                let span = (max - min) / step;
+                let count = match builtin == Some(Command::RangeInclusive) {
+                    true => (span + 1e-9).floor() + 1.0,
+                    false => (span - 1e-9).ceil(),
+                };
+                if count > RANGE_LIMIT as f64 {
+                    self.log(format!(
+                        "Error! the range from {min} to {max} by {step} has more than {RANGE_LIMIT} items\n"
+                    ));
+                    self.push_stack(Type::Error("invalid-range".to_string()));
+                    return;
+                }
+                let count = count as usize;
+                let range: Vec<Type> = (0..count)
+                    .map(|i| Type::Number(min + i as f64 * step))
+                    .collect();
+                self.push_stack(Type::List(range));
             }
 
-            // Generate a range
-            "range" => {
-                let step = self.pop_stack().get_number();
-                let max = self.pop_stack().get_number();
-                let min = self.pop_stack().get_number();
+            // Get all indices of the value in the list
+            Some(Command::FindAllIndices) => {
+                let value = self.pop_stack();
+                let list = self.pop_stack().get_list();
+                self.push_stack(Type::List(
+                    list.iter()
+                        .enumerate()
+                        .filter(|(_, x)| **x == value)
+                        .map(|(index, _)| Type::Number(index as f64))
+                        .collect::<Vec<Type>>(),
+                ));
+            }
 
-                let mut range: Vec<Type> = Vec::new();
+            // Generate a lazy range
+            Some(Command::LazyRange) => {
+                let step = self.pop_number();
+                let end = self.pop_number();
+                let start = self.pop_number();
+                if !(step.is_finite() && step != 0.0 && start.is_finite() && end.is_finite())
+                    || (end - start) / step > LAZY_LIMIT
+                {
+                    self.log(format!(
+                        "Error! the lazy range from {start} to {end} by {step} can't be generated\n"
+                    ));
+                    self.push_stack(Type::Error("invalid-range".to_string()));
+                    return;
+                }
+                self.push_stack(Type::Lazy(Lazy {
+                    start,
+                    end,
+                    step,
+                    maps: Vec::new(),
+                    take: None,
+                }));
+            }
 
-                for i in (min as usize..max as usize).step_by(step as usize) {
-                    range.push(Type::Number(i as f64));
+            // Mapping a lazy sequence when its values are generated
+            Some(Command::LazyMap) => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                match self.pop_stack() {
+                    Type::Lazy(mut lazy) => {
+                        lazy.maps.push((vars, code));
+                        self.push_stack(Type::Lazy(lazy));
+                    }
+                    _ => self.push_stack(Type::Error("not-lazy".to_string())),
                 }
+            }
 
-                self.stack.push(Type::List(range));
+            // Limit the number of values of lazy sequence
+            Some(Command::LazyTake) => {
+                let count = self.pop_number() as usize;
+                match self.pop_stack() {
+                    Type::Lazy(mut lazy) => {
+                        lazy.take = Some(lazy.take.map_or(count, |take| take.min(count)));
+                        self.push_stack(Type::Lazy(lazy));
+                    }
+                    mut other => {
+                        let mut list = other.get_list();
+                        list.truncate(count);
+                        self.push_stack(Type::List(list));
+                    }
+                }
             }
 
+            // Generate all values of lazy sequence
+            Some(Command::Collect) => match self.pop_stack() {
+                Type::Lazy(lazy) => {
+                    if lazy.len() > RANGE_LIMIT {
+                        self.log(format!(
+                            "Error! the lazy sequence has more than {RANGE_LIMIT} values to collect\n"
+                        ));
+                        self.push_stack(Type::Error("invalid-range".to_string()));
+                        return;
+                    }
+                    // Stop generating when the fuel or time runs out
+                    let mut items = Items::Lazy(lazy, 0);
+                    let mut list = Vec::new();
+                    while let Some(value) = items.next(self) {
+                        list.push(value);
+                    }
+                    self.push_stack(Type::List(list));
+                }
+                mut other => self.push_stack(Type::List(other.get_list())),
+            },
+
             // Get length of list
-            "len" => {
-                let data = self.pop_stack().get_list();
-                self.stack.push(Type::Number(data.len() as f64));
+            Some(Command::Len) => match self.pop_stack() {
+                Type::Lazy(lazy) => self.push_stack(Type::Number(lazy.len() as f64)),
+                mut other => {
+                    let data = other.get_list();
+                    self.push_stack(Type::Number(data.len() as f64));
+                }
+            },
+
+            // Commands of set processing
+
+            // Generate a empty set
+            Some(Command::SetNew) => self.push_stack(Type::Set(HashMap::new())),
+
+            // Add value in the set
+            Some(Command::SetAdd) => {
+                let data = self.pop_stack();
+                let mut set = self.pop_stack().get_set();
+                set.insert(data.canonical(), data);
+                self.push_stack(Type::Set(set));
+            }
+
+            // Is the value included in the set
+            Some(Command::SetHas) => {
+                let data = self.pop_stack();
+                let set = self.pop_stack().get_set();
+                self.push_stack(Type::Bool(set.contains_key(&data.canonical())));
+            }
+
+            // Remove value from the set
+            Some(Command::SetRemove) => {
+                let data = self.pop_stack();
+                let mut set = self.pop_stack().get_set();
+                set.remove(&data.canonical());
+                self.push_stack(Type::Set(set));
+            }
+
+            // Convert list to set
+            Some(Command::ListToSet) => {
+                let set = self.pop_stack().get_set();
+                self.push_stack(Type::Set(set));
+            }
+
+            // Convert set to list
+            Some(Command::SetToList) => {
+                let list = self.pop_stack().get_list();
+                self.push_stack(Type::List(list));
             }
 
             // Commands of memory manage
 
             // pop in the stack
-            "pop" => {
+            Some(Command::Pop) => {
                 self.pop_stack();
             }
 
             // Get size of stack
-            "size-stack" => {
+            Some(Command::Depth) => {
                 let len: f64 = self.stack.len() as f64;
-                self.stack.push(Type::Number(len));
+                self.push_stack(Type::Number(len));
+            }
+
+            // Define variable at memory
+            Some(Command::Var) => {
+                let name = self.pop_stack().get_string();
+                let data = self.pop_stack();
+                if self.reject_constant(&name) {
+                    return;
+                }
+                if let Some(access) = &mut self.access {
+                    access.writes.insert(name.clone());
+                }
+                if Command::parse(&name).is_some() {
+                    let message = format!("the variable \"{name}\" shadows the command");
+                    self.warn("shadowed-variable", Severity::Warning, message);
+                    self.purity.clear();
+                }
+                if let Some(doc) = self.pending_doc.take() {
+                    self.docs.insert(name.clone(), doc);
+                }
+                if let Some(cache) = self.memo.get_mut(&name) {
+                    cache.clear();
+                }
+                self.hook_var_write(&name, Some(&data));
+                let owner = self.owner_scope(&name);
+                for index in self.captures.clone() {
+                    // Captured value is older, so the assigned one is seen through it
+                    if owner.is_none_or(|owner| index > owner) {
+                        self.scopes[index].remove(&name);
+                    }
+                }
+                match owner {
+                    // Assign to the local variable, if it is declared
+                    Some(index) => {
+                        self.scopes[index].insert(name, data);
+                    }
+                    None => {
+                        self.memory.insert(name, data);
+                    }
+                }
+                self.show_variables()
+            }
+
+            // Define variable that can't be reassigned or freed
+            Some(Command::Const) => {
+                let name = self.pop_stack().get_string();
+                let data = self.pop_stack();
+                if self.reject_constant(&name) {
+                    return;
+                }
+                if let Some(doc) = self.pending_doc.take() {
+                    self.docs.insert(name.clone(), doc);
+                }
+                self.hook_var_write(&name, Some(&data));
+                self.memory.insert(name.clone(), data);
+                self.constants.insert(name);
+                self.show_variables()
+            }
+
+            // Define enum type, whose variants are constants "name.variant" and
+            // the list of them is constant "name"
+            Some(Command::Defenum) => {
+                let name = self.pop_stack().get_string();
+                let values: Vec<Type> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter_mut()
+                    .map(|x| Type::Enum(name.clone(), x.get_string()))
+                    .collect();
+
+                let mut constants: Vec<(String, Type)> =
+                    values.iter().map(|x| (x.display(), x.clone())).collect();
+                constants.push((name, Type::List(values)));
+                for (name, value) in constants {
+                    // The same definition can be run again
+                    if self.memory.get(&name) != Some(&value) && self.reject_constant(&name) {
+                        return;
+                    }
+                    self.hook_var_write(&name, Some(&value));
+                    self.memory.insert(name.clone(), value);
+                    self.constants.insert(name);
+                }
+                self.show_variables()
+            }
+
+            // Declare variable in the scope of running loop or word
+            Some(Command::Local) => {
+                let name = self.pop_stack().get_string();
+                let data = self.pop_stack();
+                // At the top level, it is a global variable
+                if self.scopes.is_empty() && self.reject_constant(&name) {
+                    return;
+                }
+                if Command::parse(&name).is_some() {
+                    let message = format!("the variable \"{name}\" shadows the command");
+                    self.warn("shadowed-variable", Severity::Warning, message);
+                    self.purity.clear();
+                }
+                self.hook_var_write(&name, Some(&data));
+                self.bind(&name, data);
+            }
+
+            // Get data type of value
+            Some(Command::Type) => {
+                let result = self.pop_stack().type_name();
+                self.push_stack(Type::String(result));
+            }
+
+            // Is the value nil
+            Some(Command::IsNil) => {
+                let value = self.pop_stack();
+                self.push_stack(Type::Bool(value == Type::Nil));
+            }
+
+            // Is it the number that is not a number
+            Some(Command::IsNan) => {
+                let value = self.pop_stack();
+                self.push_stack(Type::Bool(matches!(value, Type::Number(n) if n.is_nan())));
+            }
+
+            // Is it the number that is neither infinite nor NaN
+            Some(Command::IsFinite) => {
+                let value = self.pop_stack();
+                self.push_stack(Type::Bool(
+                    matches!(value, Type::Number(n) if n.is_finite()),
+                ));
+            }
+
+            // Explicit data type casting
+            Some(Command::Cast) => {
+                let types = self.pop_stack().get_string();
+                let mut value = self.pop_stack();
+                match types.as_str() {
+                    "number" => self.push_stack(Type::Number(value.get_number())),
+                    "string" => self.push_stack(Type::String(value.get_string())),
+                    "bool" => self.push_stack(Type::Bool(value.get_bool())),
+                    "list" => self.push_stack(Type::List(value.get_list())),
+                    "error" => self.push_stack(Type::Error(value.get_string())),
+                    "set" => self.push_stack(Type::Set(value.get_set())),
+                    _ => self.push_stack(value),
+                }
+            }
+
+            // Is string include only number
+            Some(Command::OnlyNumber) => {
+                let text = self.pop_stack().get_string();
+                self.push_stack(Type::Bool(parse_number(text.trim()).is_some()))
+            }
+
+            // Get memory information
+            Some(Command::Mem) => {
+                let mut list: Vec<Type> = Vec::new();
+                for (name, _) in self.memory.clone() {
+                    list.push(Type::String(name))
+                }
+                self.push_stack(Type::List(list))
+            }
+
+            // Get names of the variables in the namespace, such as "width" of "config:width"
+            Some(Command::NsList) => {
+                let prefix = format!("{}:", self.pop_stack().get_string());
+                let mut names: Vec<String> = self
+                    .memory
+                    .keys()
+                    .filter_map(|name| name.strip_prefix(&prefix))
+                    .map(|name| name.to_string())
+                    .collect();
+                names.sort();
+                self.push_stack(Type::List(names.into_iter().map(Type::String).collect()))
+            }
+
+            // Copy all variables of the namespace into another one
+            Some(Command::NsCopy) => {
+                let target = format!("{}:", self.pop_stack().get_string());
+                let source = format!("{}:", self.pop_stack().get_string());
+                let mut copied: Vec<(String, Type)> = self
+                    .memory
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        let name = name.strip_prefix(&source)?;
+                        Some((format!("{target}{name}"), value.clone()))
+                    })
+                    .collect();
+                copied.sort_by(|a, b| a.0.cmp(&b.0));
+                if copied.iter().any(|(name, _)| self.reject_constant(name)) {
+                    return;
+                }
+                for (name, value) in copied {
+                    if let Some(access) = &mut self.access {
+                        access.writes.insert(name.clone());
+                    }
+                    self.hook_var_write(&name, Some(&value));
+                    self.memory.insert(name, value);
+                }
+                self.show_variables();
+            }
+
+            // Free up all variables of the namespace
+            Some(Command::NsClear) => {
+                let prefix = format!("{}:", self.pop_stack().get_string());
+                let mut names: Vec<String> = self
+                    .memory
+                    .keys()
+                    .filter(|name| name.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                names.sort();
+                if names.iter().any(|name| self.reject_constant(name)) {
+                    return;
+                }
+                for name in names {
+                    if let Some(access) = &mut self.access {
+                        access.writes.insert(name.clone());
+                    }
+                    self.memory.remove(&name);
+                    self.hook_var_write(&name, None);
+                }
+                self.show_variables();
+            }
+
+            // Define the named word, whose body is string or list of code
+            Some(Command::Define) => {
+                let name = self.pop_stack().get_string();
+                let body = match self.pop_stack() {
+                    Type::List(list) => list
+                        .into_iter()
+                        .map(|mut x| x.get_string())
+                        .collect::<Vec<String>>()
+                        .join(" "),
+                    mut other => other.get_string(),
+                };
+                if Command::parse(&name).is_some() {
+                    self.log(format!(
+                        "Error! the built-in command \"{name}\" can't be defined\n"
+                    ));
+                    self.push_stack(Type::Error("reserved-name".to_string()));
+                    return;
+                }
+                if let Some(doc) = self.pending_doc.take() {
+                    self.docs.insert(name.clone(), doc);
+                }
+                if let Some(cache) = self.memo.get_mut(&name) {
+                    cache.clear();
+                }
+                if let Some(access) = &mut self.access {
+                    access.writes.insert(name.clone());
+                }
+                // Stack effect declared at the start, such as `( n n -- n )`
+                let body = match Signature::split_declared(&body, self.full_width()) {
+                    Some((signature, code)) => {
+                        self.signatures.insert(name.clone(), signature);
+                        code
+                    }
+                    None => body,
+                };
+                self.forget_purity();
+                let message = format!("* Defined word {name}\n");
+                self.record(LogLevel::Info, LogCategory::Memory, message);
+                self.words.insert(name, body);
             }
 
-            // Define variable at memory
-            "var" => {
+            // Define struct type with words "make-name" constructing it from the field
+            // values, "name-field" getting each field and "name?" checking the type
+            Some(Command::Defstruct) => {
                 let name = self.pop_stack().get_string();
-                let data = self.pop_stack();
-                self.memory
-                    .entry(name)
-                    .and_modify(|value| *value = data.clone())
-                    .or_insert(data);
-                self.show_variables()
-            }
+                let fields: Vec<String> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter_mut()
+                    .map(|x| x.get_string())
+                    .collect();
 
-            // Get data type of value
-            "type" => {
-                let result = match self.pop_stack() {
-                    Type::Number(_) => "number".to_string(),
-                    Type::String(_) => "string".to_string(),
-                    Type::Bool(_) => "bool".to_string(),
-                    Type::List(_) => "list".to_string(),
-                    Type::Error(_) => "error".to_string(),
-                    Type::Object(name, _) => name
+                let mut words = vec![
+                    (format!("make-{name}"), format!("({name}) new")),
+                    (format!("{name}?"), format!("type ({name}) equal")),
+                ];
+                for field in &fields {
+                    words.push((format!("{name}-{field}"), format!("({field}) field-get")));
+                }
+                if let Some((word, _)) = words
+                    .iter()
+                    .find(|(word, _)| Command::parse(word).is_some())
+                {
+                    self.log(format!(
+                        "Error! the built-in command \"{word}\" can't be defined\n"
+                    ));
+                    self.push_stack(Type::Error("reserved-name".to_string()));
+                    return;
+                }
+
+                self.structs.insert(name, fields);
+                self.forget_purity();
+                for (word, body) in words {
+                    if let Some(cache) = self.memo.get_mut(&word) {
+                        cache.clear();
+                    }
+                    if let Some(access) = &mut self.access {
+                        access.writes.insert(word.clone());
+                    }
+                    let message = format!("* Defined word {word}\n");
+                    self.record(LogLevel::Info, LogCategory::Memory, message);
+                    self.words.insert(word, body);
                 }
-                ;
-                self.stack.push(Type::String(result));
             }
 
-            // Explicit data type casting
-            "cast" => {
-                let types = self.pop_stack().get_string();
-                let mut value = self.pop_stack();
-                match types.as_str() {
-                    "number" => self.stack.push(Type::Number(value.get_number())),
-                    "string" => self.stack.push(Type::String(value.get_string())),
-                    "bool" => self.stack.push(Type::Bool(value.get_bool())),
-                    "list" => self.stack.push(Type::List(value.get_list())),
-                    "error" => self.stack.push(Type::Error(value.get_string())),
-                    _ => self.stack.push(value),
+            // Declare stack effect of the word, then it's called by its name
+            Some(Command::Signature) => {
+                let name = self.pop_stack().get_string();
+                let declaration = self.pop_stack().get_string();
+                match Signature::parse(&declaration) {
+                    Some(signature) => {
+                        if let Some(access) = &mut self.access {
+                            access.writes.insert(name.clone());
+                        }
+                        self.forget_purity();
+                        self.signatures.insert(name, signature);
+                    }
+                    None => {
+                        self.log(format!("Error! invalid signature \"{declaration}\"\n"));
+                        self.push_stack(Type::Error("invalid-signature".to_string()));
+                    }
                 }
             }
 
-            // Is string include only number
-            "only-number" => match self.pop_stack().get_string().trim().parse::<f64>() {
-                Ok(_) => self.stack.push(Type::Bool(true)),
-                Err(_) => self.stack.push(Type::Bool(false)),
-            },
+            // Cache results of the word by its arguments
+            Some(Command::Memoize) => {
+                let name = self.pop_stack().get_string();
+                if self.signatures.contains_key(&name) {
+                    self.memo.entry(name).or_default();
+                } else {
+                    self.log(format!("Error! {name} needs signature to be memoized\n"));
+                    self.push_stack(Type::Error("no-signature".to_string()));
+                }
+            }
 
-            // Get memory information
-            "mem" => {
-                let mut list: Vec<Type> = Vec::new();
-                for (name, _) in self.memory.clone() {
-                    list.push(Type::String(name))
+            // Get documentation of the word
+            Some(Command::Help) => {
+                let name = self.pop_stack().get_string();
+                match self.docs.get(&name) {
+                    Some(doc) => self.push_stack(Type::String(doc.clone())),
+                    None => {
+                        self.log(format!("Error! \"{name}\" has no documentation\n"));
+                        self.push_stack(Type::Error("no-help".to_string()));
+                    }
                 }
-                self.stack.push(Type::List(list))
             }
 
             // Free up memory space of variable
-            "free" => {
+            Some(Command::Free) => {
                 let name = self.pop_stack().get_string();
-                self.memory.remove(name.as_str());
+                if self.reject_constant(&name) {
+                    return;
+                }
+                if let Some(access) = &mut self.access {
+                    access.writes.insert(name.clone());
+                }
+                match self.scopes.iter_mut().rev().find(|x| x.contains_key(&name)) {
+                    Some(scope) => scope.remove(&name),
+                    None => self.memory.remove(&name),
+                };
+                self.hook_var_write(&name, None);
                 self.show_variables();
             }
 
             // Copy stack's top value
-            "copy" => {
+            Some(Command::Dup) => {
                 let data = self.pop_stack();
-                self.stack.push(data.clone());
-                self.stack.push(data);
+                self.push_stack(data.clone());
+                self.push_stack(data);
             }
 
             // Swap stack's top 2 value
-            "swap" => {
+            Some(Command::Swap) => {
                 let b = self.pop_stack();
                 let a = self.pop_stack();
-                self.stack.push(b);
-                self.stack.push(a);
+                self.push_stack(b);
+                self.push_stack(a);
             }
 
             // Commands of object oriented system
 
             // Generate a instance of object
-            "instance" => {
+            Some(Command::Instance) => {
                 let data = self.pop_stack().get_list();
                 let mut class = self.pop_stack().get_list();
                 let mut object: HashMap<String, Type> = HashMap::new();
@@ -831,7 +6624,7 @@ impl Executor {
                     class[0].get_string()
                 } else {
                     self.log("Error! the type name is not found.".to_string());
-                    self.stack.push(Type::Error("instance-name".to_string()));
+                    self.push_stack(Type::Error("instance-name".to_string()));
                     return;
                 };
 
@@ -850,50 +6643,46 @@ impl Executor {
                         object.insert(item[0].clone().get_string(), item[1].clone());
                     } else {
                         self.log("Error! the class data structure is wrong.".to_string());
-                        self.stack.push(Type::Error("instance-default".to_string()));
+                        self.push_stack(Type::Error("instance-default".to_string()));
                     }
                 }
 
-                self.stack.push(Type::Object(name, object))
+                self.push_stack(Type::Object(name, object))
             }
 
             // Get property of object
-            "property" => {
+            Some(Command::Property) => {
                 let name = self.pop_stack().get_string();
                 match self.pop_stack() {
-                    Type::Object(_, data) => self.stack.push(
-                        data.get(name.as_str())
-                            .unwrap_or(&Type::Error("property".to_string()))
-                            .clone(),
-                    ),
-                    _ => self.stack.push(Type::Error("not-object".to_string())),
+                    Type::Object(_, data) => {
+                        self.push_stack(data.get(name.as_str()).cloned().unwrap_or(Type::Nil))
+                    }
+                    _ => self.push_stack(Type::Error("not-object".to_string())),
                 }
             }
 
             // Call the method of object
-            "method" => {
+            Some(Command::Method) => {
                 let method = self.pop_stack().get_string();
                 match self.pop_stack() {
                     Type::Object(name, value) => {
                         let data = Type::Object(name, value.clone());
-                        self.memory
-                            .entry("self".to_string())
-                            .and_modify(|value| *value = data.clone())
-                            .or_insert(data);
-
                         let program: String = match value.get(&method) {
                             Some(i) => i.to_owned().get_string().to_string(),
                             None => "".to_string(),
                         };
 
-                        self.evaluate_program(program)
+                        self.scopes
+                            .push(HashMap::from([("self".to_string(), data)]));
+                        self.evaluate_program(program);
+                        self.scopes.pop();
                     }
-                    _ => self.stack.push(Type::Error("not-object".to_string())),
+                    _ => self.push_stack(Type::Error("not-object".to_string())),
                 }
             }
 
             // Modify the property of object
-            "modify" => {
+            Some(Command::Modify) => {
                 let data = self.pop_stack();
                 let property = self.pop_stack().get_string();
                 match self.pop_stack() {
@@ -903,30 +6692,661 @@ impl Executor {
                             .and_modify(|value| *value = data.clone())
                             .or_insert(data.clone());
 
-                        self.stack.push(Type::Object(name, value))
+                        self.push_stack(Type::Object(name, value))
+                    }
+                    _ => self.push_stack(Type::Error("not-object".to_string())),
+                }
+            }
+
+            // Define struct type with field names
+            Some(Command::Struct) => {
+                let name = self.pop_stack().get_string();
+                let fields: Vec<String> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter_mut()
+                    .map(|x| x.get_string())
+                    .collect();
+                self.structs.insert(name, fields);
+            }
+
+            // Generate a record of struct type
+            Some(Command::New) => {
+                let name = self.pop_stack().get_string();
+                let Some(fields) = self.structs.get(&name).cloned() else {
+                    self.log(format!("Error! the struct \"{name}\" is not defined\n"));
+                    self.push_stack(Type::Error("struct-undefined".to_string()));
+                    return;
+                };
+
+                let mut values: Vec<Type> = fields.iter().map(|_| self.pop_stack()).collect();
+                values.reverse();
+                self.push_stack(Type::Object(
+                    name,
+                    fields
+                        .into_iter()
+                        .zip(values)
+                        .collect::<HashMap<String, Type>>(),
+                ));
+            }
+
+            // Get field value of record
+            Some(Command::FieldGet) => {
+                let field = self.pop_stack().get_string();
+                match self.pop_stack() {
+                    Type::Object(_, data) => match data.get(&field) {
+                        Some(value) => self.push_stack(value.clone()),
+                        None => {
+                            self.log(format!("Error! the field \"{field}\" is not found\n"));
+                            self.push_stack(Type::Error("field-undefined".to_string()));
+                        }
+                    },
+                    _ => self.push_stack(Type::Error("not-object".to_string())),
+                }
+            }
+
+            // Generate a record that changed field value
+            Some(Command::FieldSet) => {
+                let data = self.pop_stack();
+                let field = self.pop_stack().get_string();
+                match self.pop_stack() {
+                    Type::Object(name, mut value) => {
+                        if let Some(old) = value.get_mut(&field) {
+                            *old = data;
+                            self.push_stack(Type::Object(name, value));
+                        } else {
+                            self.log(format!("Error! the field \"{field}\" is not found\n"));
+                            self.push_stack(Type::Error("field-undefined".to_string()));
+                        }
                     }
-                    _ => self.stack.push(Type::Error("not-object".to_string())),
+                    _ => self.push_stack(Type::Error("not-object".to_string())),
                 }
             }
 
             // Get all of properties
-            "all" => match self.pop_stack() {
-                Type::Object(_, data) => self.stack.push(Type::List(
+            Some(Command::All) => match self.pop_stack() {
+                Type::Object(_, data) => self.push_stack(Type::List(
                     data.keys()
                         .map(|x| Type::String(x.to_owned()))
                         .collect::<Vec<Type>>(),
                 )),
-                _ => self.stack.push(Type::Error("not-object".to_string())),
+                _ => self.push_stack(Type::Error("not-object".to_string())),
             },
 
             // If it is not recognized as a command, use it as a string.
-            _ => self.stack.push(Type::String(command)),
+            None if self.words.contains_key(&command) => self.call_defined(command),
+            None if self.config.strict => {
+                self.log(format!("Error! the command \"{command}\" is not found\n"));
+                self.push_stack(Type::Error("unknown-command".to_string()));
+            }
+            None => {
+                let message = format!("the unknown word \"{command}\" is treated as string");
+                self.warn("unknown-word", Severity::Info, message);
+                self.push_stack(Type::String(command))
+            }
+        }
+    }
+
+    /// Check it is running in the browser, or push error
+    fn require_browser(&mut self, command: &str) -> bool {
+        if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+            true
+        } else {
+            self.log(format!(
+                "Error! {command} is only available in the browser\n"
+            ));
+            self.push_stack(Type::Error("unsupported".to_string()));
+            false
+        }
+    }
+
+    /// Handle messages received by WebSocket
+    fn dispatch_messages(&mut self) {
+        for index in 0..self.sockets.len() {
+            let Some(handler) = self.sockets[index].handler.clone() else {
+                continue;
+            };
+            let messages: Vec<String> = self.sockets[index]
+                .state
+                .borrow_mut()
+                .inbox
+                .drain(..)
+                .collect();
+            for message in messages {
+                if self.config.audit {
+                    self.audit.push(AuditEntry {
+                        command: "ws-message".to_string(),
+                        result: message.clone(),
+                    });
+                }
+                self.push_stack(Type::String(message));
+                self.evaluate_program(handler.clone());
+            }
+        }
+    }
+
+    /// Check the capability is allowed, or push error
+    fn require(&mut self, capability: &str) -> bool {
+        if self.config.capabilities.contains(capability) {
+            return true;
+        }
+
+        // Ask the host at the first time, and remember the answer
+        let allowed = match self.permissions.get(capability) {
+            Some(allowed) => *allowed,
+            None => match &self.permission_callback {
+                Some(callback) => {
+                    let answer = callback
+                        .call1(&JsValue::NULL, &JsValue::from_str(capability))
+                        .map(|x| x.is_truthy())
+                        .unwrap_or(false);
+                    self.permissions.insert(capability.to_string(), answer);
+                    answer
+                }
+                None => false,
+            },
+        };
+
+        if allowed {
+            true
+        } else {
+            self.log(format!(
+                "Error! the capability \"{capability}\" is not allowed\n"
+            ));
+            self.push_stack(Type::Error("permission-denied".to_string()));
+            false
+        }
+    }
+
+    /// Run source of the package, and bind its exports with each prefix
+    fn use_package(&mut self, name: &str, prefixes: &[String]) {
+        let Some(package) = self.packages.get(name).cloned() else {
+            self.log(format!("Error! the package \"{name}\" is not loaded\n"));
+            self.push_stack(Type::Error("package-not-found".to_string()));
+            return;
+        };
+        let message = format!("* Using package {} {}\n", package.name, package.version);
+        self.record(LogLevel::Info, LogCategory::Io, message);
+
+        let mut scratch = self.run_isolated(package.source);
+        self.export(name, &mut scratch, package.exports, prefixes);
+    }
+
+    /// Run source of the registered module, and bind all its definitions as "name.word".
+    /// References between them in the source are also qualified
+    fn import_module(&mut self, name: &str) {
+        let Some(source) = self.modules.get(name).cloned() else {
+            self.log(format!("Error! the module \"{name}\" is not registered\n"));
+            self.push_stack(Type::Error("module-not-found".to_string()));
+            return;
+        };
+        let message = format!("* Importing module {name}\n");
+        self.record(LogLevel::Info, LogCategory::Io, message);
+
+        let mut scratch = self.run_isolated(source);
+        let prefix = format!("{name}.");
+        let names: HashSet<String> = scratch
+            .words
+            .keys()
+            .chain(scratch.memory.keys())
+            .cloned()
+            .collect();
+        for code in scratch.words.values_mut() {
+            *code = qualify(self, code, &names, &prefix);
+        }
+        for (word, value) in scratch.memory.iter_mut() {
+            // Variable called as a word by its signature
+            if scratch.signatures.contains_key(word) {
+                *value = Type::String(qualify(self, &value.get_string(), &names, &prefix));
+            }
+        }
+        let mut exports: Vec<String> = names.into_iter().collect();
+        exports.sort();
+        self.export(name, &mut scratch, exports, &[prefix]);
+    }
+
+    /// Run the source on a copy of the state, apart from the variables
+    fn run_isolated(&mut self, source: String) -> Executor {
+        let mut scratch = self.clone();
+        scratch.stack.clear();
+        scratch.memory.clear();
+        scratch.words.clear();
+        scratch.signatures.clear();
+        scratch.docs.clear();
+        scratch.scopes.clear();
+        scratch.evaluate_program(source);
+        self.output = scratch.output.clone();
+        self.prints = scratch.prints.clone();
+        self.log = scratch.log.clone();
+        self.entries = scratch.entries.clone();
+        self.steps = scratch.steps;
+        self.halted = scratch.halted;
+        self.thrown = scratch.thrown.take();
+        self.status = scratch.status;
+        self.failed |= scratch.failed;
+        scratch
+    }
+
+    /// Bind the exports defined in the scratch executor with each prefix
+    fn export(
+        &mut self,
+        name: &str,
+        scratch: &mut Executor,
+        exports: Vec<String>,
+        prefixes: &[String],
+    ) {
+        // Exported words may replace the ones that pure code calls
+        self.forget_purity();
+        for export in exports {
+            if let Some(code) = scratch.words.remove(&export) {
+                for prefix in prefixes {
+                    let word = format!("{prefix}{export}");
+                    if self.words.get(&word).is_some_and(|old| *old != code) {
+                        let message = format!(
+                            "\"{word}\" of the package \"{name}\" shadows the existing one"
+                        );
+                        self.log(format!("Warning! {message}\n"));
+                        self.warn("shadowed-variable", Severity::Warning, message);
+                    }
+                    if let Some(doc) = scratch.docs.get(&export) {
+                        self.docs.insert(word.clone(), doc.clone());
+                    }
+                    if let Some(signature) = scratch.signatures.get(&export) {
+                        self.signatures.insert(word.clone(), signature.clone());
+                    }
+                    if let Some(access) = &mut self.access {
+                        access.writes.insert(word.clone());
+                    }
+                    self.words.insert(word, code.clone());
+                }
+                continue;
+            }
+            let Some(value) = scratch.memory.remove(&export) else {
+                self.log(format!(
+                    "Error! the package \"{name}\" doesn't define \"{export}\"\n"
+                ));
+                self.push_stack(Type::Error("export-not-found".to_string()));
+                continue;
+            };
+            for prefix in prefixes {
+                let word = format!("{prefix}{export}");
+                if self.memory.get(&word).is_some_and(|old| *old != value) {
+                    let message =
+                        format!("\"{word}\" of the package \"{name}\" shadows the existing one");
+                    self.log(format!("Warning! {message}\n"));
+                    self.warn("shadowed-variable", Severity::Warning, message);
+                }
+                if let Some(access) = &mut self.access {
+                    access.writes.insert(word.clone());
+                }
+                if let Some(doc) = scratch.docs.get(&export) {
+                    self.docs.insert(word.clone(), doc.clone());
+                }
+                if let Some(signature) = scratch.signatures.get(&export) {
+                    self.signatures.insert(word.clone(), signature.clone());
+                }
+                self.hook_var_write(&word, Some(&value));
+                self.memory.insert(word, value.clone());
+            }
+        }
+        self.show_variables()
+    }
+
+    /// Forget what's known about pure code, because a word that it may call is changed
+    fn forget_purity(&mut self) {
+        self.purity.clear();
+        self.pure_results.clear();
+    }
+
+    /// Call the word registered by `define`
+    fn call_defined(&mut self, name: String) {
+        let code = self.words.get(&name).cloned().unwrap_or_default();
+        if let Some(access) = &mut self.access {
+            access.reads.insert(name.clone());
+        }
+        match self.signatures.get(&name).cloned() {
+            Some(signature) => self.call_word(&name, code, signature),
+            None => self.evaluate_scoped(code),
+        }
+    }
+
+    /// Evaluate code of the word in its own scope of local variables
+    fn evaluate_scoped(&mut self, code: String) {
+        self.enter_frame(code.clone(), HashMap::new(), false);
+        self.evaluate_program(code);
+        self.leave_frame();
+    }
+
+    /// Start running the block or word, with the scope of its variables
+    fn enter_frame(&mut self, code: String, scope: HashMap<String, Type>, captured: bool) {
+        if captured {
+            self.captures.push(self.scopes.len());
         }
+        self.frames.push((code, scope.clone(), captured));
+        self.scopes.push(scope);
+    }
+
+    /// Finish running the innermost block or word
+    fn leave_frame(&mut self) {
+        self.scopes.pop();
+        self.frames.pop();
+        if self.captures.last() == Some(&self.scopes.len()) {
+            self.captures.pop();
+        }
+    }
+
+    /// Index of the innermost scope that declares the variable, except the captured ones
+    fn owner_scope(&self, name: &str) -> Option<usize> {
+        (0..self.scopes.len())
+            .rev()
+            .find(|i| !self.captures.contains(i) && self.scopes[*i].contains_key(name))
+    }
+
+    /// Get values of the variables that the tokens refer to, including inside nested code
+    fn capture(&mut self, tokens: &[String]) -> HashMap<String, Type> {
+        let mut captured = HashMap::new();
+        for token in tokens {
+            let nested = matches!(
+                (token.chars().next(), token.chars().last()),
+                (Some('('), Some(')')) | (Some('['), Some(']')) | (Some('{'), Some('}'))
+            );
+            if nested && token.len() >= 2 {
+                let inner = self.analyze_syntax(token[1..token.len() - 1].to_string());
+                captured.extend(self.capture(&inner));
+            } else if let Some(value) = self.lookup(token) {
+                captured.insert(token.clone(), value.clone());
+            }
+        }
+        captured
+    }
+
+    /// Get value of the variable, from the innermost scope that has it
+    fn lookup(&self, name: &str) -> Option<&Type> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .or_else(|| self.memory.get(name))
+    }
+
+    /// Set the variable in the innermost scope, or globally at the top level
+    fn bind(&mut self, name: &str, value: Type) {
+        match self.scopes.last_mut() {
+            Some(scope) => scope.insert(name.to_string(), value),
+            None => self.memory.insert(name.to_string(), value),
+        };
+    }
+
+    /// Evaluate code of the word, or reuse its result if it is memoized
+    fn call_word(&mut self, name: &str, code: String, signature: Signature) {
+        let count = signature.inputs.len();
+        let key = match self.memo.contains_key(name) && self.stack.len() >= count {
+            true => {
+                let base = self.stack.len() - count;
+                let args: Vec<String> = self.stack[base..].iter().map(|x| x.canonical()).collect();
+                Some((base, args.join(" ")))
+            }
+            false => None,
+        };
+        let Some((base, key)) = key else {
+            self.run_word(name, code, signature);
+            return;
+        };
+
+        // Reuse the result of the same arguments
+        if let Some(result) = self.memo.get(name).and_then(|cache| cache.get(&key)) {
+            let result = result.clone();
+            let message = format!("* Memoized result of {name}\n");
+            self.record(LogLevel::Debug, LogCategory::Stack, message);
+            self.stack.truncate(base);
+            self.stack.extend(result);
+            return;
+        }
+
+        let produced = signature.outputs.len();
+        self.run_word(name, code, signature);
+        if self.stopped() || self.stack.len() != base + produced {
+            return;
+        }
+        let result = self.stack[base..].to_vec();
+        if let Some(cache) = self.memo.get_mut(name) {
+            if cache.len() >= MEMO_LIMIT {
+                cache.clear();
+            }
+            cache.insert(key, result);
+        }
+    }
+
+    /// Run the word, checking its signature in checked mode
+    fn run_word(&mut self, name: &str, code: String, signature: Signature) {
+        if !self.config.checked {
+            self.evaluate_scoped(code);
+            return;
+        }
+
+        let count = signature.inputs.len();
+        if self.stack.len() < count {
+            self.log(format!(
+                "Error! {name} needs {count} values, but the stack has {}\n",
+                self.stack.len()
+            ));
+            self.push_stack(Type::Error("stack-underflow".to_string()));
+            return;
+        }
+        let base = self.stack.len() - count;
+        if !self.check_signature(name, &signature.inputs, base, "argument") {
+            return;
+        }
+
+        self.evaluate_scoped(code);
+        if self.stopped() {
+            return;
+        }
+        let count = signature.outputs.len();
+        if self.stack.len() != base + count {
+            let count = self.stack.len() as isize - base as isize;
+            self.log(format!(
+                "Error! {name} should produce {} values, but it produced {count}\n",
+                signature.outputs.len()
+            ));
+            self.push_stack(Type::Error("signature-mismatch".to_string()));
+            return;
+        }
+        self.check_signature(name, &signature.outputs, base, "result");
+    }
+
+    /// Check types of the values from the base of the stack
+    fn check_signature(&mut self, name: &str, types: &[String], base: usize, kind: &str) -> bool {
+        for (index, expected) in types.iter().enumerate() {
+            let value = &self.stack[base + index];
+            if !Signature::accepts(expected, value) {
+                let actual = value.type_name();
+                self.log(format!(
+                    "Error! {kind} {} of {name} should be {expected}, but it is {actual}\n",
+                    index + 1
+                ));
+                self.push_stack(Type::Error("signature-mismatch".to_string()));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Get the command that the alias refers to
+    fn resolve_alias(&mut self, name: String) -> String {
+        let Some(alias) = ALIASES.iter().find(|alias| alias.name == name) else {
+            return name;
+        };
+
+        if alias.deprecated && self.warned.insert(name.clone()) {
+            let message = format!(
+                "\"{name}\" is deprecated, use \"{}\" instead",
+                alias.command
+            );
+            self.log(format!("Warning! {message}\n"));
+            self.warn("deprecated", Severity::Warning, message);
+        }
+        alias.command.to_string()
+    }
+
+    /// Ask the user until the answer is converted, and push it or error
+    fn ask_typed(&mut self, prompt: &str, hint: &str, convert: impl Fn(&str) -> Option<Type>) {
+        let mut message = prompt.to_string();
+        for _ in 0..INPUT_ATTEMPTS {
+            let Some(answer) = self.next_input().or_else(|| host_input(&message)) else {
+                break;
+            };
+            if let Some(value) = convert(&answer) {
+                self.push_stack(value);
+                return;
+            }
+            let warning = format!("the input \"{answer}\" is not {hint}");
+            self.warn("invalid-input", Severity::Warning, warning);
+            message = format!("Please enter {hint}. {prompt}");
+        }
+        self.log(format!("Error! the input is not {hint}\n"));
+        self.push_stack(Type::Error("invalid-input".to_string()));
+    }
+
+    /// Get the next answer of input, from the options and then from the input provider
+    fn next_input(&mut self) -> Option<String> {
+        self.config
+            .inputs
+            .pop_front()
+            .or_else(|| self.answers.pop_front())
+    }
+
+    /// Evaluate condition code, that must push exactly one value
+    fn evaluate_condition(&mut self, code: &str, command: &str) -> Option<bool> {
+        let old_len = self.stack.len();
+        self.evaluate_program(code.to_string());
+        if self.stopped() {
+            return None;
+        }
+        self.condition_result(old_len, command)
+    }
+
+    /// Take the bool that the condition pushed on the stack of the length
+    fn condition_result(&mut self, old_len: usize, command: &str) -> Option<bool> {
+        let effect = self.stack.len() as isize - old_len as isize;
+        if effect != 1 {
+            self.log(format!(
+                "Error! the condition of {command} must push exactly one value, but the stack changed by {effect}\n"
+            ));
+            self.push_stack(Type::Error(format!("{command}-condition")));
+            return None;
+        }
+
+        match self.pop_stack() {
+            Type::Bool(b) => Some(b),
+            mut other => {
+                let message = format!(
+                    "the condition of {command} is not bool, {} is converted",
+                    other.display()
+                );
+                self.log(format!("Warning! {message}\n"));
+                self.warn("coercion", Severity::Warning, message);
+                Some(other.get_bool())
+            }
+        }
+    }
+
+    /// Pop the values to iterate, or report that the command can't iterate over it
+    fn pop_items(&mut self, command: &str) -> Option<Items> {
+        let value = self.pop_stack();
+        let display = value.display();
+        let name = value.type_name();
+        let items = value.items();
+        if items.is_none() {
+            self.log(format!(
+                "Error! {command} can't iterate over the {name} {display}\n"
+            ));
+            self.push_stack(Type::Error("not-iterable".to_string()));
+        }
+        items
+    }
+
+    /// Generate value of lazy sequence by index
+    fn lazy_nth(&mut self, lazy: &Lazy, index: usize) -> Option<Type> {
+        let mut value = Type::Number(lazy.nth(index)?);
+        for (vars, code) in &lazy.maps {
+            self.scopes.push(HashMap::from([(vars.clone(), value)]));
+            self.evaluate_program(code.clone());
+            self.scopes.pop();
+            value = self.pop_stack();
+        }
+        Some(value)
+    }
+
+    /// Sort list by merge sort, that keeps order of equal values
+    fn sort_with(&mut self, mut list: Vec<Type>, code: &str) -> Vec<Type> {
+        if list.len() <= 1 {
+            return list;
+        }
+
+        let right = list.split_off(list.len() / 2);
+        let mut left = self.sort_with(list, code).into_iter().peekable();
+        let mut right = self.sort_with(right, code).into_iter().peekable();
+
+        let mut result = Vec::new();
+        while let (Some(a), Some(b)) = (left.peek().cloned(), right.peek().cloned()) {
+            // Take right value only if it is strictly less, to keep stability
+            if self.is_less(b, a, code) {
+                result.extend(right.next());
+            } else {
+                result.extend(left.next());
+            }
+        }
+        result.extend(left);
+        result.extend(right);
+        result
+    }
+
+    /// Is the first value less than the second by the comparator code
+    fn is_less(&mut self, a: Type, b: Type, code: &str) -> bool {
+        self.push_stack(a);
+        self.push_stack(b);
+        self.evaluate_program(code.to_string());
+        match self.pop_stack() {
+            Type::Bool(b) => b,
+            mut other => other.get_number() < 0.0,
+        }
+    }
+
+    /// Pop number from the stack, warning if it's converted from string
+    fn pop_number(&mut self) -> f64 {
+        if let Some(Type::String(s)) = self.stack.last() {
+            let message = format!("the string \"{s}\" is converted to number");
+            self.warn("coercion", Severity::Warning, message);
+        }
+        self.pop_stack().get_number()
+    }
+
+    /// Push value on the stack
+    fn push_stack(&mut self, value: Type) {
+        if let Some(hook) = &self.hooks.on_push {
+            if hook
+                .call1(&JsValue::NULL, &JsValue::from_str(&value.display()))
+                .is_err()
+            {
+                self.halt(Status::Aborted);
+            }
+        }
+        self.stack.push(value);
     }
 
     /// Pop stack's top value
     fn pop_stack(&mut self) -> Type {
         if let Some(value) = self.stack.pop() {
+            if let Some(hook) = &self.hooks.on_pop {
+                if hook
+                    .call1(&JsValue::NULL, &JsValue::from_str(&value.display()))
+                    .is_err()
+                {
+                    self.halt(Status::Aborted);
+                }
+            }
+            self.lowest = self.lowest.min(self.stack.len());
             value
         } else {
             self.log(
@@ -937,3 +7357,155 @@ impl Executor {
         }
     }
 }
+
+/// Find paths matching the pattern, that can include `*` and `?`
+fn glob(pattern: &str) -> Vec<String> {
+    let (mut paths, parts) = if let Some(rest) = pattern.strip_prefix('/') {
+        (vec!["/".to_string()], rest)
+    } else {
+        (vec![String::new()], pattern)
+    };
+
+    for part in parts.split('/').filter(|x| !x.is_empty()) {
+        let mut next = Vec::new();
+        for dir in &paths {
+            let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if is_wildcard_match(part, &name) {
+                    next.push(Path::new(dir).join(name).to_string_lossy().to_string());
+                }
+            }
+        }
+        paths = next;
+    }
+    paths
+}
+
+/// Get paths of the virtual files and their directories matching the pattern
+fn glob_virtual(pattern: &str, files: &BTreeSet<String>) -> Vec<String> {
+    let parts = |path: &str| -> Vec<String> {
+        path.split('/')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .collect()
+    };
+    let pattern_parts = parts(pattern);
+    let absolute = pattern.starts_with('/');
+
+    let mut paths = BTreeSet::new();
+    for file in files.iter().filter(|x| x.starts_with('/') == absolute) {
+        let names = parts(file);
+        if names.len() < pattern_parts.len() {
+            continue;
+        }
+        // The file itself, or its directory at the depth of the pattern
+        let names = &names[..pattern_parts.len()];
+        if names
+            .iter()
+            .zip(&pattern_parts)
+            .all(|(name, part)| is_wildcard_match(part, name))
+        {
+            let path = names.join("/");
+            paths.insert(if absolute { format!("/{path}") } else { path });
+        }
+    }
+    paths.into_iter().collect()
+}
+
+/// Is the text matching the pattern, `*` is any string and `?` is any character
+fn is_wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // matched[j] is whether pattern so far matches text[..j]
+    let mut matched = vec![false; text.len() + 1];
+    matched[0] = true;
+    for p in pattern {
+        let mut next = vec![false; text.len() + 1];
+        for j in 0..=text.len() {
+            next[j] = match p {
+                '*' => matched[j] || (j > 0 && next[j - 1]),
+                '?' => j > 0 && matched[j - 1],
+                c => j > 0 && matched[j - 1] && text[j - 1] == c,
+            };
+        }
+        matched = next;
+    }
+    matched[text.len()]
+}
+
+/// Split the text at any of delimiter characters, except inside quotes of `"` or `'`.
+/// Quotes are removed, and kept delimiters other than spaces become tokens
+fn split_quoted(text: &str, delimiters: &str, keep: bool) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut buffer = String::new();
+    let mut quote: Option<char> = None; // Quote character that is open
+    let mut quoted = false; // Whether the buffer is a quoted token, kept even if empty
+
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => buffer.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                quoted = true;
+            }
+            None if delimiters.contains(c) => {
+                if !buffer.is_empty() || quoted {
+                    tokens.push(std::mem::take(&mut buffer));
+                    quoted = false;
+                }
+                if keep && !c.is_whitespace() {
+                    tokens.push(c.to_string());
+                }
+            }
+            None => buffer.push(c),
+        }
+    }
+
+    if !buffer.is_empty() || quoted {
+        tokens.push(buffer);
+    }
+    tokens
+}
+
+/// Get values of set in order of its keys
+fn set_values(set: &HashMap<String, Type>) -> Vec<Type> {
+    let mut keys: Vec<&String> = set.keys().collect();
+    keys.sort();
+    keys.iter().map(|key| set[*key].clone()).collect()
+}
+
+/// Move the last value of heap up to the right position
+fn sift_up(heap: &mut [Type]) {
+    let mut index = heap.len().saturating_sub(1);
+    while index > 0 {
+        let parent = (index - 1) / 2;
+        if heap[index].compare(&heap[parent]) != Ordering::Less {
+            break;
+        }
+        heap.swap(index, parent);
+        index = parent;
+    }
+}
+
+/// Move the first value of heap down to the right position
+fn sift_down(heap: &mut [Type]) {
+    let mut index = 0;
+    loop {
+        let mut min = index;
+        for child in [index * 2 + 1, index * 2 + 2] {
+            if child < heap.len() && heap[child].compare(&heap[min]) == Ordering::Less {
+                min = child;
+            }
+        }
+        if min == index {
+            break;
+        }
+        heap.swap(index, min);
+        index = min;
+    }
+}
@@ -0,0 +1,61 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+use web_stack::Session;
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // Run the program once per line of standard input, on the stack with only the line
+    let each_line = args.iter().any(|x| x == "--each-line");
+    // Allow executing other programs
+    let allow_exec = args.iter().any(|x| x == "--allow-exec");
+    args.retain(|x| x != "--each-line" && x != "--allow-exec");
+
+    let Some(path) = args.first() else {
+        eprintln!("Usage: stack [--each-line] [--allow-exec] <file> [args...]");
+        exit(1);
+    };
+    let src = match fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(err) => {
+            eprintln!("Error! failed to read {path}: {err}");
+            exit(1);
+        }
+    };
+
+    let mut session = Session::new();
+    let capabilities = if allow_exec {
+        vec!["fs", "exec"]
+    } else {
+        vec!["fs"]
+    };
+    let options = serde_json::json!({
+        "trace": "errors",
+        "capabilities": capabilities,
+        "args": &args[1..],
+    });
+    session.configure(&options.to_string());
+
+    if each_line {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            session.clear_stack();
+            session.push_string(&line);
+            show(&session.run(&src));
+        }
+    } else {
+        show(&session.run(&src));
+    }
+}
+
+/// Write output and log of the run, and exit if the program did
+fn show(result: &web_stack::Result) {
+    print!("{}", result.output());
+    eprint!("{}", result.log());
+    io::stdout().flush().ok();
+    if let Some(code) = result.exit_code() {
+        exit(code);
+    }
+}
@@ -0,0 +1,1261 @@
+use web_stack::{
+    compile, diff_runs, examples_catalog, format_stack, minify_stack, run_builtin_example,
+    run_program, run_stack, run_stack_opts, specialize, tokenize, Notebook, Session, EXAMPLES,
+};
+
+/// Output of the program
+fn output(src: &str) -> String {
+    run_stack(src).output()
+}
+
+#[test]
+fn block_assigns_captured_global() {
+    assert_eq!(
+        output("0 (count) var { count 1 add (count) var } call count print"),
+        "1\n"
+    );
+    assert_eq!(output("0 (n) var { n 1 add (n) var n print } call"), "1\n");
+}
+
+#[test]
+fn block_reads_captured_local() {
+    let src = "((x) local { x 1 add } ) (make) define 41 make call print";
+    assert_eq!(output(src), "42\n");
+}
+
+#[test]
+fn range_too_large_is_error() {
+    assert_eq!(output("0 1 1e-300 range type print"), "error\n");
+    assert_eq!(output("0 1e18 1 range type print"), "error\n");
+    assert_eq!(output("0 3 1 range len print"), "3\n");
+}
+
+#[test]
+fn thrown_word_skips_signature_check() {
+    let src = "(n -- n) (f) signature (pop (x) throw 1) (f) define { 2 f } try print";
+    let result = run_stack_opts(src, r#"{"checked": true}"#);
+    assert_eq!(result.output(), "error:x\n");
+    assert!(!result.log().contains("should produce"), "{}", result.log());
+    assert_eq!(result.status(), "completed");
+}
+
+#[test]
+fn format_keeps_behavior() {
+    let mut sources = vec![
+        "(x\n  y) print",
+        "[1\n 2   3] len print",
+        "{ 1\n2 add } call print",
+    ];
+    sources.extend(web_stack::EXAMPLES.iter().map(|(_, _, source)| *source));
+    for src in sources {
+        let formatted = format_stack(src);
+        assert_eq!(output(&formatted), output(src), "{formatted}");
+        assert_eq!(format_stack(&formatted), formatted);
+    }
+    assert_eq!(format_stack("(x\n  y) print"), "(x\n  y) print\n");
+    assert_eq!(format_stack("[1\n2]  len"), "[\n    1\n    2\n] len\n");
+}
+
+#[test]
+fn local_at_top_level_keeps_constant() {
+    assert_eq!(
+        output("5 (x) const (6 (x) local) try print x print"),
+        "error:const-reassign\n5\n"
+    );
+    assert_eq!(
+        output("5 (x) const (6 (x) local x print) (f) define f x print"),
+        "6\n5\n"
+    );
+}
+
+#[test]
+fn input_choice_matches_name_first() {
+    let src = "(pick) [10 20 30] input-choice print";
+    let result = run_stack_opts(src, r#"{"inputs": ["20"]}"#);
+    assert_eq!(result.output(), "20\n");
+    let result = run_stack_opts(src, r#"{"inputs": ["3"]}"#);
+    assert_eq!(result.output(), "30\n");
+    let result = run_stack_opts(src, r#"{"inputs": ["x", "10"], "audit": true}"#);
+    assert_eq!(result.output(), "10\n");
+    assert!(result.warnings().contains("invalid-input"));
+    assert!(!result.log().contains("Warning!"));
+    assert!(result.audit().contains("input-choice"));
+}
+
+#[test]
+fn output_after_limit_is_dropped() {
+    let src = "(hello) print (long line here) print (ok) print";
+    let result = run_stack_opts(src, r#"{"output_limit": 14}"#);
+    assert_eq!(result.output(), "hello\n");
+    assert!(!result.log().contains("Warning!"));
+    assert!(result.warnings().contains("output-truncated"));
+}
+
+#[test]
+fn hot_reload_applies_only_definitions() {
+    let mut session = Session::new();
+    session.run("0 (count) var 1 (limit) const (1 print) (f) define");
+    let reload = "(2 print) (f) define 5 (count) var (boom) print 9 (limit) const 3 (step) const";
+    let result = session.hot_reload(reload);
+    assert_eq!(result.output(), "");
+    assert_eq!(
+        session.run("f count print limit print step print").output(),
+        "2\n0\n1\n3\n"
+    );
+}
+
+#[test]
+fn bytecode_matches_nested_evaluation() {
+    // Recording the trace runs every command by nested evaluation
+    let cases = [
+        "0 (i) var (i print i 1 add (i) var) (i 3 less) while",
+        "0 (i) var (i 1 add (i) var i 2 equal (continue) () i 2 equal if i print) (i 4 less) while",
+        "0 (i) var (i 1 add (i) var i 3 equal (break) () i 3 equal if i print) (false) until",
+        "[1 2 3] (x) (x 2 equal (continue) () x 2 equal if x loop-index add print) for",
+        "[1 2 3] (x) ([4 5] (y) (x y mul) map print) for",
+        "[1 2 3] (x) (x 2 equal (break) () x 2 equal if x) map print",
+        "(i print i 2 equal (break) () i 2 equal if) 5 times",
+        "{ (1 print (boom) throw) 3 times } try print",
+        "(1) (false) while print",
+        "(pop) (1 2) while",
+        "1 4 1 range (x) (x x mul) map print",
+        "0 (s) var (b) (a) var (s 1 add (s) var) (s 3 less) while s print",
+        "{ 1 add } (inc) var 1 inc call print 5 (x) var [1 2] (y) (x y add (x) var x) map print",
+        "(1 2 add print) eval (3 print) (4 print) false if",
+    ];
+    for src in cases {
+        let plain = run_stack(src);
+        let nested = run_stack_opts(src, r#"{"record_trace": true}"#);
+        assert_eq!(plain.output(), nested.output(), "{src}");
+        assert_eq!(plain.status(), nested.status(), "{src}");
+    }
+}
+
+#[test]
+fn deep_recursion_runs_without_nesting() {
+    let src = "((n) local n 0 greater (n 1 sub sum n add) (0) n 0 greater if) (sum) define 2000 sum print";
+    let result = run_stack_opts(src, r#"{"trace": "off"}"#);
+    assert_eq!(result.output(), "2001000\n");
+}
+
+#[test]
+fn seed_repeats_random() {
+    let src = "random print random print random dup 0 greater-equal swap 1 less and print";
+    let first = run_stack_opts(src, r#"{"seed": 7}"#).output();
+    assert_eq!(first, run_stack_opts(src, r#"{"seed": 7}"#).output());
+    assert_ne!(first, run_stack_opts(src, r#"{"seed": 8}"#).output());
+    assert!(first.ends_with("true\n"), "{first}");
+}
+
+#[test]
+fn signature_invalidates_pure_results() {
+    let src = "(1 add) (f) var [1 2] (x) (x f) map print (n -- n) (f) signature [1 2] (x) (x f) map print";
+    let result = run_stack_opts(src, r#"{"optimize": true}"#);
+    assert_eq!(result.output(), "[(1 add) (1 add)]\n[2 3]\n");
+}
+
+#[test]
+fn recover_keeps_errors_of_try() {
+    let src = "{ heap-new heap-peek } try type print heap-new heap-peek type print";
+    let result = run_stack_opts(src, r#"{"recover": true}"#);
+    assert_eq!(result.output(), "error\nnumber\n");
+}
+
+#[test]
+fn clear_stack_keeps_variables() {
+    let mut session = Session::new();
+    session.run("1 2 5 (x) var");
+    session.clear_stack();
+    session.push_string("a");
+    assert_eq!(session.run("depth print x print").output(), "1\n5\n");
+}
+
+#[test]
+fn glob_lists_virtual_files() {
+    let mut session = Session::new();
+    session.configure(r#"{"capabilities": ["fs"]}"#);
+    for path in ["src/main.rs", "src/lib.rs", "src/bin/tool.rs", "README.md"] {
+        session.add_file(path);
+    }
+    let result = session.run("(src/*.rs) glob print (*) glob print (src/*/*) glob print");
+    assert_eq!(
+        result.output(),
+        "[(src/lib.rs) (src/main.rs)]\n[(README.md) (src)]\n[(src/bin/tool.rs)]\n"
+    );
+}
+
+#[test]
+fn minus_is_number_sign_or_sub() {
+    // Sign of number literal
+    assert_eq!(
+        output("-5 type print -.5 print -1e3 print"),
+        "number\n-0.5\n-1000\n"
+    );
+    // `-` alone is sub, even after a negative number
+    assert_eq!(
+        output("5 3 - print 5 -3 - print -2 -3 - print"),
+        "2\n8\n1\n"
+    );
+    // Unary minus is neg
+    assert_eq!(output("3 neg print -5 neg print"), "-3\n5\n");
+    // Other words starting with `-` are neither numbers nor commands
+    assert_eq!(
+        output("-x type print --5 type print (-) type print"),
+        "string\nstring\nstring\n"
+    );
+}
+
+#[test]
+fn syntax_errors_are_reported_before_running() {
+    let result = run_stack("1 print (2 print");
+    assert_eq!(result.output(), "");
+    assert!(
+        result
+            .log()
+            .contains("Error! unclosed \"(\" at line 1, column 9"),
+        "{}",
+        result.log()
+    );
+    assert!(
+        result.syntax_errors().contains("unclosed"),
+        "{}",
+        result.syntax_errors()
+    );
+    assert_eq!(result.status(), "error");
+}
+
+#[test]
+fn full_width_setting_stays_with_its_run() {
+    let src = "（a） print";
+    let formatted = format_stack(src);
+    let kept = run_stack_opts(src, r#"{"keep_full_width": true}"#);
+    assert_eq!(kept.output(), "（a）\n");
+    // Later runs and formatting read full-width delimiters as before
+    assert_eq!(output(src), "a\n");
+    assert_eq!(format_stack(src), formatted);
+}
+
+#[test]
+fn shift_of_64_or_more_moves_every_bit_out() {
+    assert_eq!(output("1 3 shl print 16 2 shr print"), "8\n4\n");
+    assert_eq!(
+        output("1 64 shl print 1 100 shl print 1 -1 shl print"),
+        "0\n0\n0\n"
+    );
+    assert_eq!(output("8 64 shr print -8 64 shr print"), "0\n-1\n");
+}
+
+#[test]
+fn lazy_range_streams_values() {
+    let src = "0 1e15 1 lazy-range (x) (x x mul) lazy-map 4 lazy-take collect print";
+    assert_eq!(output(src), "[0 1 4 9]\n");
+    let src = "0 1e15 1 lazy-range (x) ((break) () x 3 equal if x print) for";
+    assert_eq!(output(src), "0\n1\n2\n");
+    assert_eq!(output("0 10 3 lazy-range collect print"), "[0 3 6 9]\n");
+}
+
+#[test]
+fn lazy_range_too_large_is_error() {
+    let result = run_stack_opts(
+        "0 1e15 1 lazy-range collect type print",
+        r#"{"fuel": 100000}"#,
+    );
+    assert_eq!(result.output(), "error\n");
+    assert_eq!(result.status(), "error");
+    assert_eq!(output("0 1e300 1e-300 lazy-range type print"), "error\n");
+    assert_eq!(output("0 1 0 div 1 lazy-range type print"), "error\n");
+    assert_eq!(output("0 10 0 lazy-range type print"), "error\n");
+}
+
+#[test]
+fn loops_iterate_collections_only() {
+    let src = "[[(a) 1] [(b) 2]] list-to-dict (p) (p print) for (ab) (c) (c print) for";
+    assert_eq!(output(src), "[(a) 1]\n[(b) 2]\na\nb\n");
+    for src in [
+        "5 (x) (x print) for",
+        "true (x) (x) map",
+        "{ 1 } (x) (true) filter",
+        "3 (a) (b) (a b add) reduce",
+    ] {
+        let result = run_stack(&format!("{src} print"));
+        assert_eq!(result.output(), "error:not-iterable\n", "{src}");
+        assert!(result.log().contains("can't iterate over"), "{src}");
+    }
+}
+
+#[test]
+fn options_are_checked() {
+    let result = run_stack_opts("1 print", r#"{"locale": "de", "fuel": 100}"#);
+    assert_eq!(result.output(), "1\n");
+    assert!(
+        result.log().contains("the option locale doesn't exist"),
+        "{}",
+        result.log()
+    );
+    assert!(
+        result.warnings().contains("unknown-option"),
+        "{}",
+        result.warnings()
+    );
+    let result = run_stack_opts("1 print", r#"{"fuel": -1}"#);
+    assert_eq!(result.output(), "");
+    assert_eq!(result.status(), "error");
+    assert!(
+        result.log().starts_with("Error! invalid options"),
+        "{}",
+        result.log()
+    );
+}
+
+#[test]
+fn redefined_word_makes_callers_stale() {
+    let mut notebook = Notebook::new();
+    let define = notebook.add_cell("(2 mul) (f) define");
+    let call = notebook.add_cell("3 f print");
+    let other = notebook.add_cell("4 print");
+    notebook.run_all();
+    assert!(notebook.stale_cells().is_empty());
+
+    notebook.update_cell(define, "(3 mul) (f) define");
+    notebook.run_cell(define);
+    assert_eq!(notebook.stale_cells(), vec![call]);
+    assert!(!notebook.stale_cells().contains(&other));
+    let results = notebook.run_stale();
+    assert_eq!(results[0].output(), "9\n");
+}
+
+#[test]
+fn doc_comment_documents_next_definition() {
+    let src = "#: doubles the number# (2 mul) (dbl) define (dbl) help print";
+    assert_eq!(output(src), "doubles the number\n");
+    let src = "#: doubles the number# 1 print 3 print (2 mul) (dbl) define (dbl) help type print";
+    assert_eq!(output(src), "1\n3\nerror\n");
+
+    let mut session = Session::new();
+    session.run("#: squares it# (dup mul) (sq) define #: unused#");
+    session.run("(1 add) (inc) define");
+    assert_eq!(
+        session.command_catalog(),
+        r#"[{"doc":"squares it","name":"sq"}]"#
+    );
+}
+
+#[test]
+fn define_takes_declared_stack_effect() {
+    let src = "(( n n -- n ) add) (plus) define 1 2 plus print";
+    let result = run_stack(src);
+    assert_eq!(result.output(), "3\n");
+    assert_eq!(result.warnings(), "[]");
+
+    // The call is found to take more values than the stack has, before running
+    let src = "(started) print (( n n -- n ) add) (plus) define 1 plus";
+    let result = run_stack_opts(src, r#"{"checked": true}"#);
+    assert!(
+        result.warnings().contains("stack-underflow"),
+        "{}",
+        result.warnings()
+    );
+    let log = result.log();
+    let warned = log.find("Warning! plus takes 2 values").expect(&log);
+    assert!(warned < log.find("started").unwrap_or(log.len()), "{log}");
+    assert!(log.contains("Error! plus needs 2 values"), "{log}");
+}
+
+#[test]
+fn match_picks_first_matching_pattern() {
+    let arms = "[[1 (one print)] [(:number) (number print)] [[($a) [($b) _]] (a b add print)] [_ (other print)]]";
+    for (value, expected) in [
+        ("1", "one\n"),
+        ("2", "number\n"),
+        ("[3 [4 5]]", "7\n"),
+        ("[3 4]", "other\n"),
+        ("(text)", "other\n"),
+    ] {
+        assert_eq!(
+            output(&format!("{value} {arms} match")),
+            expected,
+            "{value}"
+        );
+    }
+}
+
+#[test]
+fn match_without_matching_arm_is_error() {
+    let result = run_stack("7 [[1 (one print)]] match print");
+    assert_eq!(result.output(), "error:no-match\n");
+    assert!(
+        result.log().contains("Error! no pattern matches 7"),
+        "{}",
+        result.log()
+    );
+    let result = run_stack("7 [[1]] match print");
+    assert_eq!(result.output(), "error:match-arm\n");
+    assert!(
+        result.log().contains("pairs of pattern and code"),
+        "{}",
+        result.log()
+    );
+}
+
+#[test]
+fn sleep_waits_for_milliseconds() {
+    let result = run_stack("now-time 20 sleep now-time swap sub 20 less not print");
+    assert_eq!(result.output(), "true\n");
+    let result = run_stack("-1 sleep print");
+    assert_eq!(result.output(), "error:invalid-duration\n");
+}
+
+#[test]
+fn slots_restore_saved_state() {
+    let mut session = Session::new();
+    session.run("5 (x) var");
+    assert!(session.save_slot("a"));
+    session.run("9 (x) var");
+    assert!(session.load_slot("a"));
+    assert_eq!(session.run("x print").output(), "5\n");
+    assert!(!session.load_slot("b"));
+    assert_eq!(session.slots(), vec!["a".to_string()]);
+    assert!(session.delete_slot("a"));
+    assert!(session.slots().is_empty());
+}
+
+#[test]
+fn contains_compares_by_type() {
+    assert_eq!(
+        output("[1 2 3] 2 contains print (abc) (b) contains print"),
+        "true\ntrue\n"
+    );
+    assert_eq!(output("[1 (2)] 2 contains print"), "false\n");
+    assert_eq!(output("[1 2 1] 1 find-all-indices print"), "[0 2]\n");
+}
+
+#[test]
+fn sort_with_keeps_order_of_equal_values() {
+    assert_eq!(output("[3 1 2] (sub) sort-with print"), "[1 2 3]\n");
+    assert_eq!(output("[3 1 2] (less) sort-with print"), "[1 2 3]\n");
+    let src = "[(bb) (a) (cc) (d)] (len swap len swap less) sort-with print";
+    assert_eq!(output(src), "[(a) (d) (bb) (cc)]\n");
+}
+
+#[test]
+fn group_by_and_frequencies_make_dicts() {
+    let src = "[1 2 3 4] (x) (x 2 mod) group-by copy (1) property print (0) property print";
+    assert_eq!(output(src), "[1 3]\n[2 4]\n");
+    let src = "[(a) (b) (a)] frequencies copy type print (a) property print";
+    assert_eq!(output(src), "dict\n2\n");
+}
+
+#[test]
+fn heap_pops_smallest_first() {
+    let src = "heap-new 5 heap-push 1 heap-push 3 heap-push [2 (b)] heap-push \
+               heap-peek print heap-pop print heap-pop print heap-pop print heap-pop print \
+               heap-pop type print";
+    assert_eq!(output(src), "1\n1\n3\n5\n[2 (b)]\nerror\n");
+}
+
+#[test]
+fn queue_and_stack_keep_their_order() {
+    let src = "queue-new 1 enqueue 2 enqueue dequeue print dequeue print dequeue type print";
+    assert_eq!(output(src), "1\n2\nerror\n");
+    let src = "stack-new 1 push-to 2 push-to pop-from print pop-from print pop-from type print";
+    assert_eq!(output(src), "2\n1\nerror\n");
+}
+
+#[test]
+fn set_keeps_unique_values() {
+    let src =
+        "set-new 1 set-add (1) set-add 1 set-add copy print copy 1 set-has print 2 set-has print";
+    assert_eq!(output(src), "Set{1 (1)}\ntrue\nfalse\n");
+    let src = "[3 1 3 (a)] list-to-set copy len print copy 3 set-remove set-to-list print set-to-list print";
+    assert_eq!(output(src), "3\n[1 (a)]\n[1 3 (a)]\n");
+}
+
+#[test]
+fn struct_fields_are_copied_on_set() {
+    let src = "[(x) (y)] (point) struct 1 2 (point) new (p) var \
+               p (y) field-get print p (x) 5 field-set (x) field-get print p (x) field-get print \
+               p (z) field-get type print p type print";
+    assert_eq!(output(src), "2\n5\n1\nerror\npoint\n");
+}
+
+#[test]
+fn string_builder_accumulates_text() {
+    let src = "sb-new (a) sb-push 1 sb-push (bc) sb-push sb-build copy type print print";
+    assert_eq!(output(src), "string\na1bc\n");
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn result_encodes_to_msgpack() {
+    let bytes = run_stack_opts("1 2 add print", r#"{"trace": "off"}"#).to_msgpack();
+    // Map of the fields, where the output is a string of its own
+    assert!(
+        matches!(bytes.first(), Some(0x80..=0x8f | 0xde | 0xdf)),
+        "{bytes:?}"
+    );
+    let has = |part: &[u8]| bytes.windows(part.len()).any(|x| x == part);
+    assert!(has(b"\xa6output\xa23\n"), "{bytes:?}");
+    assert!(has(b"\xa6status"), "{bytes:?}");
+}
+
+#[test]
+fn while_condition_must_push_one_value() {
+    assert_eq!(
+        output("0 (i) var (i print i 1 add (i) var) (i 3 less) while"),
+        "0\n1\n2\n"
+    );
+    assert_eq!(
+        output("1 2 (pop) () while print"),
+        "error:while-condition\n"
+    );
+}
+
+#[test]
+fn deprecated_alias_runs_its_command() {
+    let result = run_stack("1 copy copy depth print size-stack print");
+    assert_eq!(result.output(), "3\n3\n");
+    assert_eq!(result.log().matches("deprecated").count(), 2);
+}
+
+#[test]
+fn sessions_share_parsed_code() {
+    let src = "0 (n) var [1 2 3] (x) (n x add (n) var) for n print (";
+    for _ in 0..2 {
+        let result = Session::new().run(src);
+        assert_eq!(result.output(), "");
+        assert!(result.log().contains("Error!"), "{}", result.log());
+        assert_eq!(Session::new().run(&src[..src.len() - 1]).output(), "6\n");
+    }
+}
+
+#[test]
+fn notebook_cells_share_state() {
+    let mut notebook = Notebook::new();
+    notebook.add_cell("5 (x) var (a) print");
+    notebook.add_cell("x 1 add print");
+    let results = notebook.run_all();
+    assert_eq!(results[0].output(), "a\n");
+    assert_eq!(results[1].output(), "6\n");
+    assert_eq!(notebook.cell_count(), 2);
+    assert!(notebook.run_cell(7).log().contains("not found"));
+}
+
+#[test]
+fn exec_needs_its_capability() {
+    let src = "(no-such-program) [(x)] exec print";
+    let result = run_stack(src);
+    assert_eq!(result.output(), "error:permission-denied\n");
+    assert!(
+        result
+            .log()
+            .contains("the capability \"exec\" is not allowed"),
+        "{}",
+        result.log()
+    );
+    let result = run_stack_opts(src, r#"{"capabilities": ["exec"]}"#);
+    assert_eq!(result.output(), "error:exec-failed\n");
+}
+
+#[test]
+fn websocket_needs_browser() {
+    let result = run_stack("(ws://localhost) ws-connect print");
+    assert_eq!(result.output(), "error:unsupported\n");
+    assert!(
+        result.log().contains("only available in the browser"),
+        "{}",
+        result.log()
+    );
+}
+
+#[test]
+fn minified_errors_point_at_source() {
+    let minified = minify_stack("1 2 add  #comment#\n\n  heap-new   heap-pop\nprint");
+    assert_eq!(minified.code(), "1 2 add heap-new heap-pop print");
+    let options = format!(r#"{{"trace": "errors", "source_map": {}}}"#, minified.map());
+    let result = run_stack_opts(&minified.code(), &options);
+    assert_eq!(
+        result.log(),
+        "Error! the heap is empty (at line 3, column 14)\n"
+    );
+}
+
+#[test]
+fn diff_finds_first_different_step() {
+    let options = r#"{"record_trace": true, "trace": "off"}"#;
+    let a = run_stack_opts("1 2 add (x) var x print", options);
+    let b = run_stack_opts("1 2 sub (x) var x print", options);
+    assert_eq!(diff_runs(&a, &a), "null");
+    assert_eq!(
+        diff_runs(&a, &b),
+        r#"{"a":"add","b":"sub","kind":"token","step":2}"#
+    );
+}
+
+#[test]
+fn audit_lists_nondeterministic_commands() {
+    let src = "(q) input print 1 2 add print";
+    let result = run_stack_opts(src, r#"{"audit": true, "inputs": ["yes"]}"#);
+    assert_eq!(result.audit(), r#"[{"command":"input","result":"(yes)"}]"#);
+    assert_eq!(run_stack("1 2 add").audit(), "[]");
+}
+
+#[test]
+fn package_exports_are_used_by_name() {
+    let mut session = Session::new();
+    let package = r#"{"name": "math", "version": "1.0", "exports": ["sq"],
+                      "source": "(dup mul) (sq) define (x) (tmp) var"}"#;
+    assert!(session.load_package(package));
+    assert!(!session.load_package("{}"));
+    let result = session.run("(math) use 3 sq print 4 math:sq print tmp print");
+    assert_eq!(result.output(), "9\n16\ntmp\n");
+    let result = session.run("(nope) use print");
+    assert_eq!(result.output(), "error:package-not-found\n");
+}
+
+#[test]
+fn imported_words_take_prefix() {
+    let mut session = Session::new();
+    let package = r#"{"name": "math", "version": "1.0", "exports": ["sq"],
+                      "source": "(dup mul) (sq) define"}"#;
+    session.load_package(package);
+    session.register_module(
+        "geo",
+        "(2 mul) (double) define (double double) (quad) define",
+    );
+    let result = session.run("(math) (m) with-prefix 5 m:sq print (geo) import 3 geo.quad print");
+    assert_eq!(result.output(), "25\n12\n");
+    assert_eq!(session.run("sq print").output(), "sq\n");
+}
+
+#[test]
+fn memoized_word_reuses_results() {
+    let fib =
+        "(( n -- n ) dup 2 less () swap (dup 1 sub fib swap 2 sub fib add) swap if) (fib) define";
+    let options = r#"{"fuel": 20000, "trace": "off"}"#;
+    let result = run_stack_opts(&format!("{fib} 20 fib print"), options);
+    assert_eq!(result.status(), "fuel-exhausted");
+    let result = run_stack_opts(&format!("{fib} (fib) memoize 20 fib print"), options);
+    assert_eq!(result.output(), "6765\n");
+    let result = run_stack("(dup) (f) define (f) memoize print");
+    assert_eq!(result.output(), "error:no-signature\n");
+}
+
+#[test]
+fn specialize_folds_known_inputs() {
+    let env = r#"{"price": 3, "qty": 4, "tax": 0.5}"#;
+    assert_eq!(specialize("price qty mul tax add print", env), "12.5 print");
+    assert_eq!(
+        specialize("a b add #c# 1 div", r#"{"a": 1}"#),
+        "1 b add 1 div"
+    );
+    assert_eq!(specialize("[1 2] n 1 add", r#"{"n": [1]}"#), "[1 2] 2");
+}
+
+#[test]
+fn warnings_are_kept_apart_from_errors() {
+    let src = "(3) 1 add pop 5 (add) var";
+    let result = run_stack_opts(src, r#"{"trace": "off"}"#);
+    assert_eq!(result.status(), "completed");
+    let warnings = result.warnings();
+    assert!(
+        warnings.contains(r#""category":"coercion","severity":"warning""#),
+        "{warnings}"
+    );
+    assert!(
+        warnings.contains(r#""category":"unused-result","severity":"info""#),
+        "{warnings}"
+    );
+    assert!(warnings.contains("shadowed-variable"), "{warnings}");
+    let result = run_stack_opts(src, r#"{"trace": "off", "suppress": ["coercion"]}"#);
+    assert!(
+        !result.warnings().contains("coercion"),
+        "{}",
+        result.warnings()
+    );
+}
+
+#[test]
+fn words_resolve_to_variables_then_commands() {
+    assert_eq!(
+        output("1 2 add print 5 (add) var add print hello print"),
+        "3\n5\nhello\n"
+    );
+    let result = run_stack_opts("hello print", r#"{"strict": true}"#);
+    assert_eq!(result.output(), "error:unknown-command\n");
+}
+
+#[test]
+fn output_html_escapes_markup() {
+    let src = "(<b>&) print 27 decode ([31mred) concat 27 decode ([0m\") concat concat print";
+    let result = run_stack_opts(src, r#"{"trace": "off"}"#);
+    assert_eq!(
+        result.output_html(true),
+        "&lt;b&gt;&amp;\n<span class=\"ansi-red\">red</span>&quot;\n"
+    );
+    assert_eq!(result.output_html(false), "&lt;b&gt;&amp;\nred&quot;\n");
+    assert!(result
+        .output_events()
+        .starts_with(r#"[{"kind":"print","text":"<b>&"}"#));
+}
+
+#[test]
+fn defined_word_runs_its_body() {
+    let src = "[ (dup mul) ] (square) define 3 square print (2 mul) (double) define 4 double square print";
+    assert_eq!(output(src), "9\n64\n");
+    let result = run_stack("(1) (add) define 1 2 add print");
+    assert_eq!(result.output(), "3\n");
+    assert_eq!(result.status(), "error");
+    assert!(result.log().contains("\"add\" can't be defined"));
+}
+
+#[test]
+fn args_are_given_by_options() {
+    let result = run_stack_opts("args print args len print", r#"{"args": ["a", "2"]}"#);
+    assert_eq!(result.output(), "[(a) (2)]\n2\n");
+    assert_eq!(output("args print"), "[]\n");
+}
+
+#[test]
+fn exit_sets_status_and_code() {
+    let exited = run_stack("1 print 3 exit 2 print");
+    assert_eq!(exited.status(), "exited");
+    assert_eq!(exited.exit_code(), Some(3));
+    assert_eq!(exited.output(), "1\n");
+    assert_eq!(
+        run_stack("(2 exit) (f) define f 5 print").exit_code(),
+        Some(2)
+    );
+    assert_eq!(run_stack("1 print").status(), "completed");
+    assert_eq!(
+        run_stack_opts("(1) (x) (true) while", r#"{"fuel": 100}"#).status(),
+        "fuel-exhausted"
+    );
+}
+
+#[test]
+fn missing_item_is_nil() {
+    assert_eq!(
+        output("[1 2] 5 get dup print is-nil print [1] 0 get is-nil print nil type print"),
+        "nil\ntrue\nfalse\nnil\n"
+    );
+}
+
+#[test]
+fn spread_and_concat_lists() {
+    assert_eq!(
+        output("[1 2] [3] concat-list print [2] 1 prepend print [1 2 3] spread add add print"),
+        "[1 2 3]\n[1 2]\n6\n"
+    );
+    assert_eq!(
+        output("4 5 6 2 collect-n print print 9 collect-n type print"),
+        "[5 6]\n4\nerror\n"
+    );
+}
+
+#[test]
+fn dicts_merge_map_and_filter() {
+    let dicts = "[[(a) 1] [(b) 2]] list-to-dict (d) var [[(b) 9] [(c) 3]] list-to-dict (e) var ";
+    let run = |code: &str| output(&format!("{dicts}{code}"));
+    assert_eq!(
+        run("d e (left) dict-merge dict-to-list print"),
+        "[[(a) 1] [(b) 2] [(c) 3]]\n"
+    );
+    assert_eq!(
+        run("d e (right) dict-merge dict-to-list print"),
+        "[[(a) 1] [(b) 9] [(c) 3]]\n"
+    );
+    assert_eq!(run("d e (error) dict-merge type print"), "error\n");
+    assert_eq!(
+        run("d (v) (v 10 mul) dict-map-values dict-to-list print"),
+        "[[(a) 10] [(b) 20]]\n"
+    );
+    assert_eq!(
+        run("d (k v) (1 v less) dict-filter dict-to-list print"),
+        "[[(b) 2]]\n"
+    );
+}
+
+#[test]
+fn blocks_are_values_until_called() {
+    assert_eq!(output("{1 2 add} copy type print call print"), "block\n3\n");
+    assert_eq!(
+        output("{(a (b)) print} call [1 2] (x) {x (a b) concat} map print"),
+        "a (b)\n[(1a b) (2a b)]\n"
+    );
+    assert_eq!(
+        output("{ [1 {2}] } call print 3 call type print"),
+        "[1 {2}]\nerror\n"
+    );
+}
+
+#[test]
+fn tokenize_splits_outside_quotes() {
+    assert_eq!(
+        output(r#"(a,"b,c",,'d',"") (,) false tokenize print"#),
+        "[(a) (b,c) (d) ()]\n"
+    );
+    assert_eq!(
+        output("(1 + 23*4) ( +*) true tokenize print"),
+        "[(1) (+) (23) (*) (4)]\n"
+    );
+}
+
+#[test]
+fn locals_end_with_their_scope() {
+    assert_eq!(
+        output("[1 2] (x) ([3 4] (x) (x print) for x print) for x print"),
+        "3\n4\n1\n3\n4\n2\nx\n"
+    );
+    assert_eq!(
+        output("0 (sum) var [1 2 3] (x) (sum x add (sum) var) for sum print"),
+        "6\n"
+    );
+    assert_eq!(
+        output("(5 (t) local t 1 add (t) var t print) (f) define f t print"),
+        "6\nt\n"
+    );
+    assert_eq!(
+        output("[1 2 3] (a) (b) (a b add) reduce print a print"),
+        "6\na\n"
+    );
+}
+
+#[test]
+fn number_literals_take_base_prefixes() {
+    assert_eq!(
+        output("0xFF print 0b1010 print 0o17 print -0x10 print"),
+        "255\n10\n15\n-16\n"
+    );
+    assert_eq!(output("0x print 0b2 print"), "0x\n0b2\n");
+}
+
+#[test]
+fn precision_rounds_displayed_numbers() {
+    assert_eq!(
+        output("0.1 0.2 add print 0.1 0.2 add 0.3 1e-9 approx-equal print"),
+        "0.30000000000000004\ntrue\n"
+    );
+    assert_eq!(output("0.1 0.2 add 0.3 0 approx-equal print"), "false\n");
+    assert_eq!(
+        output("3 precision 2 3 div print 10 print -1 precision 1 3 div print"),
+        "0.667\n10\n0.3333333333333333\n"
+    );
+    assert_eq!(
+        run_stack_opts("1 3 div print", r#"{"precision": 2}"#).output(),
+        "0.33\n"
+    );
+}
+
+#[test]
+fn imported_module_words_take_its_name() {
+    let mut session = Session::new();
+    session.register_module(
+        "math",
+        "(dup mul) (sq) define (sq sq) (quad) define 0 (zero) var",
+    );
+    assert_eq!(
+        session
+            .run("(math) import 3 math.sq print 2 math.quad print math.zero print sq print")
+            .output(),
+        "9\n16\n0\nsq\n"
+    );
+    assert_eq!(session.run("(nope) import type print").output(), "error\n");
+}
+
+#[test]
+fn checked_math_reports_bad_results() {
+    assert_eq!(
+        output("1 0 div print 0 0 div nan? print 1 0 div finite? print"),
+        "inf\ntrue\nfalse\n"
+    );
+    let src = "1 0 div print\n-1 0.5 pow print 10 308 pow 10 mul print 1 2 div print";
+    let result = run_stack_opts(src, r#"{"checked_math": true, "trace": "errors"}"#);
+    assert_eq!(
+        result.output(),
+        "error:division-by-zero\nerror:nan-result\nerror:infinite-result\n0.5\n"
+    );
+    assert!(result
+        .log()
+        .contains("Error! division by zero (at line 1, column 5)"));
+}
+
+#[test]
+fn namespaces_group_variables() {
+    let src = "1 (config:width) var 2 (config:height) var 3 (width) var (config) ns-list print \
+               (config) (saved) ns-copy saved:width print (config) ns-clear (config) ns-list print \
+               saved:height print width print";
+    assert_eq!(output(src), "[(height) (width)]\n1\n[]\n2\n3\n");
+}
+
+#[test]
+fn shipped_examples_complete() {
+    for (name, _, _) in EXAMPLES {
+        let result = run_builtin_example(name).unwrap();
+        assert_eq!(result.status(), "completed", "{name}");
+        assert!(!result.output().is_empty(), "{name}");
+    }
+    assert!(run_builtin_example("missing").is_none());
+}
+
+#[test]
+fn log_keeps_chosen_categories() {
+    let options = r#"{"log_level": "debug", "log_categories": ["memory"]}"#;
+    let result = run_stack_opts("1 (x) var #note# 2 print", options);
+    assert!(result.log().starts_with("Variables {\n x: 1\n}\n"));
+    assert!(!result.log().contains("←"));
+    assert!(result
+        .log_entries()
+        .contains(r#""level":"debug","category":"memory""#));
+
+    let options = r#"{"log_level": "debug", "log_categories": ["parser"]}"#;
+    assert_eq!(
+        run_stack_opts("#note# 1 0 div", options).log(),
+        "* Comment \"note\"\n"
+    );
+    let full = run_stack("1 (x) var x print").log();
+    assert!(full.contains("←") && full.contains("Variables {"));
+}
+
+#[test]
+fn break_and_continue_leave_innermost_loop() {
+    assert_eq!(
+        output("[1 2 3 4 5] (x) ((continue) () x 2 equal if (break) () x 4 equal if x print) for"),
+        "1\n3\n"
+    );
+    assert_eq!(
+        output("0 (i) var (i 1 add (i) var (break) () i 3 equal if i print) (true) while i print"),
+        "1\n2\n3\n"
+    );
+    assert_eq!(
+        output("[1 2] (x) ([5 6] (y) (break) for x print) for"),
+        "1\n2\n"
+    );
+}
+
+#[test]
+fn case_runs_first_equal_arm() {
+    let arms = "[[1 (one)] [(1) ((text one))] [[1 2] (pair)]] (other) case print";
+    assert_eq!(
+        output(&format!("1 {arms} (1) {arms} [1 2] {arms} 3 {arms}")),
+        "one\ntext one\npair\nother\n"
+    );
+}
+
+#[test]
+fn paused_session_keeps_its_state() {
+    let mut session = Session::new();
+    session.run("1 (x) var");
+    session.pause();
+    assert!(session.is_paused());
+    assert_eq!(session.dispatch_events().output(), "");
+    session.resume();
+    assert!(!session.is_paused());
+    assert_eq!(session.run("x print").output(), "1\n");
+}
+
+#[test]
+fn defstruct_makes_constructor_and_accessors() {
+    let src = "[(x) (y)] (point) defstruct 3 4 make-point (p) var \
+               p point-x print p point-y print p point? print 5 point? print p type print";
+    assert_eq!(output(src), "3\n4\ntrue\nfalse\npoint\n");
+    assert_eq!(output("[(number)] (input) defstruct type print"), "error\n");
+}
+
+#[test]
+fn catalog_lists_examples_with_commands() {
+    let catalog: serde_json::Value = serde_json::from_str(&examples_catalog()).unwrap();
+    let examples = catalog.as_array().unwrap();
+    assert_eq!(examples.len(), EXAMPLES.len());
+    assert_eq!(examples[0]["name"], "strings");
+    assert_eq!(examples[0]["difficulty"], "beginner");
+    assert!(examples[0]["commands"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|x| x == "tokenize"));
+}
+
+#[test]
+fn tail_calls_do_not_nest() {
+    let countdown = "((n) local (n 1 sub countdown) (n print) n 0 equal not if) (countdown) define";
+    assert_eq!(output(&format!("{countdown} 100000 countdown")), "0\n");
+    assert_eq!(
+        output("((x) local 1 (x) local x) (f) define 5 (x) var f print x print"),
+        "1\n5\n"
+    );
+}
+
+#[test]
+fn escapes_in_strings_are_decoded() {
+    assert_eq!(
+        output(r"(a\nb\tc) print (\(unbalanced) print (back\\slash) print (\u00e9\u3042) print"),
+        "a\nb\tc\n(unbalanced\nback\\slash\né\u{3042}\n"
+    );
+    assert_eq!(
+        output(r"(C:\dir\q) print (\u12) print"),
+        "C:\\dir\\q\n\\u12\n"
+    );
+    assert_eq!(output(r"[(a\)) (b)] len print"), "2\n");
+}
+
+#[test]
+fn interp_fills_variables_in_braces() {
+    assert_eq!(
+        output(
+            "(Ann) (name) var 90 (score) var (Hello {name}, you scored { score }!) interp print"
+        ),
+        "Hello Ann, you scored 90!\n"
+    );
+    assert_eq!(
+        output("[1 2] (xs) var ({{xs}} is {xs}, {missing} and } {) interp print"),
+        "{xs} is [1 2], {missing} and } {\n"
+    );
+    assert_eq!(
+        output("[1 2] (x) (x 5 (y) local (x={x} y={y}) interp) map print"),
+        "[(x=1 y=5) (x=2 y=5)]\n"
+    );
+}
+
+#[test]
+fn raw_strings_keep_their_text() {
+    assert_eq!(
+        output("\"\"\"line one\n\tline (two) # not comment\\n\"\"\" print"),
+        "line one\n\tline (two) # not comment\\n\n"
+    );
+    assert_eq!(output("[\"\"\"x y\"\"\" \"\"\"\"\"\"] len print"), "2\n");
+    assert_eq!(output("\"\"\"\"\"\" len print 1 2 add print"), "0\n3\n");
+}
+
+#[test]
+fn compiled_program_executes_in_session() {
+    let program = compile("(x) var x x mul print");
+    assert!(program.is_valid());
+    let mut session = Session::new();
+    session.run("3");
+    assert_eq!(session.execute(&program, "").output(), "9\n");
+    session.run("0.33");
+    assert_eq!(
+        session.execute(&program, r#"{"precision": 2}"#).output(),
+        "0.11\n"
+    );
+
+    let invalid = compile("(a b\n [1 2]] # note");
+    assert!(!invalid.is_valid());
+    assert_eq!(session.execute(&invalid, "").status(), "error");
+}
+
+#[test]
+fn definitions_move_between_sessions() {
+    let mut first = Session::new();
+    first.run("#: Square the number # (dup mul) (sq) define (1 add) (inc) define 5 (v) var");
+    let definitions = first.export_definitions();
+
+    let mut second = Session::new();
+    assert!(second.import_definitions(&definitions));
+    assert_eq!(
+        second.run("3 sq print 3 inc print v print").output(),
+        "9\n4\nv\n"
+    );
+    assert_eq!(
+        second.run("(sq) help print").output(),
+        "Square the number\n"
+    );
+    assert!(!second.import_definitions(r#"[{"name": "add", "code": "1"}]"#));
+    assert!(!second.import_definitions("nope"));
+}
+
+#[test]
+fn nested_lists_keep_their_shape() {
+    assert_eq!(
+        output(
+            "[[1 2] [(a b) 3]] (l) var l len print l 1 get 0 get print l 0 get len print l print"
+        ),
+        "2\na b\n2\n[[1 2] [(a b) 3]]\n"
+    );
+    assert_eq!(output("[[[1] []] ( x ) [(y [z])]] len print"), "3\n");
+}
+
+#[test]
+fn comparisons_compare_by_value() {
+    assert_eq!(
+        output(
+            "3 2 greater print 2 2 less-equal print 1 2 greater-equal print 1 2 not-equal print"
+        ),
+        "true\ntrue\nfalse\ntrue\n"
+    );
+    assert_eq!(
+        output("[1 [2 (a)]] [1 [2 (a)]] equal print [1 2] (1 2) equal print [1] [1 2] equal print"),
+        "true\nfalse\nfalse\n"
+    );
+}
+
+#[test]
+fn bytes_hold_utf8_of_strings() {
+    assert_eq!(
+        output("(Hé) bytes (b) var b len print b type print b bytes-to-list print b 1 3 bytes-slice bytes-to-string print"),
+        "3\nbytes\n[72 195 169]\né\n"
+    );
+    assert_eq!(
+        output(
+            "[104 105] bytes [33] bytes bytes-concat bytes-to-string print [256] bytes type print"
+        ),
+        "hi!\nerror\n"
+    );
+    assert_eq!(output("[255] bytes bytes-to-string type print"), "error\n");
+}
+
+#[test]
+fn times_runs_block_with_index() {
+    assert_eq!(
+        output("(i print) 3 times {(x) print} 2 times"),
+        "0\n1\n2\nx\nx\n"
+    );
+    assert_eq!(
+        output("((break) () i 5 equal if (continue) () i 2 equal if i print) 10 times 7 (i) var (1) 2 times i print"),
+        "0\n1\n3\n4\n7\n"
+    );
+}
+
+#[test]
+fn select_picks_by_condition() {
+    assert_eq!(
+        output("(yes) (no) 1 2 less select print (a b) [1] false select print"),
+        "yes\n[1]\n"
+    );
+}
+
+#[test]
+fn symbols_alias_arithmetic_and_comparison() {
+    assert_eq!(
+        output("2 3 + print 2 3 * print 7 2 / print 7 2 % print 5 1 - print"),
+        "5\n6\n3.5\n1\n4\n"
+    );
+    assert_eq!(
+        output("1 2 < print 1 2 > print 2 2 <= print 1 2 >= print [1] [1] = print 1 2 != print"),
+        "true\nfalse\ntrue\nfalse\ntrue\ntrue\n"
+    );
+}
+
+#[test]
+fn enum_variants_are_their_own_type() {
+    let color = "[(Red) (Green) (Blue)] (Color) defenum ";
+    assert_eq!(
+        output(&format!("{color}Color.Green (c) var c print c type print c Color.Green equal print c (Color.Green) equal print Color len print")),
+        "Color.Green\nColor\ntrue\nfalse\n3\n"
+    );
+    assert_eq!(
+        output(&format!(
+            "{color}Color.Red [[Color.Green (g)] [Color.Red (r)]] match print"
+        )),
+        "r\n"
+    );
+    let mut session = Session::new();
+    session.run("[(A)] (E) defenum");
+    assert_eq!(session.run("5 (E.A) var").status(), "error");
+}
+
+#[test]
+fn until_repeats_while_condition_is_false() {
+    assert_eq!(
+        output("1 (n) var (n 2 mul (n) var) (n 100 less not) until n print"),
+        "128\n"
+    );
+    assert_eq!(
+        output("[(a) (b)] (x) (loop-index print) for loop-index type print"),
+        "0\n1\nerror\n"
+    );
+    assert_eq!(
+        output("((continue) () loop-index 1 equal if loop-index print) 3 times"),
+        "0\n2\n"
+    );
+}
+
+#[test]
+fn recurse_calls_the_running_block() {
+    assert_eq!(
+        output("5 {(n) local (n 1 sub recurse n mul) (1) n 1 less-equal not if} call print"),
+        "120\n"
+    );
+    assert_eq!(output("recurse type print"), "error\n");
+}
+
+#[test]
+fn lexer_errors_have_line_and_column() {
+    assert_eq!(
+        compile("é\n  (a ]").errors(),
+        r#"[{"message":"unclosed \"(\"","position":4,"offset":5,"line":2,"column":3}]"#
+    );
+    assert_eq!(
+        compile("1 ] \"\"\"x").errors(),
+        r#"[{"message":"unexpected \"]\"","position":2,"offset":2,"line":1,"column":3},{"message":"unclosed raw string","position":4,"offset":4,"line":1,"column":5}]"#
+    );
+}
+
+#[test]
+fn compiled_program_runs_many_times() {
+    let program = compile("0 (n) var [(n 1 add (n) var) 3 times] eval n print");
+    for _ in 0..3 {
+        assert_eq!(run_program(&program, "").output(), "3\n");
+    }
+    let invalid = compile("(1 2");
+    assert!(!invalid.is_valid());
+    assert!(run_program(&invalid, "").log().contains("Error!"));
+    assert!(run_program(&program, "{").log().contains("invalid options"));
+}
+
+#[test]
+fn escaped_brackets_stay_in_strings() {
+    assert_eq!(
+        output("(x \\( y) print (\\]) print (\\#) print"),
+        "x ( y\n]\n#\n"
+    );
+    assert_eq!(output("(a # b) print 1 print"), "a # b\n1\n");
+    assert_eq!(output("(1 # c # 2 add) eval print"), "3\n");
+}
+
+#[test]
+fn tokens_have_kinds_and_offsets() {
+    let tokens: serde_json::Value =
+        serde_json::from_str(&tokenize("1 (a b)\n[2] #c# print x")).unwrap();
+    let kinds: Vec<&str> = tokens
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["kind"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        kinds,
+        ["number", "string", "list", "comment", "command", "unknown"]
+    );
+    assert_eq!(tokens[1]["start"], 2);
+    assert_eq!(tokens[1]["end"], 7);
+    assert_eq!(tokens[2]["start"], 8);
+}
+
+#[test]
+fn comments_are_skipped_outside_strings() {
+    assert_eq!(output("1 print // one (\n2 print"), "1\n2\n");
+    assert_eq!(output("/* multi\nline ( [ */ 3 print"), "3\n");
+    assert_eq!(
+        output("[1 // c ]\n 2] len print [1 /* x */ 2] len print"),
+        "2\n2\n"
+    );
+    assert_eq!(output("(a // b) print 4 2 / print"), "a // b\n2\n");
+    assert!(run_stack("/* open")
+        .syntax_errors()
+        .contains("unclosed block comment"));
+    assert_eq!(minify_stack("1 // x\n2 add print").code(), "1 2 add print");
+}
+
+#[test]
+fn numbers_take_exponents_and_underscores() {
+    assert_eq!(
+        output("1_000_000 print 1.5e-3 print 2E3 print [0x1 1e2] print"),
+        "1000000\n0.0015\n2000\n[1 100]\n"
+    );
+    assert_eq!(output("_1 print 1_ print 1__0 print"), "_1\n1_\n1__0\n");
+    assert_eq!(
+        output("(0x10) (number) cast 1 add print (0b2) only-number print"),
+        "17\nfalse\n"
+    );
+}
@@ -0,0 +1,181 @@
+//! Tests of the wasm surface in a headless browser, run by
+//! `wasm-pack test --headless --firefox`
+#![cfg(target_arch = "wasm32")]
+
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+use web_stack::{eval_value, run_batch, run_stack, run_stack_with_args, Session};
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+/// Answers of `input`
+fn inputs(answers: &[&str]) -> js_sys::Array {
+    answers.iter().map(|x| JsValue::from_str(x)).collect()
+}
+
+#[wasm_bindgen_test]
+fn input_is_answered_from_the_queue() {
+    let mut session = Session::with_providers(None, None, inputs(&["Alice", "41"]));
+    let result = session.run("(name) input print (age) input-number 1 add print");
+    assert_eq!(result.output(), "Alice\n42\n");
+    assert_eq!(result.status(), "completed");
+}
+
+#[wasm_bindgen_test]
+fn configure_keeps_the_input_queue() {
+    let mut session = Session::with_providers(None, None, inputs(&["Alice"]));
+    session.configure(r#"{"trace": "off"}"#);
+    assert_eq!(session.run("(name) input print").output(), "Alice\n");
+}
+
+#[wasm_bindgen_test]
+fn random_comes_from_the_provider() {
+    let random = js_sys::Function::new_no_args("return 0.25;");
+    let mut session = Session::with_providers(None, Some(random), inputs(&[]));
+    assert_eq!(
+        session.run("random print random print").output(),
+        "0.25\n0.25\n"
+    );
+}
+
+#[wasm_bindgen_test]
+fn now_time_comes_from_the_clock() {
+    let clock = js_sys::Function::new_no_args("return 5000;");
+    let mut session = Session::with_providers(Some(clock), None, inputs(&[]));
+    let result = session.run("now-time print 250 sleep now-time print");
+    assert_eq!(result.output(), "5000\n5250\n");
+}
+
+#[wasm_bindgen_test]
+fn time_limit_follows_the_clock() {
+    // The clock goes on by a second every time it's read
+    let clock = js_sys::Function::new_no_args(
+        "globalThis.clockTime = (globalThis.clockTime || 0) + 1000; return globalThis.clockTime;",
+    );
+    let mut session = Session::with_providers(Some(clock), None, inputs(&[]));
+    session.configure(r#"{"time_limit": 10}"#);
+    let result = session.run("(1 pop) 1000 times");
+    assert_eq!(result.status(), "timeout");
+}
+
+#[wasm_bindgen_test]
+fn glob_lists_the_virtual_files() {
+    let mut session = Session::with_providers(None, None, inputs(&[]));
+    session.configure(r#"{"capabilities": ["fs"]}"#);
+    session.add_file("data/a.txt");
+    session.add_file("data/b.csv");
+    let result = session.run("(data/*.txt) glob print");
+    assert_eq!(result.output(), "[(data/a.txt)]\n");
+}
+
+#[wasm_bindgen_test]
+fn output_bytes_are_utf8() {
+    let result = run_stack("(héllo) print");
+    assert_eq!(result.output_bytes().to_vec(), "héllo\n".as_bytes());
+}
+
+#[wasm_bindgen_test]
+fn permission_is_asked_once() {
+    let callback = js_sys::Function::new_with_args(
+        "capability",
+        "globalThis.asked = (globalThis.asked || 0) + 1; return capability === 'fs';",
+    );
+    let mut session = Session::with_providers(None, None, inputs(&[]));
+    session.set_permission_callback(callback);
+    session.add_file("a.txt");
+    let result = session.run("(*.txt) glob print (*.txt) glob print (u) [] exec print");
+    assert_eq!(
+        result.output(),
+        "[(a.txt)]\n[(a.txt)]\nerror:permission-denied\n"
+    );
+    let asked = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("asked")).unwrap();
+    assert_eq!(asked.as_f64(), Some(1.0));
+}
+
+#[wasm_bindgen_test]
+fn imported_url_is_fetched_once() {
+    let fetch = js_sys::Function::new_with_args(
+        "url",
+        "globalThis.fetches = (globalThis.fetches || 0) + 1; return url.endsWith('.stk') ? '(2 mul) (dbl) define' : null;",
+    );
+    let mut session = Session::with_providers(None, None, inputs(&[]));
+    session.configure(r#"{"capabilities": ["network"]}"#);
+    session.set_fetch_callback(fetch);
+    let src = "(https://example.com/lib.stk) import-url 4 dbl print \
+               (https://example.com/lib.stk) import-url (https://example.com/x) import-url print";
+    assert_eq!(session.run(src).output(), "8\nerror:import-url\n");
+    let fetches = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("fetches")).unwrap();
+    assert_eq!(fetches.as_f64(), Some(2.0));
+}
+
+/// Call the method of the JS object without arguments
+fn call_method(object: &JsValue, name: &str) -> JsValue {
+    let method: js_sys::Function = js_sys::Reflect::get(object, &JsValue::from_str(name))
+        .unwrap()
+        .into();
+    method.call0(object).unwrap()
+}
+
+#[wasm_bindgen_test]
+fn batch_runs_each_environment() {
+    let inputs = js_sys::JSON::parse(r#"[{"x": 1}, {"x": 41}, 5]"#).unwrap();
+    let results: js_sys::Array = run_batch("x 1 add print", inputs).into();
+    let outputs: Vec<JsValue> = results.iter().map(|x| call_method(&x, "output")).collect();
+    assert_eq!(outputs, ["2\n", "42\n", ""].map(JsValue::from_str));
+    let log = call_method(&results.get(2), "log");
+    assert_eq!(
+        log.as_string().unwrap(),
+        "Error! invalid input: expected object\n"
+    );
+}
+
+#[wasm_bindgen_test]
+fn eval_value_returns_the_top_of_the_stack() {
+    assert_eq!(eval_value("1 2 add").unwrap().as_f64(), Some(3.0));
+    let list: js_sys::Array = eval_value("[1 (a) true]").unwrap().into();
+    assert_eq!(list.length(), 3);
+    assert_eq!(list.get(1).as_string().as_deref(), Some("a"));
+    assert!(eval_value("").is_err());
+    assert!(eval_value("(oops) throw").is_err());
+}
+
+#[wasm_bindgen_test]
+fn hooks_see_commands_and_variables() {
+    let before = js_sys::Function::new_with_args(
+        "name",
+        "(globalThis.commands = globalThis.commands || []).push(name);",
+    );
+    let write =
+        js_sys::Function::new_with_args("name, value", "globalThis.written = name + '=' + value;");
+    let mut session = Session::with_providers(None, None, inputs(&[]));
+    assert!(session.set_hook("before-command", before));
+    assert!(session.set_hook("on-var-write", write));
+    assert!(!session.set_hook("unknown", js_sys::Function::new_no_args("")));
+    assert_eq!(session.run("1 2 add (x) var").status(), "completed");
+
+    let global = |name: &str| js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str(name));
+    let commands: js_sys::Array = global("commands").unwrap().into();
+    assert_eq!(commands.join(",").as_string().unwrap(), "add,var");
+    assert_eq!(global("written").unwrap().as_string().unwrap(), "x=3");
+
+    let abort = js_sys::Function::new_no_args("throw new Error('stop');");
+    session.set_hook("before-command", abort);
+    assert_eq!(session.run("1 print").status(), "aborted");
+}
+
+#[wasm_bindgen_test]
+fn args_are_passed_from_javascript() {
+    let result = run_stack_with_args("args print args len print", inputs(&["a", "2"]));
+    assert_eq!(result.output(), "[(a) (2)]\n2\n");
+}
+
+#[wasm_bindgen_test]
+fn usage_counts_commands_of_the_run() {
+    let usage = js_sys::Function::new_with_args("counts", "globalThis.usage = counts;");
+    let mut session = Session::with_providers(None, None, inputs(&[]));
+    assert!(session.set_hook("on-usage", usage));
+    session.run("1 2 add 3 add print");
+
+    let usage = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("usage")).unwrap();
+    assert_eq!(usage.as_string().unwrap(), r#"{"add":2,"print":1}"#);
+}